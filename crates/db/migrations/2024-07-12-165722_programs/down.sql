@@ -1 +1 @@
-DROP TABLE programs;
\ No newline at end of file
+DROP TABLE IF EXISTS programs;
\ No newline at end of file
@@ -1 +1 @@
-DROP TABLE idls;
\ No newline at end of file
+DROP TABLE IF EXISTS idls;
\ No newline at end of file
@@ -1 +1 @@
-DROP TABLE squads;
\ No newline at end of file
+DROP TABLE IF EXISTS squads;
\ No newline at end of file
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS blocks_unprocessed_key;
+ALTER TABLE blocks DROP COLUMN IF EXISTS last_error;
+ALTER TABLE blocks DROP COLUMN IF EXISTS claimed_at;
+ALTER TABLE blocks DROP COLUMN IF EXISTS attempts;
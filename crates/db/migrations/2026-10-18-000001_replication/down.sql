@@ -0,0 +1,9 @@
+DROP TABLE IF EXISTS replication_state;
+DROP TRIGGER IF EXISTS squads_change_id ON squads;
+DROP TRIGGER IF EXISTS programs_change_id ON programs;
+DROP TRIGGER IF EXISTS idls_change_id ON idls;
+ALTER TABLE squads DROP COLUMN IF EXISTS change_id;
+ALTER TABLE programs DROP COLUMN IF EXISTS change_id;
+ALTER TABLE idls DROP COLUMN IF EXISTS change_id;
+DROP FUNCTION IF EXISTS set_replication_change_id();
+DROP SEQUENCE IF EXISTS replication_change_id_seq;
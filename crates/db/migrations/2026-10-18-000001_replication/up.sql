@@ -0,0 +1,37 @@
+-- every insert, or update which changes a row, assigns the row a new change_id from a shared sequence,
+-- allowing replication to copy rows changed since a high-water mark
+CREATE SEQUENCE IF NOT EXISTS replication_change_id_seq;
+
+CREATE OR REPLACE FUNCTION set_replication_change_id() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND NEW IS NOT DISTINCT FROM OLD THEN
+        RETURN NEW;
+    END IF;
+    NEW.change_id := nextval('replication_change_id_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE idls ADD COLUMN IF NOT EXISTS change_id BIGINT NOT NULL DEFAULT nextval('replication_change_id_seq');
+ALTER TABLE programs ADD COLUMN IF NOT EXISTS change_id BIGINT NOT NULL DEFAULT nextval('replication_change_id_seq');
+ALTER TABLE squads ADD COLUMN IF NOT EXISTS change_id BIGINT NOT NULL DEFAULT nextval('replication_change_id_seq');
+
+CREATE INDEX IF NOT EXISTS idls_change_id_key ON idls (change_id);
+CREATE INDEX IF NOT EXISTS programs_change_id_key ON programs (change_id);
+CREATE INDEX IF NOT EXISTS squads_change_id_key ON squads (change_id);
+
+CREATE TRIGGER idls_change_id BEFORE INSERT OR UPDATE ON idls
+    FOR EACH ROW EXECUTE FUNCTION set_replication_change_id();
+CREATE TRIGGER programs_change_id BEFORE INSERT OR UPDATE ON programs
+    FOR EACH ROW EXECUTE FUNCTION set_replication_change_id();
+CREATE TRIGGER squads_change_id BEFORE INSERT OR UPDATE ON squads
+    FOR EACH ROW EXECUTE FUNCTION set_replication_change_id();
+
+-- high-water marks of tables replicated into this database, keyed by the source database
+CREATE TABLE IF NOT EXISTS replication_state (
+    source VARCHAR NOT NULL,
+    table_name VARCHAR NOT NULL,
+    high_water_mark BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (source, table_name)
+);
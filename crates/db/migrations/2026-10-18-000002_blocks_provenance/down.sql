@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS blocks_ingested_at_key;
+DROP INDEX IF EXISTS blocks_source_key;
+ALTER TABLE blocks DROP COLUMN IF EXISTS encoding_version;
+ALTER TABLE blocks DROP COLUMN IF EXISTS ingested_at;
+ALTER TABLE blocks DROP COLUMN IF EXISTS minimized;
+ALTER TABLE blocks DROP COLUMN IF EXISTS source;
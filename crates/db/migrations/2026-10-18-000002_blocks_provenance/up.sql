@@ -0,0 +1,8 @@
+-- provenance of stored blocks, blocks ingested before these columns existed have null values
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS source VARCHAR;
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS minimized BOOLEAN;
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS ingested_at TIMESTAMPTZ;
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS encoding_version INTEGER;
+
+CREATE INDEX IF NOT EXISTS blocks_source_key ON blocks (source);
+CREATE INDEX IF NOT EXISTS blocks_ingested_at_key ON blocks (ingested_at);
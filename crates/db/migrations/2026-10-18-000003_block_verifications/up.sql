@@ -0,0 +1,16 @@
+-- results of comparing stored blocks against a fresh copy fetched from an upstream source
+CREATE TABLE IF NOT EXISTS block_verifications (
+    id BIGSERIAL PRIMARY KEY,
+    slot BIGINT NOT NULL,
+    -- upstream the block was re-fetched from, ie bigtable, rpc
+    upstream VARCHAR NOT NULL,
+    -- one of match, mismatch, missing, error
+    status VARCHAR NOT NULL,
+    -- structural differences between the stored and upstream block, empty unless status is mismatch
+    differences JSONB NOT NULL DEFAULT '[]',
+    error TEXT,
+    verified_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS block_verifications_slot_key ON block_verifications (slot);
+CREATE INDEX IF NOT EXISTS block_verifications_status_key ON block_verifications (status, verified_at);
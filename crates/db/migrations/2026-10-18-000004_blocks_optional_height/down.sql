@@ -0,0 +1,24 @@
+-- blocks without a height can not be keyed by height. fail rather than deleting them, they must be
+-- given a height or removed before reverting
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM blocks WHERE number IS NULL) THEN
+        RAISE EXCEPTION 'blocks without a height are stored, set their height or delete them before reverting';
+    END IF;
+END
+$$;
+
+UPDATE replication_state rs
+SET high_water_mark = COALESCE((SELECT b.number FROM blocks b WHERE b.slot = rs.high_water_mark), -1)
+WHERE rs.table_name = 'blocks';
+
+DROP INDEX IF EXISTS blocks_unprocessed_key;
+CREATE INDEX IF NOT EXISTS blocks_unprocessed_key ON blocks (number) WHERE processed = false;
+
+DROP INDEX IF EXISTS blocks_missing_height_key;
+ALTER TABLE blocks DROP COLUMN IF EXISTS height_derived;
+
+ALTER TABLE blocks DROP CONSTRAINT IF EXISTS blocks_pkey;
+ALTER TABLE blocks ADD CONSTRAINT blocks_slot_key UNIQUE (slot);
+ALTER TABLE blocks ALTER COLUMN number SET NOT NULL;
+ALTER TABLE blocks ADD PRIMARY KEY (number);
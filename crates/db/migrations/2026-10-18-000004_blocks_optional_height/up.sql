@@ -0,0 +1,20 @@
+-- blocks produced before block heights were recorded (pre-2021 mainnet) have no height, so blocks
+-- are keyed by slot and the height is optional
+ALTER TABLE blocks DROP CONSTRAINT IF EXISTS blocks_pkey;
+ALTER TABLE blocks ALTER COLUMN number DROP NOT NULL;
+ALTER TABLE blocks ADD PRIMARY KEY (slot);
+-- superseded by the primary key
+ALTER TABLE blocks DROP CONSTRAINT IF EXISTS blocks_slot_key;
+
+-- whether the height was derived by counting from a block with a known height, rather than reported upstream
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS height_derived BOOLEAN NOT NULL DEFAULT false;
+CREATE INDEX IF NOT EXISTS blocks_missing_height_key ON blocks (slot) WHERE number IS NULL;
+
+-- the work queue is ordered by slot so that blocks without a height are processed
+DROP INDEX IF EXISTS blocks_unprocessed_key;
+CREATE INDEX IF NOT EXISTS blocks_unprocessed_key ON blocks (slot) WHERE processed = false;
+
+-- blocks are replicated by slot, convert replicated block heights to the slot of the replicated block
+UPDATE replication_state rs
+SET high_water_mark = COALESCE((SELECT b.slot FROM blocks b WHERE b.number = rs.high_water_mark), -1)
+WHERE rs.table_name = 'blocks';
@@ -0,0 +1,17 @@
+-- outcome of each attempt at repairing a range of missing block heights
+CREATE TABLE IF NOT EXISTS gap_repairs (
+    id BIGSERIAL PRIMARY KEY,
+    gap_start BIGINT NOT NULL,
+    gap_end BIGINT NOT NULL,
+    -- upstream the missing blocks were fetched from, ie bigtable, rpc
+    upstream VARCHAR NOT NULL,
+    -- one of fetched, partially_fetched, failed
+    status VARCHAR NOT NULL,
+    -- number of missing blocks fetched and sent to be persisted
+    blocks_found BIGINT NOT NULL DEFAULT 0,
+    error TEXT,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS gap_repairs_gap_start_key ON gap_repairs (gap_start, attempted_at);
+CREATE INDEX IF NOT EXISTS gap_repairs_status_key ON gap_repairs (status, attempted_at);
@@ -0,0 +1 @@
+ALTER TABLE idls DROP COLUMN IF EXISTS kind;
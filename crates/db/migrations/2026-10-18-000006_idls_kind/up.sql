@@ -0,0 +1,2 @@
+-- dialect of the stored idl, ie anchor, codama or shank
+ALTER TABLE idls ADD COLUMN IF NOT EXISTS kind VARCHAR NOT NULL DEFAULT 'anchor';
@@ -0,0 +1,13 @@
+DROP TABLE IF EXISTS replication_failures;
+DROP TRIGGER IF EXISTS blocks_change_id ON blocks;
+ALTER TABLE blocks DROP COLUMN IF EXISTS change_id;
+
+CREATE OR REPLACE FUNCTION set_replication_change_id() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND NEW IS NOT DISTINCT FROM OLD THEN
+        RETURN NEW;
+    END IF;
+    NEW.change_id := nextval('replication_change_id_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,30 @@
+-- assign the row's transaction an xid before taking a change_id, so that replication can wait for
+-- every transaction which could still commit a change_id below a fence, see `Client::replication_fence`
+CREATE OR REPLACE FUNCTION set_replication_change_id() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND NEW IS NOT DISTINCT FROM OLD THEN
+        RETURN NEW;
+    END IF;
+    PERFORM pg_current_xact_id();
+    NEW.change_id := nextval('replication_change_id_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- nullable so that existing blocks are not rewritten, blocks stored before this migration are
+-- replicated by slot. only changes to the stored block assign a new change_id, not work queue updates
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS change_id BIGINT;
+CREATE INDEX IF NOT EXISTS blocks_change_id_key ON blocks (change_id);
+CREATE TRIGGER blocks_change_id
+    BEFORE INSERT OR UPDATE OF number, time, data, source, minimized, encoding_version, height_derived ON blocks
+    FOR EACH ROW EXECUTE FUNCTION set_replication_change_id();
+
+-- rows which could not be written to this database during replication, retried by every run
+CREATE TABLE IF NOT EXISTS replication_failures (
+    source VARCHAR NOT NULL,
+    table_name VARCHAR NOT NULL,
+    row_key VARCHAR NOT NULL,
+    error TEXT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (source, table_name, row_key)
+);
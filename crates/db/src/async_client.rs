@@ -0,0 +1,66 @@
+//! Async access to postgres for code running inside the tokio runtime.
+//!
+//! [`Client`] and diesel are synchronous, so calling them from async tasks blocks runtime worker
+//! threads. [`AsyncClient`] instead runs queries on connections checked out from a bounded
+//! deadpool pool, each of which executes on tokio's blocking thread pool. Once every connection is
+//! in use, callers wait for one to be returned, which bounds the number of queries in flight.
+
+use {
+    crate::client::Client,
+    anyhow::{anyhow, Result},
+    deadpool_diesel::{
+        postgres::{Manager, Pool},
+        Runtime,
+    },
+    diesel::PgConnection,
+};
+
+#[derive(Clone)]
+pub struct AsyncClient {
+    pool: Pool,
+    client: Client,
+}
+
+impl AsyncClient {
+    /// Creates a client backed by a pool of at most `max_size` connections to `db_url`.
+    ///
+    /// Connections are established lazily, so this does not fail if postgres is unreachable
+    pub fn new(db_url: &str, max_size: usize) -> Result<Self> {
+        if max_size == 0 {
+            return Err(anyhow!("max_size must be positive"));
+        }
+        let manager = Manager::new(db_url, Runtime::Tokio1);
+        let pool = Pool::builder(manager)
+            .max_size(max_size)
+            .build()
+            .map_err(|err| anyhow!("failed to build connection pool {err:#?}"))?;
+        Ok(Self {
+            pool,
+            client: Client {},
+        })
+    }
+    /// Runs `f` on a pooled connection, waiting for a connection to become available if all are in use
+    pub async fn interact<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(Client, &mut PgConnection) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| anyhow!("failed to get pool connection {err:#?}"))?;
+        let client = self.client;
+        conn.interact(move |conn| f(client, conn))
+            .await
+            .map_err(|err| anyhow!("database task failed {err:#?}"))?
+    }
+    /// Applies any pending migrations, see [`crate::migrations::run_migrations`]
+    pub async fn run_migrations(&self) -> Result<()> {
+        self.interact(|_, conn| {
+            crate::migrations::run_migrations(conn);
+            Ok(())
+        })
+        .await
+    }
+}
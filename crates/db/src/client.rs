@@ -1,9 +1,17 @@
 use anyhow::{anyhow, Context};
 use chrono::prelude::*;
-use diesel::{pg::Pg, prelude::*, result::DatabaseErrorKind, sql_query};
-use uuid::Uuid;
+use diesel::{
+    pg::Pg,
+    prelude::*,
+    result::DatabaseErrorKind,
+    sql_query,
+    sql_types::{BigInt, Bool, Nullable},
+};
 
-use crate::models::{Blocks, Idls, NewBlock, NewSquads, Programs, Squads};
+use crate::models::{
+    BlockVerifications, Blocks, GapRepairs, Idls, NewBlock, NewBlockVerification, NewGapRepair, NewIdl,
+    NewSquads, Programs, Provenance, Squads,
+};
 
 #[derive(Clone, Copy)]
 pub struct Client {}
@@ -16,8 +24,159 @@ pub enum BlockFilter {
     Number(i64),
     /// returns the oldest block we have based on block number
     FirstBlock,
-    /// return all blocks
+    /// returns the newest block we have based on block number
+    LastBlock,
+    /// return all blocks, ordered by slot. prefer [`Client::select_block_pages`] for large tables
     All,
+    /// blocks whose slot falls within `[start, end]`, ordered by slot
+    SlotRange {
+        start: i64,
+        end: i64,
+        order: BlockOrder,
+        limit: Option<i64>,
+    },
+    /// blocks whose block number falls within `[start, end]`, ordered by block number
+    NumberRange {
+        start: i64,
+        end: i64,
+        order: BlockOrder,
+        limit: Option<i64>,
+    },
+    /// blocks whose time falls within `[start, end]`, ordered by slot. blocks without a time never match
+    TimeRange {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        order: BlockOrder,
+        limit: Option<i64>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Column blocks are ordered, and paged, by
+#[derive(Clone, Copy)]
+enum BlockKey {
+    Slot,
+    Number,
+}
+
+type BlockPredicate = Box<dyn BoxableExpression<crate::schema::blocks::table, Pg, SqlType = Bool>>;
+
+impl BlockFilter {
+    fn predicate(&self) -> BlockPredicate {
+        use crate::schema::blocks::dsl::*;
+        match *self {
+            Self::Slot(s) => Box::new(slot.eq(s)),
+            Self::Number(n) => Box::new(number.assume_not_null().eq(n)),
+            Self::FirstBlock | Self::LastBlock => Box::new(number.is_not_null()),
+            Self::All => Box::new(slot.is_not_null()),
+            Self::SlotRange { start, end, .. } => Box::new(slot.between(start, end)),
+            Self::NumberRange { start, end, .. } => Box::new(number.assume_not_null().between(start, end)),
+            Self::TimeRange { start, end, .. } => Box::new(time.assume_not_null().between(start, end)),
+        }
+    }
+    /// returns the key matching blocks are ordered by, the order, and the max number of blocks returned
+    fn ordering(&self) -> (BlockKey, BlockOrder, Option<i64>) {
+        match *self {
+            Self::Slot(_) | Self::All => (BlockKey::Slot, BlockOrder::Ascending, None),
+            Self::Number(_) => (BlockKey::Number, BlockOrder::Ascending, None),
+            Self::FirstBlock => (BlockKey::Number, BlockOrder::Ascending, Some(1)),
+            Self::LastBlock => (BlockKey::Number, BlockOrder::Descending, Some(1)),
+            Self::SlotRange { order, limit, .. } | Self::TimeRange { order, limit, .. } => {
+                (BlockKey::Slot, order, limit)
+            }
+            Self::NumberRange { order, limit, .. } => (BlockKey::Number, order, limit),
+        }
+    }
+}
+
+/// Iterates over the blocks matching a filter one page at a time, see [`Client::select_block_pages`]
+pub struct BlockPages<'a> {
+    conn: &'a mut PgConnection,
+    client: Client,
+    filter: BlockFilter,
+    page_size: i64,
+    /// slot or block number of the last block returned
+    cursor: Option<i64>,
+    /// number of blocks which may still be returned, when the filter has a limit
+    remaining: Option<i64>,
+    done: bool,
+}
+
+impl Iterator for BlockPages<'_> {
+    type Item = anyhow::Result<Vec<Blocks>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page_size = self
+            .remaining
+            .map_or(self.page_size, |remaining| remaining.min(self.page_size));
+        if page_size <= 0 {
+            self.done = true;
+            return None;
+        }
+        match self
+            .client
+            .load_blocks(self.conn, self.filter, self.cursor, Some(page_size))
+        {
+            Ok(page) => {
+                // a short page means there are no more matching blocks
+                if (page.len() as i64) < page_size {
+                    self.done = true;
+                }
+                let last = page.last()?;
+                self.cursor = match self.filter.ordering().0 {
+                    BlockKey::Slot => Some(last.slot),
+                    BlockKey::Number => last.number,
+                };
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining -= page.len() as i64;
+                }
+                Some(Ok(page))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Selects stored blocks by provenance, unset fields match every block
+#[derive(Clone, Debug, Default)]
+pub struct ProvenanceFilter {
+    pub source: Option<String>,
+    pub minimized: Option<bool>,
+    /// matches blocks with an encoding version below this, including blocks without an encoding version
+    pub encoding_version_below: Option<i32>,
+}
+
+/// Result of [`Client::insert_or_update_idl`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdlChange {
+    /// no idl version was active at the observed slot
+    Inserted,
+    /// a new version was stored, closing out the version which began at `previous_begin_height`
+    Updated { previous_begin_height: i64 },
+    /// the stored idl was identical
+    Unchanged,
+}
+
+/// Result of [`Client::replace_block`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplaceOutcome {
+    /// no block existed for the slot
+    Inserted,
+    /// the stored block was overwritten, containing the previously stored block
+    Replaced(Blocks),
+    /// the stored block data was identical, only the provenance was updated
+    Unchanged,
 }
 
 #[derive(Clone)]
@@ -44,31 +203,71 @@ impl Client {
             .with_context(|| "failed to select program ids")?;
         Ok(ids)
     }
-    /// Select a block matching against the block number or slot number
+    /// Select the blocks matching the filter
     pub fn select_block(
         self,
         conn: &mut PgConnection,
         filter: BlockFilter,
     ) -> anyhow::Result<Vec<Blocks>> {
-        use crate::schema::blocks;
-        match filter {
-            BlockFilter::Number(blk_num) => Ok(blocks::dsl::blocks
-                .filter(blocks::dsl::number.eq(blk_num))
-                .select(Blocks::as_select())
-                .get_results(conn)?),
-            BlockFilter::Slot(slot_num) => Ok(blocks::dsl::blocks
-                .filter(blocks::dsl::slot.eq(slot_num))
-                .select(Blocks::as_select())
-                .get_results(conn)?),
-            BlockFilter::FirstBlock => Ok(blocks::dsl::blocks
-                .order(blocks::dsl::number.asc())
-                .limit(1)
-                .select(Blocks::as_select())
-                .get_results(conn)?),
-            BlockFilter::All => Ok(blocks::dsl::blocks
-                .select(Blocks::as_select())
-                .get_results(conn)?),
+        let (_, _, limit) = filter.ordering();
+        self.load_blocks(conn, filter, None, limit)
+    }
+    /// Returns an iterator over the blocks matching the filter, loading at most `page_size` blocks at a time.
+    ///
+    /// Pages are read using the last returned slot or block number as a cursor, so scanning the whole
+    /// table uses bounded memory, and blocks inserted behind the cursor are not returned
+    pub fn select_block_pages(
+        self,
+        conn: &mut PgConnection,
+        filter: BlockFilter,
+        page_size: i64,
+    ) -> anyhow::Result<BlockPages<'_>> {
+        if page_size <= 0 {
+            return Err(anyhow!("page_size must be positive"));
+        }
+        let (_, _, limit) = filter.ordering();
+        Ok(BlockPages {
+            conn,
+            client: self,
+            filter,
+            page_size,
+            cursor: None,
+            remaining: limit,
+            done: false,
+        })
+    }
+    /// Loads the blocks matching the filter which are ordered after `cursor`
+    fn load_blocks(
+        self,
+        conn: &mut PgConnection,
+        filter: BlockFilter,
+        cursor: Option<i64>,
+        limit: Option<i64>,
+    ) -> anyhow::Result<Vec<Blocks>> {
+        use crate::schema::blocks::dsl::*;
+        let (key, order, _) = filter.ordering();
+        let mut query = blocks
+            .select(Blocks::as_select())
+            .filter(filter.predicate())
+            .into_boxed();
+        query = match (key, order) {
+            (BlockKey::Slot, BlockOrder::Ascending) => query.order(slot.asc()),
+            (BlockKey::Slot, BlockOrder::Descending) => query.order(slot.desc()),
+            (BlockKey::Number, BlockOrder::Ascending) => query.order(number.asc()),
+            (BlockKey::Number, BlockOrder::Descending) => query.order(number.desc()),
+        };
+        if let Some(cursor) = cursor {
+            query = match (key, order) {
+                (BlockKey::Slot, BlockOrder::Ascending) => query.filter(slot.gt(cursor)),
+                (BlockKey::Slot, BlockOrder::Descending) => query.filter(slot.lt(cursor)),
+                (BlockKey::Number, BlockOrder::Ascending) => query.filter(number.assume_not_null().gt(cursor)),
+                (BlockKey::Number, BlockOrder::Descending) => query.filter(number.assume_not_null().lt(cursor)),
+            };
         }
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        query.load(conn).with_context(|| "failed to select blocks")
     }
     pub fn select_squads<'a>(
         self,
@@ -84,54 +283,105 @@ impl Client {
             SquadsFilter::All => Ok(squads.select(Squads::as_select()).load(conn)?),
         }
     }
+    /// Records `program_idl` as the idl of `program_id`, as observed at `observed_slot`. `idl_kind` is
+    /// the dialect of the idl, and a changed kind is a new version even when the idl is identical.
+    ///
+    /// Idl versions are valid from their `begin_height` slot, up to but excluding their `end_height`
+    /// slot. A changed idl closes the version active at `observed_slot`, and starts a new version
+    /// there which lasts until the next stored version begins, so that versions reconstructed from
+    /// older blocks can be inserted before the current version
     pub fn insert_or_update_idl(
         self,
         conn: &mut PgConnection,
         program_id: String,
-        b_height: i64,
-        e_height: Option<i64>,
+        observed_slot: i64,
+        idl_kind: &str,
         program_idl: serde_json::Value,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<IdlChange> {
         use crate::schema::idls::dsl::*;
         conn.transaction::<_, anyhow::Error, _>(|conn| {
-            match idls
+            let versions = idls
                 .filter(id.eq(&program_id))
-                .filter(begin_height.eq(b_height))
-                .limit(1)
+                .order(begin_height.asc())
                 .select(Idls::as_select())
+                .for_update()
                 .load(conn)
-            {
-                Ok(mut idl_infos) => {
-                    if idl_infos.is_empty() {
-                        // new idl
-                        Idls {
-                            id: program_id,
-                            begin_height: b_height,
-                            end_height: e_height,
-                            idl: program_idl,
-                        }
-                        .insert_into(idls)
-                        .execute(conn)?;
-                    } else {
-                        // updated idl
-                        // todo: we need to set the end height of the old idl
-                        let mut idl_info: Idls = std::mem::take(&mut idl_infos[0]);
-                        idl_info.begin_height = b_height;
-                        idl_info.end_height = e_height;
-                        idl_info.idl = program_idl;
-                        diesel::update(
-                            idls.filter(id.eq(&program_id))
-                                .filter(begin_height.eq(b_height)),
-                        )
-                        .set(idl_info)
-                        .execute(conn)?;
-                    }
+                .with_context(|| "failed to select idl versions")?;
+            let prev = versions
+                .iter()
+                .rfind(|version| version.begin_height <= observed_slot);
+            let next_begin_height = versions
+                .iter()
+                .find(|version| version.begin_height > observed_slot)
+                .map(|version| version.begin_height);
+            match prev {
+                Some(prev) if prev.idl == program_idl && prev.kind == idl_kind => {
+                    return Ok(IdlChange::Unchanged)
                 }
-                Err(err) => return Err(anyhow!("failed to query db {err:#?}")),
+                // the idl changed again within the same slot
+                Some(prev) if prev.begin_height == observed_slot => {
+                    diesel::update(
+                        idls.filter(id.eq(&program_id))
+                            .filter(begin_height.eq(observed_slot)),
+                    )
+                    .set((idl.eq(program_idl), kind.eq(idl_kind)))
+                    .execute(conn)?;
+                    return Ok(IdlChange::Updated {
+                        previous_begin_height: observed_slot,
+                    });
+                }
+                Some(prev) => {
+                    diesel::update(
+                        idls.filter(id.eq(&program_id))
+                            .filter(begin_height.eq(prev.begin_height)),
+                    )
+                    .set(end_height.eq(Some(observed_slot)))
+                    .execute(conn)?;
+                }
+                None => (),
             }
-            Ok(())
-        })?;
-        Ok(())
+            NewIdl {
+                id: program_id,
+                begin_height: observed_slot,
+                end_height: next_begin_height,
+                idl: program_idl,
+                kind: idl_kind.to_string(),
+            }
+            .insert_into(idls)
+            .execute(conn)?;
+            Ok(match prev {
+                Some(prev) => IdlChange::Updated {
+                    previous_begin_height: prev.begin_height,
+                },
+                None => IdlChange::Inserted,
+            })
+        })
+    }
+    /// Returns the idl version of `program_id` which was active at `at_slot`, ie when a transaction in that slot executed
+    pub fn idl_at(
+        self,
+        conn: &mut PgConnection,
+        program_id: &str,
+        at_slot: i64,
+    ) -> anyhow::Result<Option<Idls>> {
+        use crate::schema::idls::dsl::*;
+        idls.filter(id.eq(program_id))
+            .filter(begin_height.le(at_slot))
+            .filter(end_height.is_null().or(end_height.gt(at_slot)))
+            .order(begin_height.desc())
+            .select(Idls::as_select())
+            .first(conn)
+            .optional()
+            .with_context(|| "failed to select idl")
+    }
+    /// Returns every idl version of `program_id`, oldest first
+    pub fn idl_versions(self, conn: &mut PgConnection, program_id: &str) -> anyhow::Result<Vec<Idls>> {
+        use crate::schema::idls::dsl::*;
+        idls.filter(id.eq(program_id))
+            .order(begin_height.asc())
+            .select(Idls::as_select())
+            .load(conn)
+            .with_context(|| "failed to select idl versions")
     }
     pub fn insert_or_update_program(
         self,
@@ -170,6 +420,7 @@ impl Client {
         })?;
         Ok(())
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn insert_or_update_squads(
         self,
         conn: &mut PgConnection,
@@ -236,13 +487,18 @@ impl Client {
         Ok(end_number)
     }
 
+    /// Inserts a block, `n` is None for blocks whose height is unknown.
+    ///
+    /// Inserting a block which is already stored is a no-op, but fails when the stored block has a
+    /// different reported height, or another slot is stored with height `n`
     pub fn insert_block(
         &self,
         conn: &mut PgConnection,
-        n: i64,
+        n: Option<i64>,
         s: i64,
         t: Option<DateTime<Utc>>,
         d: &serde_json::Value,
+        p: &Provenance,
     ) -> anyhow::Result<()> {
         use crate::schema::blocks::dsl::*;
         let res = NewBlock {
@@ -251,6 +507,11 @@ impl Client {
             time: t,
             processed: false,
             data: d,
+            source: Some(p.source.as_str()),
+            minimized: p.minimized,
+            ingested_at: Some(Utc::now()),
+            encoding_version: p.encoding_version,
+            height_derived: false,
         }
         .insert_into(blocks)
         .execute(conn);
@@ -262,19 +523,890 @@ impl Client {
                     // this ensure we don't persist data to disk which we have already indexed
                     //
                     // this can potentially occur if two indexing services try to index the same data
-                    DatabaseErrorKind::UniqueViolation => Ok(()),
+                    //
+                    // when the stored block has no height, or a derived one, the reported height is
+                    // recorded instead, ie when repairing gaps over blocks stored without a height
+                    DatabaseErrorKind::UniqueViolation => match n {
+                        Some(n) => {
+                            let updated = diesel::update(
+                                blocks
+                                    .filter(slot.eq(s))
+                                    .filter(number.is_null().or(height_derived.eq(true))),
+                            )
+                            .set((number.eq(n), height_derived.eq(false)))
+                            .execute(conn)
+                            .with_context(|| format!("failed to set height of block(slot={s})"))?;
+                            if updated > 0 {
+                                return Ok(());
+                            }
+                            let stored = blocks
+                                .filter(slot.eq(s))
+                                .select(number)
+                                .first::<Option<i64>>(conn)
+                                .optional()
+                                .with_context(|| format!("failed to select block(slot={s})"))?;
+                            match stored {
+                                Some(Some(stored)) if stored == n => Ok(()),
+                                Some(stored) => Err(anyhow!(
+                                    "block(slot={s}) is already stored with height {stored:?}, not {n}"
+                                )),
+                                // the violation was on the height, which another slot is stored with
+                                None => Err(anyhow!(
+                                    "block(slot={s}) height {n} is already stored for another slot"
+                                )),
+                            }
+                        }
+                        None => Ok(()),
+                    },
                     _ => Err(anyhow!("{:#?}", kind)),
                 }
             }
             Err(err) => Err(anyhow!("{err:#?}")),
         }
     }
+    /// Inserts the block, or atomically overwrites the block stored for the same slot.
+    ///
+    /// Unlike [`Client::insert_block`] an existing block is replaced, which is used to re-ingest
+    /// blocks with different options. Replaced blocks are marked unprocessed so that downstream
+    /// workers pick up the new data. When `n` is None the height of the existing block is kept,
+    /// and when it is set it replaces a derived height
+    pub fn replace_block(
+        &self,
+        conn: &mut PgConnection,
+        n: Option<i64>,
+        s: i64,
+        t: Option<DateTime<Utc>>,
+        d: &serde_json::Value,
+        p: &Provenance,
+    ) -> anyhow::Result<ReplaceOutcome> {
+        use crate::schema::blocks::dsl::*;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let existing = blocks
+                .filter(slot.eq(s))
+                .select(Blocks::as_select())
+                .for_update()
+                .first(conn)
+                .optional()
+                .with_context(|| format!("failed to select block(slot={s})"))?;
+            let Some(existing) = existing else {
+                NewBlock {
+                    number: n,
+                    slot: s,
+                    time: t,
+                    processed: false,
+                    data: d,
+                    source: Some(p.source.as_str()),
+                    minimized: p.minimized,
+                    ingested_at: Some(Utc::now()),
+                    encoding_version: p.encoding_version,
+                    height_derived: false,
+                }
+                .insert_into(blocks)
+                .execute(conn)
+                .with_context(|| format!("failed to insert block(slot={s})"))?;
+                return Ok(ReplaceOutcome::Inserted);
+            };
+            let (n, derived) = match n {
+                Some(n) => (Some(n), false),
+                None => (existing.number, existing.height_derived),
+            };
+            let provenance = (
+                source.eq(p.source.as_str()),
+                minimized.eq(p.minimized),
+                ingested_at.eq(Some(Utc::now())),
+                encoding_version.eq(p.encoding_version),
+                height_derived.eq(derived),
+            );
+            if existing.data == *d && existing.time == t && existing.number == n {
+                diesel::update(blocks.filter(slot.eq(s)))
+                    .set(provenance)
+                    .execute(conn)
+                    .with_context(|| format!("failed to update block(slot={s})"))?;
+                return Ok(ReplaceOutcome::Unchanged);
+            }
+            diesel::update(blocks.filter(slot.eq(s)))
+                .set((
+                    number.eq(n),
+                    time.eq(t),
+                    data.eq(d),
+                    processed.eq(false),
+                    attempts.eq(0),
+                    last_error.eq(None::<String>),
+                    provenance,
+                ))
+                .execute(conn)
+                .with_context(|| format!("failed to replace block(slot={s})"))?;
+            Ok(ReplaceOutcome::Replaced(existing))
+        })
+    }
+    /// Returns the slots of stored blocks within `[start_slot, end_slot]` matching the filter, ordered by slot
+    pub fn select_slots_by_provenance(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+        filter: &ProvenanceFilter,
+    ) -> anyhow::Result<Vec<i64>> {
+        use crate::schema::blocks::dsl::*;
+        let mut query = blocks
+            .filter(slot.ge(start_slot))
+            .filter(slot.le(end_slot))
+            .select(slot)
+            .order(slot.asc())
+            .into_boxed();
+        if let Some(src) = &filter.source {
+            query = query.filter(source.eq(src));
+        }
+        if let Some(min) = filter.minimized {
+            query = query.filter(minimized.eq(min));
+        }
+        if let Some(version) = filter.encoding_version_below {
+            query = query.filter(encoding_version.is_null().or(encoding_version.lt(version)));
+        }
+        query
+            .load(conn)
+            .with_context(|| "failed to select slots by provenance")
+    }
+    /// Returns a random sample of stored slots within `[start_slot, end_slot]`, ordered by slot.
+    ///
+    /// Each block is included with probability `sample_rate`, so `1.0` selects every block in the range.
+    /// When more than `limit` blocks are sampled, a random subset of them is returned rather than the
+    /// earliest, so the sample stays spread across the range
+    pub fn select_sampled_slots(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+        sample_rate: f64,
+        limit: Option<i64>,
+    ) -> anyhow::Result<Vec<i64>> {
+        use crate::schema::blocks::dsl::*;
+        use diesel::{dsl::sql, sql_types::Double};
+        let mut query = blocks
+            .filter(slot.ge(start_slot))
+            .filter(slot.le(end_slot))
+            .select(slot)
+            .into_boxed();
+        if sample_rate < 1.0 {
+            query = query.filter(sql::<Bool>("random() < ").bind::<Double, _>(sample_rate));
+        }
+        let mut slots: Vec<i64> = match limit {
+            Some(limit) => query.order(sql::<Double>("random()")).limit(limit).load(conn),
+            None => query.load(conn),
+        }
+        .with_context(|| "failed to select sampled slots")?;
+        slots.sort_unstable();
+        Ok(slots)
+    }
+    /// Sets the heights of blocks stored without one, given as `(slot, number)` pairs.
+    ///
+    /// Blocks which already have a height are left untouched. Returns the number of blocks updated
+    pub fn set_derived_block_heights(
+        self,
+        conn: &mut PgConnection,
+        heights: &[(i64, i64)],
+    ) -> anyhow::Result<usize> {
+        use crate::schema::blocks::dsl::*;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let mut updated = 0;
+            for (block_slot, block_number) in heights {
+                updated += diesel::update(blocks.filter(slot.eq(block_slot)).filter(number.is_null()))
+                    .set((number.eq(block_number), height_derived.eq(true)))
+                    .execute(conn)
+                    .with_context(|| format!("failed to set derived height of block(slot={block_slot})"))?;
+            }
+            Ok(updated)
+        })
+    }
+    /// Returns the block heights which may fall within `[start_slot, end_slot]`.
+    ///
+    /// The range extends to the heights adjacent to the nearest stored blocks before and after the
+    /// slot range, so that missing blocks at either end of it are included. Returns None if no
+    /// block with a height bounds the range
+    pub fn height_range_for_slots(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+    ) -> anyhow::Result<Option<(i64, i64)>> {
+        use crate::schema::blocks::dsl::*;
+        if end_slot < start_slot {
+            return Err(anyhow!("end_slot({end_slot}) < start_slot({start_slot})"));
+        }
+        let heights = || blocks.filter(number.is_not_null()).select(number);
+        let prev = heights()
+            .filter(slot.lt(start_slot))
+            .order(slot.desc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let first = heights()
+            .filter(slot.ge(start_slot))
+            .filter(slot.le(end_slot))
+            .order(slot.asc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let last = heights()
+            .filter(slot.ge(start_slot))
+            .filter(slot.le(end_slot))
+            .order(slot.desc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let next = heights()
+            .filter(slot.gt(end_slot))
+            .order(slot.asc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        Ok(bounding_heights(
+            prev.flatten(),
+            first.flatten(),
+            last.flatten(),
+            next.flatten(),
+        ))
+    }
+    /// Returns the block heights which may fall within `[start, end]`, see [`Client::height_range_for_slots`]
+    pub fn height_range_for_times(
+        self,
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Option<(i64, i64)>> {
+        use crate::schema::blocks::dsl::*;
+        if end < start {
+            return Err(anyhow!("end({end}) < start({start})"));
+        }
+        let heights = || blocks.filter(number.is_not_null()).select(number);
+        let prev = heights()
+            .filter(time.lt(start))
+            .order(time.desc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let first = heights()
+            .filter(time.ge(start))
+            .filter(time.le(end))
+            .order(time.asc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let last = heights()
+            .filter(time.ge(start))
+            .filter(time.le(end))
+            .order(time.desc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        let next = heights()
+            .filter(time.gt(end))
+            .order(time.asc())
+            .first::<Option<i64>>(conn)
+            .optional()?;
+        Ok(bounding_heights(
+            prev.flatten(),
+            first.flatten(),
+            last.flatten(),
+            next.flatten(),
+        ))
+    }
+    /// Returns the lowest and highest stored block heights, None if no block has a height
+    pub fn stored_height_range(self, conn: &mut PgConnection) -> anyhow::Result<Option<(i64, i64)>> {
+        use crate::schema::blocks::dsl::*;
+        use diesel::dsl::{max, min};
+        let (lowest, highest) = blocks
+            .select((min(number), max(number)))
+            .first::<(Option<i64>, Option<i64>)>(conn)
+            .with_context(|| "failed to select stored height range")?;
+        Ok(lowest.zip(highest))
+    }
+    /// Returns the number of stored blocks without a height within `[start_slot, end_slot]`
+    pub fn count_blocks_missing_height(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+    ) -> anyhow::Result<i64> {
+        use crate::schema::blocks::dsl::*;
+        blocks
+            .filter(slot.ge(start_slot))
+            .filter(slot.le(end_slot))
+            .filter(number.is_null())
+            .count()
+            .get_result(conn)
+            .with_context(|| "failed to count blocks missing height")
+    }
+    /// Returns the lowest and highest slots of blocks stored with a time within `[start, end)`
+    pub fn slot_range_for_times(
+        self,
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Option<(i64, i64)>> {
+        use crate::schema::blocks::dsl::*;
+        use diesel::dsl::{max, min};
+        let (lowest, highest) = blocks
+            .filter(time.ge(start))
+            .filter(time.lt(end))
+            .select((min(slot), max(slot)))
+            .first::<(Option<i64>, Option<i64>)>(conn)
+            .with_context(|| "failed to select slot range for times")?;
+        Ok(lowest.zip(highest))
+    }
+    /// Counts the stored blocks and known skipped slots within `[start_slot, end_slot]`.
+    ///
+    /// Slots between two stored blocks with consecutive heights can't contain a block, so they are
+    /// known to be skipped. The nearest stored blocks outside of the range are included so that
+    /// skipped slots at either end of the range are counted
+    pub fn slot_coverage(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+    ) -> anyhow::Result<SlotCoverage> {
+        if end_slot < start_slot {
+            return Err(anyhow!("end_slot({end_slot}) < start_slot({start_slot})"));
+        }
+        sql_query(
+            "WITH stored AS (
+                SELECT slot, number FROM blocks WHERE slot BETWEEN $1 AND $2
+                UNION ALL
+                (SELECT slot, number FROM blocks WHERE slot < $1 ORDER BY slot DESC LIMIT 1)
+                UNION ALL
+                (SELECT slot, number FROM blocks WHERE slot > $2 ORDER BY slot ASC LIMIT 1)
+            ), windowed AS (
+                SELECT
+                    slot,
+                    number,
+                    LAG(slot) OVER (ORDER BY slot) AS prev_slot,
+                    LAG(number) OVER (ORDER BY slot) AS prev_number
+                FROM stored
+            )
+            SELECT
+                COUNT(*) FILTER (WHERE slot BETWEEN $1 AND $2) AS stored_blocks,
+                COUNT(*) FILTER (WHERE slot BETWEEN $1 AND $2 AND number IS NULL) AS missing_height,
+                COALESCE(
+                    SUM(GREATEST(LEAST(slot, $2 + 1) - GREATEST(prev_slot + 1, $1), 0))
+                        FILTER (WHERE number = prev_number + 1),
+                    0
+                )::BIGINT AS known_skipped
+            FROM windowed",
+        )
+        .bind::<BigInt, _>(start_slot)
+        .bind::<BigInt, _>(end_slot)
+        .get_result(conn)
+        .with_context(|| "failed to select slot coverage")
+    }
+    /// Returns all blocks whose slot falls within `[start_slot, end_slot]`, ordered by slot
+    pub fn select_blocks_by_slot_range(
+        self,
+        conn: &mut PgConnection,
+        start_slot: i64,
+        end_slot: i64,
+    ) -> anyhow::Result<Vec<Blocks>> {
+        self.select_block(
+            conn,
+            BlockFilter::SlotRange {
+                start: start_slot,
+                end: end_slot,
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+        )
+    }
+    /// Returns all blocks whose block height falls within `[start_number, end_number]`, ordered by block height
+    pub fn select_blocks_by_number_range(
+        self,
+        conn: &mut PgConnection,
+        start_number: i64,
+        end_number: i64,
+    ) -> anyhow::Result<Vec<Blocks>> {
+        self.select_block(
+            conn,
+            BlockFilter::NumberRange {
+                start: start_number,
+                end: end_number,
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+        )
+    }
+    /// Deletes the blocks with the given slots, returning the number of rows removed
+    pub fn delete_blocks_by_slot(
+        self,
+        conn: &mut PgConnection,
+        slots: &[i64],
+    ) -> anyhow::Result<usize> {
+        use crate::schema::blocks;
+        diesel::delete(blocks::dsl::blocks.filter(blocks::dsl::slot.eq_any(slots)))
+            .execute(conn)
+            .with_context(|| "failed to delete blocks")
+    }
+    /// Claims up to `limit` unprocessed blocks, ordered by slot, for processing by the caller.
+    ///
+    /// Rows are selected with `FOR UPDATE SKIP LOCKED` so that concurrent workers never claim the same
+    /// block. A claim lasts for `lease`, after which the block can be claimed again if it was neither marked
+    /// processed nor failed, for example because the worker crashed. Every claim counts as an attempt, and
+    /// blocks which have been attempted `max_attempts` times are no longer returned.
+    pub fn claim_unprocessed_blocks(
+        self,
+        conn: &mut PgConnection,
+        limit: i64,
+        lease: chrono::Duration,
+        max_attempts: i32,
+    ) -> anyhow::Result<Vec<Blocks>> {
+        use crate::schema::blocks::dsl::*;
+        let now = Utc::now();
+        let mut claimed = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let slots: Vec<i64> = blocks
+                .filter(processed.eq(false))
+                .filter(attempts.lt(max_attempts))
+                .filter(claimed_at.is_null().or(claimed_at.lt(now - lease)))
+                .order(slot.asc())
+                .limit(limit)
+                .select(slot)
+                .for_update()
+                .skip_locked()
+                .load(conn)
+                .with_context(|| "failed to select unprocessed blocks")?;
+            if slots.is_empty() {
+                return Ok(vec![]);
+            }
+            diesel::update(blocks.filter(slot.eq_any(&slots)))
+                .set((claimed_at.eq(now), attempts.eq(attempts + 1)))
+                .returning(Blocks::as_returning())
+                .get_results(conn)
+                .with_context(|| "failed to claim blocks")
+        })?;
+        claimed.sort_unstable_by_key(|block| block.slot);
+        Ok(claimed)
+    }
+    /// Marks the claimed blocks with the given slots as processed, returning the number of blocks updated
+    pub fn mark_blocks_processed(
+        self,
+        conn: &mut PgConnection,
+        slots: &[i64],
+    ) -> anyhow::Result<usize> {
+        use crate::schema::blocks::dsl::*;
+        diesel::update(blocks.filter(slot.eq_any(slots)))
+            .set((
+                processed.eq(true),
+                claimed_at.eq(None::<DateTime<Utc>>),
+                last_error.eq(None::<String>),
+            ))
+            .execute(conn)
+            .with_context(|| "failed to mark blocks processed")
+    }
+    /// Releases the claim on a block which failed to be processed, recording the error.
+    ///
+    /// The block can be claimed again immediately unless it has reached the max attempts
+    pub fn record_block_failure(
+        self,
+        conn: &mut PgConnection,
+        block_slot: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        use crate::schema::blocks::dsl::*;
+        diesel::update(blocks.filter(slot.eq(block_slot)))
+            .set((claimed_at.eq(None::<DateTime<Utc>>), last_error.eq(error)))
+            .execute(conn)
+            .with_context(|| format!("failed to record failure for block(slot={block_slot})"))?;
+        Ok(())
+    }
+    /// Returns the slot, attempt count and last error for blocks which failed processing at least once
+    pub fn select_failed_blocks(
+        self,
+        conn: &mut PgConnection,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i64, i32, Option<String>)>> {
+        use crate::schema::blocks::dsl::*;
+        blocks
+            .filter(processed.eq(false))
+            .filter(last_error.is_not_null())
+            .order(slot.asc())
+            .limit(limit)
+            .select((slot, attempts, last_error))
+            .load(conn)
+            .with_context(|| "failed to select failed blocks")
+    }
+    pub fn insert_block_verification(
+        self,
+        conn: &mut PgConnection,
+        verification: &NewBlockVerification,
+    ) -> anyhow::Result<()> {
+        use crate::schema::block_verifications::dsl::*;
+        diesel::insert_into(block_verifications)
+            .values(verification)
+            .execute(conn)
+            .with_context(|| format!("failed to insert verification for block({})", verification.slot))?;
+        Ok(())
+    }
+    /// Returns the most recent verification results, optionally only those with the given status
+    pub fn select_block_verifications(
+        self,
+        conn: &mut PgConnection,
+        with_status: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<BlockVerifications>> {
+        use crate::schema::block_verifications::dsl::*;
+        let mut query = block_verifications
+            .order(id.desc())
+            .limit(limit)
+            .select(BlockVerifications::as_select())
+            .into_boxed();
+        if let Some(with_status) = with_status {
+            query = query.filter(status.eq(with_status));
+        }
+        query
+            .load(conn)
+            .with_context(|| "failed to select block verifications")
+    }
+    pub fn insert_gap_repair(self, conn: &mut PgConnection, repair: &NewGapRepair) -> anyhow::Result<()> {
+        use crate::schema::gap_repairs::dsl::*;
+        diesel::insert_into(gap_repairs)
+            .values(repair)
+            .execute(conn)
+            .with_context(|| {
+                format!("failed to insert repair of gap({}..={})", repair.gap_start, repair.gap_end)
+            })?;
+        Ok(())
+    }
+    /// Returns the most recent gap repair attempts, optionally only those with the given status
+    pub fn select_gap_repairs(
+        self,
+        conn: &mut PgConnection,
+        with_status: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<GapRepairs>> {
+        use crate::schema::gap_repairs::dsl::*;
+        let mut query = gap_repairs
+            .order(id.desc())
+            .limit(limit)
+            .select(GapRepairs::as_select())
+            .into_boxed();
+        if let Some(with_status) = with_status {
+            query = query.filter(status.eq(with_status));
+        }
+        query.load(conn).with_context(|| "failed to select gap repairs")
+    }
+    /// Returns the high-water mark of `table` replicated from `source`, if replication has started
+    pub fn select_replication_hwm(
+        self,
+        conn: &mut PgConnection,
+        src: &str,
+        table: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        use crate::schema::replication_state::dsl::*;
+        replication_state
+            .filter(source.eq(src))
+            .filter(table_name.eq(table))
+            .select(high_water_mark)
+            .first(conn)
+            .optional()
+            .with_context(|| format!("failed to select replication state for {table}"))
+    }
+    pub fn update_replication_hwm(
+        self,
+        conn: &mut PgConnection,
+        src: &str,
+        table: &str,
+        hwm: i64,
+    ) -> anyhow::Result<()> {
+        use crate::schema::replication_state::dsl::*;
+        diesel::insert_into(replication_state)
+            .values((
+                source.eq(src),
+                table_name.eq(table),
+                high_water_mark.eq(hwm),
+                updated_at.eq(Utc::now()),
+            ))
+            .on_conflict((source, table_name))
+            .do_update()
+            .set((high_water_mark.eq(hwm), updated_at.eq(Utc::now())))
+            .execute(conn)
+            .with_context(|| format!("failed to update replication state for {table}"))?;
+        Ok(())
+    }
+    /// Returns a fence below which every change id has either committed or rolled back once
+    /// [`Client::replication_fence_complete`] returns true.
+    ///
+    /// Change ids are taken from a sequence when a row is written, so a transaction can hold a lower
+    /// change id than a row which has already committed. Replicating only up to a complete fence
+    /// ensures that such rows are not skipped once the high-water mark has moved past them.
+    /// Must not be called within a transaction
+    pub fn replication_fence(self, conn: &mut PgConnection) -> anyhow::Result<ReplicationFence> {
+        let ChangeId { change_id } = sql_query(
+            "SELECT CASE WHEN is_called THEN last_value ELSE 0 END AS change_id FROM replication_change_id_seq",
+        )
+        .get_result::<ChangeId>(conn)
+        .with_context(|| "failed to select last change id")?;
+        // the trigger assigns an xid before taking a change id, so every transaction holding a
+        // change id up to `change_id` has an xid below the xmax of a snapshot taken afterwards
+        let Xid { xid } =
+            sql_query("SELECT pg_snapshot_xmax(pg_current_snapshot())::text::bigint AS xid")
+                .get_result::<Xid>(conn)
+                .with_context(|| "failed to select snapshot xmax")?;
+        Ok(ReplicationFence { change_id, xmax: xid })
+    }
+    /// Returns true once every transaction which could hold a change id below `fence` has finished
+    pub fn replication_fence_complete(
+        self,
+        conn: &mut PgConnection,
+        fence: ReplicationFence,
+    ) -> anyhow::Result<bool> {
+        let Xid { xid } =
+            sql_query("SELECT pg_snapshot_xmin(pg_current_snapshot())::text::bigint AS xid")
+                .get_result::<Xid>(conn)
+                .with_context(|| "failed to select snapshot xmin")?;
+        Ok(xid >= fence.xmax)
+    }
+    /// Returns up to `limit` blocks without a change id with a slot above `after`, ordered by slot.
+    ///
+    /// Blocks stored before change ids were assigned to blocks are never modified without being
+    /// assigned one, so they can be replicated by slot
+    pub fn select_blocks_after(
+        self,
+        conn: &mut PgConnection,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Blocks>> {
+        use crate::schema::blocks;
+        Ok(blocks::dsl::blocks
+            .filter(blocks::dsl::change_id.is_null())
+            .filter(blocks::dsl::slot.gt(after))
+            .order(blocks::dsl::slot.asc())
+            .limit(limit)
+            .select(Blocks::as_select())
+            .get_results(conn)?)
+    }
+    /// Returns up to `limit` blocks which changed after `after` and up to `up_to`, ordered by change id
+    pub fn select_blocks_changed_after(
+        self,
+        conn: &mut PgConnection,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(Blocks, i64)>> {
+        use crate::schema::blocks::dsl::*;
+        blocks
+            .filter(change_id.gt(after))
+            .filter(change_id.le(up_to))
+            .order(change_id.asc())
+            .limit(limit)
+            .select((Blocks::as_select(), change_id.assume_not_null()))
+            .load(conn)
+            .with_context(|| "failed to select changed blocks")
+    }
+    /// Upserts replicated blocks, replacing the stored block of existing slots while keeping their
+    /// work queue state. Replaced blocks whose data changed are marked unprocessed, as with
+    /// [`Client::replace_block`]. Returns the number of blocks written
+    pub fn upsert_replicated_blocks(
+        self,
+        conn: &mut PgConnection,
+        replicated: &[Blocks],
+    ) -> anyhow::Result<usize> {
+        use crate::schema::blocks::dsl::*;
+        use diesel::upsert::excluded;
+        let new_blocks = replicated
+            .iter()
+            .map(|block| NewBlock {
+                number: block.number,
+                slot: block.slot,
+                time: block.time,
+                processed: block.processed,
+                data: &block.data,
+                source: block.source.as_deref(),
+                minimized: block.minimized,
+                ingested_at: block.ingested_at,
+                encoding_version: block.encoding_version,
+                height_derived: block.height_derived,
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(blocks)
+            .values(&new_blocks)
+            .on_conflict(slot)
+            .do_update()
+            .set((
+                number.eq(excluded(number)),
+                time.eq(excluded(time)),
+                processed.eq(processed.and(data.eq(excluded(data)))),
+                data.eq(excluded(data)),
+                source.eq(excluded(source)),
+                minimized.eq(excluded(minimized)),
+                ingested_at.eq(excluded(ingested_at)),
+                encoding_version.eq(excluded(encoding_version)),
+                height_derived.eq(excluded(height_derived)),
+            ))
+            .execute(conn)
+            .with_context(|| "failed to upsert replicated blocks")
+    }
+    /// Returns up to `limit` idls which changed after `after` and up to `up_to`, ordered by change id
+    pub fn select_idls_changed_after(
+        self,
+        conn: &mut PgConnection,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(Idls, i64)>> {
+        use crate::schema::idls::dsl::*;
+        idls.filter(change_id.gt(after))
+            .filter(change_id.le(up_to))
+            .order(change_id.asc())
+            .limit(limit)
+            .select((Idls::as_select(), change_id))
+            .load(conn)
+            .with_context(|| "failed to select changed idls")
+    }
+    pub fn upsert_idls(self, conn: &mut PgConnection, rows: &[Idls]) -> anyhow::Result<usize> {
+        use crate::schema::idls::dsl::*;
+        use diesel::upsert::excluded;
+        diesel::insert_into(idls)
+            .values(rows)
+            .on_conflict((id, begin_height))
+            .do_update()
+            .set((
+                end_height.eq(excluded(end_height)),
+                idl.eq(excluded(idl)),
+                kind.eq(excluded(kind)),
+            ))
+            .execute(conn)
+            .with_context(|| "failed to upsert idls")
+    }
+    /// Returns up to `limit` programs which changed after `after` and up to `up_to`, ordered by change id
+    pub fn select_programs_changed_after(
+        self,
+        conn: &mut PgConnection,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(Programs, i64)>> {
+        use crate::schema::programs::dsl::*;
+        programs
+            .filter(change_id.gt(after))
+            .filter(change_id.le(up_to))
+            .order(change_id.asc())
+            .limit(limit)
+            .select((Programs::as_select(), change_id))
+            .load(conn)
+            .with_context(|| "failed to select changed programs")
+    }
+    pub fn upsert_programs(self, conn: &mut PgConnection, rows: &[Programs]) -> anyhow::Result<usize> {
+        use crate::schema::programs::dsl::*;
+        use diesel::upsert::excluded;
+        diesel::insert_into(programs)
+            .values(rows)
+            .on_conflict((id, last_deployed_slot))
+            .do_update()
+            .set((
+                executable_account.eq(excluded(executable_account)),
+                executable_data.eq(excluded(executable_data)),
+            ))
+            .execute(conn)
+            .with_context(|| "failed to upsert programs")
+    }
+    /// Returns up to `limit` multisigs which changed after `after` and up to `up_to`, ordered by change id
+    pub fn select_squads_changed_after(
+        self,
+        conn: &mut PgConnection,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(Squads, i64)>> {
+        use crate::schema::squads::dsl::*;
+        squads
+            .filter(change_id.gt(after))
+            .filter(change_id.le(up_to))
+            .order(change_id.asc())
+            .limit(limit)
+            .select((Squads::as_select(), change_id))
+            .load(conn)
+            .with_context(|| "failed to select changed squads")
+    }
+    pub fn upsert_squads(self, conn: &mut PgConnection, rows: &[Squads]) -> anyhow::Result<usize> {
+        use crate::schema::squads::dsl::*;
+        use diesel::upsert::excluded;
+        diesel::insert_into(squads)
+            .values(rows)
+            .on_conflict(account)
+            .do_update()
+            .set((
+                vaults.eq(excluded(vaults)),
+                members.eq(excluded(members)),
+                threshold.eq(excluded(threshold)),
+                program_version.eq(excluded(program_version)),
+                voting_members_count.eq(excluded(voting_members_count)),
+            ))
+            .execute(conn)
+            .with_context(|| "failed to upsert squads")
+    }
+    /// Returns multisigs by account
+    pub fn select_squads_by_accounts(
+        self,
+        conn: &mut PgConnection,
+        accounts: &[String],
+    ) -> anyhow::Result<Vec<Squads>> {
+        use crate::schema::squads::dsl::*;
+        squads
+            .filter(account.eq_any(accounts))
+            .select(Squads::as_select())
+            .load(conn)
+            .with_context(|| "failed to select squads")
+    }
+    /// Records a row of `table` which could not be replicated from `source`, replacing any earlier error
+    pub fn insert_replication_failure(
+        self,
+        conn: &mut PgConnection,
+        src: &str,
+        table: &str,
+        key: &str,
+        err: &str,
+    ) -> anyhow::Result<()> {
+        use crate::schema::replication_failures::dsl::*;
+        diesel::insert_into(replication_failures)
+            .values((
+                source.eq(src),
+                table_name.eq(table),
+                row_key.eq(key),
+                error.eq(err),
+                failed_at.eq(Utc::now()),
+            ))
+            .on_conflict((source, table_name, row_key))
+            .do_update()
+            .set((error.eq(err), failed_at.eq(Utc::now())))
+            .execute(conn)
+            .with_context(|| format!("failed to record replication failure for {table}({key})"))?;
+        Ok(())
+    }
+    pub fn delete_replication_failure(
+        self,
+        conn: &mut PgConnection,
+        src: &str,
+        table: &str,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        use crate::schema::replication_failures::dsl::*;
+        diesel::delete(
+            replication_failures
+                .filter(source.eq(src))
+                .filter(table_name.eq(table))
+                .filter(row_key.eq(key)),
+        )
+        .execute(conn)
+        .with_context(|| format!("failed to delete replication failure for {table}({key})"))?;
+        Ok(())
+    }
+    /// Returns the keys of rows of `table` which could not be replicated from `source`
+    pub fn select_replication_failures(
+        self,
+        conn: &mut PgConnection,
+        src: &str,
+        table: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        use crate::schema::replication_failures::dsl::*;
+        replication_failures
+            .filter(source.eq(src))
+            .filter(table_name.eq(table))
+            .order(row_key.asc())
+            .select(row_key)
+            .load(conn)
+            .with_context(|| format!("failed to select replication failures for {table}"))
+    }
     pub fn find_gaps(&self, conn: &mut PgConnection, start_height: i64, end_height: i64, limit: Option<i64>) -> anyhow::Result<Vec<i64>> {
-        let limit = if let Some(limit) = limit {
-            limit
-        } else {
-            100
-        };
+        let limit = limit.unwrap_or(100);
         // generally speaking one should never use formatted inputs like this
         // due to sql injection vulnerabilities, but as this is initiated from 
         // backend services, and we don't store personal/sensitive information
@@ -292,6 +1424,128 @@ impl Client {
         )).load::<Gaps>(conn)?;
         Ok(gaps.into_iter().map(|g| g.number).collect::<Vec<_>>())
     }
+    /// Returns the ranges of block heights within `[start_height, end_height]` which have no stored block.
+    ///
+    /// Gaps are found with a single scan over the stored heights, comparing each block with the next
+    /// stored block. Ranges are clipped to `[start_height, end_height]`, and include the slots of the
+    /// stored blocks on either side of the gap, which may lie outside of the range
+    pub fn find_gap_ranges(
+        self,
+        conn: &mut PgConnection,
+        start_height: i64,
+        end_height: i64,
+    ) -> anyhow::Result<Vec<GapRange>> {
+        if end_height < start_height {
+            return Err(anyhow!("end_height({end_height}) < start_height({start_height})"));
+        }
+        // the nearest blocks outside of the range bound gaps at either end of it. when there is
+        // no such block, a sentinel without a slot is used instead
+        sql_query(
+            "WITH stored AS (
+                SELECT number, slot FROM blocks WHERE number BETWEEN $1 AND $2
+                UNION ALL
+                (SELECT number, slot FROM blocks WHERE number < $1 ORDER BY number DESC LIMIT 1)
+                UNION ALL
+                (SELECT number, slot FROM blocks WHERE number > $2 ORDER BY number ASC LIMIT 1)
+            ), bounded AS (
+                SELECT number, slot FROM stored
+                UNION ALL
+                SELECT $1 - 1, NULL WHERE NOT EXISTS (SELECT 1 FROM stored WHERE number < $1)
+                UNION ALL
+                SELECT $2 + 1, NULL WHERE NOT EXISTS (SELECT 1 FROM stored WHERE number > $2)
+            ), windowed AS (
+                SELECT
+                    number,
+                    slot,
+                    LEAD(number) OVER (ORDER BY number) AS next_number,
+                    LEAD(slot) OVER (ORDER BY number) AS next_slot
+                FROM bounded
+            )
+            SELECT
+                GREATEST(number + 1, $1) AS gap_start,
+                LEAST(next_number - 1, $2) AS gap_end,
+                slot AS prev_slot,
+                next_slot
+            FROM windowed
+            WHERE next_number > number + 1 AND number < $2 AND next_number > $1
+            ORDER BY number",
+        )
+        .bind::<BigInt, _>(start_height)
+        .bind::<BigInt, _>(end_height)
+        .load::<GapRange>(conn)
+        .with_context(|| "failed to find gap ranges")
+    }
+}
+
+/// Combines the heights of the stored blocks nearest to a range into the heights to scan for it.
+///
+/// `prev` and `next` are the heights of the nearest blocks outside of the range, and `first` and
+/// `last` the lowest and highest heights within it
+fn bounding_heights(
+    prev: Option<i64>,
+    first: Option<i64>,
+    last: Option<i64>,
+    next: Option<i64>,
+) -> Option<(i64, i64)> {
+    let start = prev.map(|prev| prev + 1).or(first)?;
+    let end = next.map(|next| next - 1).or(last)?;
+    (start <= end).then_some((start, end))
+}
+
+/// Point in the change id sequence, see [`Client::replication_fence`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplicationFence {
+    /// last change id assigned when the fence was taken
+    pub change_id: i64,
+    /// every transaction which could hold a change id up to `change_id` has an xid below this
+    pub xmax: i64,
+}
+
+#[derive(QueryableByName)]
+struct ChangeId {
+    #[diesel(sql_type = BigInt)]
+    change_id: i64,
+}
+
+#[derive(QueryableByName)]
+struct Xid {
+    #[diesel(sql_type = BigInt)]
+    xid: i64,
+}
+
+/// Stored block counts for a range of slots, see [`Client::slot_coverage`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, QueryableByName, serde::Serialize)]
+pub struct SlotCoverage {
+    #[diesel(sql_type = BigInt)]
+    pub stored_blocks: i64,
+    /// stored blocks without a block height
+    #[diesel(sql_type = BigInt)]
+    pub missing_height: i64,
+    /// slots between stored blocks with consecutive heights
+    #[diesel(sql_type = BigInt)]
+    pub known_skipped: i64,
+}
+
+/// A range of consecutive block heights without a stored block
+#[derive(Clone, Debug, PartialEq, Eq, QueryableByName, serde::Serialize)]
+pub struct GapRange {
+    #[diesel(sql_type = BigInt)]
+    pub gap_start: i64,
+    #[diesel(sql_type = BigInt)]
+    pub gap_end: i64,
+    /// slot of the stored block preceding the gap, None if there is no earlier block
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub prev_slot: Option<i64>,
+    /// slot of the stored block following the gap, None if there is no later block
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub next_slot: Option<i64>,
+}
+
+impl GapRange {
+    /// number of missing blocks in the range
+    pub fn missing(&self) -> i64 {
+        self.gap_end - self.gap_start + 1
+    }
 }
 
 #[derive(Debug, QueryableByName, Queryable)]
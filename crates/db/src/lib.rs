@@ -7,6 +7,7 @@ use {
     },
 };
 //use diesel::{Connection, PgConnection};
+pub mod async_client;
 pub mod client;
 pub mod migrations;
 pub mod models;
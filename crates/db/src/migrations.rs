@@ -1,9 +1,109 @@
-use diesel::PgConnection;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use diesel::{pg::Pg, PgConnection};
+use diesel::migration::MigrationSource;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
-/// run_migrations is inteded to cause a panic if the migration fails, so that it causes any application startup to fail
+/// whether [`run_migrations`] applies pending migrations, see [`set_auto_migrate`]
+static AUTO_MIGRATE: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether [`run_migrations`] applies pending migrations. When disabled, migrations must be
+/// applied explicitly with [`migrate`], and [`run_migrations`] only checks that none are pending
+pub fn set_auto_migrate(enabled: bool) {
+    AUTO_MIGRATE.store(enabled, Ordering::SeqCst);
+}
+
+/// run_migrations is inteded to cause a panic if the migration fails, so that it causes any application startup to fail.
+///
+/// When auto migration is disabled it panics if any migration is pending instead
 pub fn run_migrations(conn: &mut PgConnection) {
-    conn.run_pending_migrations(MIGRATIONS).unwrap();
+    if AUTO_MIGRATE.load(Ordering::SeqCst) {
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        return;
+    }
+    let pending = pending_names(conn).unwrap();
+    if !pending.is_empty() {
+        panic!("auto migration is disabled, and migrations are pending {pending:?}. apply them with `db migrate`");
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct MigrationStatus {
+    /// name of the migration directory, ie 2024-07-12-165722_programs
+    pub name: String,
+    pub applied: bool,
+}
+
+/// Applies all pending migrations, returning the names of the applied migrations
+pub fn migrate(conn: &mut PgConnection) -> Result<Vec<String>> {
+    let pending = pending_names(conn)?;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(|err| anyhow!("failed to apply migrations {err:#?}"))?;
+    Ok(pending)
+}
+
+/// Reverts the last `n` applied migrations, returning the names of the reverted migrations.
+/// Fewer migrations are reverted when less than `n` are applied
+pub fn rollback(conn: &mut PgConnection, n: usize) -> Result<Vec<String>> {
+    let mut reverted = vec![];
+    for _ in 0..n {
+        let Some(last) = applied_names(conn)?.pop() else {
+            break;
+        };
+        conn.revert_last_migration(MIGRATIONS)
+            .map_err(|err| anyhow!("failed to revert migration({last}) {err:#?}"))?;
+        reverted.push(last);
+    }
+    Ok(reverted)
+}
+
+/// Reverts and re-applies the last applied migration, returning its name
+pub fn redo(conn: &mut PgConnection) -> Result<String> {
+    let Some(last) = applied_names(conn)?.pop() else {
+        return Err(anyhow!("no migrations are applied"));
+    };
+    conn.revert_last_migration(MIGRATIONS)
+        .map_err(|err| anyhow!("failed to revert migration({last}) {err:#?}"))?;
+    conn.run_next_migration(MIGRATIONS)
+        .map_err(|err| anyhow!("failed to apply migration({last}) {err:#?}"))?;
+    Ok(last)
+}
+
+/// Returns every embedded migration in order, along with whether it is applied
+pub fn migration_status(conn: &mut PgConnection) -> Result<Vec<MigrationStatus>> {
+    let applied = conn
+        .applied_migrations()
+        .map_err(|err| anyhow!("failed to select applied migrations {err:#?}"))?
+        .into_iter()
+        .map(|version| version.to_string())
+        .collect::<Vec<_>>();
+    let mut migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .map_err(|err| anyhow!("failed to load migrations {err:#?}"))?;
+    migrations.sort_by_key(|migration| migration.name().version().to_string());
+    Ok(migrations
+        .into_iter()
+        .map(|migration| MigrationStatus {
+            name: migration.name().to_string(),
+            applied: applied.contains(&migration.name().version().to_string()),
+        })
+        .collect())
+}
+
+fn applied_names(conn: &mut PgConnection) -> Result<Vec<String>> {
+    Ok(migration_status(conn)?
+        .into_iter()
+        .filter(|migration| migration.applied)
+        .map(|migration| migration.name)
+        .collect())
+}
+
+fn pending_names(conn: &mut PgConnection) -> Result<Vec<String>> {
+    Ok(migration_status(conn)?
+        .into_iter()
+        .filter(|migration| !migration.applied)
+        .map(|migration| migration.name)
+        .collect())
 }
@@ -1,26 +1,110 @@
-use {anyhow::anyhow, chrono::{DateTime, Utc}, diesel::prelude::*, uuid::Uuid};
+use {chrono::{DateTime, Utc}, diesel::prelude::*};
 
 
 #[derive(
-    Queryable, AsChangeset, Identifiable, Debug, Clone, Selectable, Default, PartialEq, Eq,
+    Queryable,
+    AsChangeset,
+    Identifiable,
+    Debug,
+    Clone,
+    Selectable,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
 )]
 #[diesel(table_name = super::schema::blocks)]
-#[diesel(primary_key(number))]
+#[diesel(primary_key(slot))]
 pub struct Blocks {
-    pub number: i64,
+    /// block height, None for blocks ingested without a height which could not be derived
+    pub number: Option<i64>,
     pub slot: i64,
     pub time: Option<DateTime<Utc>>,
     pub processed: bool,
     pub data: serde_json::Value,
+    /// service the block was ingested by, see [`Provenance`]
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub minimized: Option<bool>,
+    #[serde(default)]
+    pub ingested_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub encoding_version: Option<i32>,
+    /// whether `number` was derived from a neighbouring block rather than reported upstream
+    #[serde(default)]
+    pub height_derived: bool,
+}
+
+/// Describes how a block was ingested, stored alongside the block data
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Provenance {
+    /// service which ingested the block, ie geyser, bigtable, rpc
+    pub source: String,
+    /// whether vote transactions were filtered from the block, None if unknown
+    pub minimized: Option<bool>,
+    /// version of the json encoding used for the block data, None if unknown
+    pub encoding_version: Option<i32>,
+}
+
+#[derive(Queryable, Identifiable, Debug, Clone, Selectable, serde::Serialize)]
+#[diesel(table_name = super::schema::block_verifications)]
+pub struct BlockVerifications {
+    pub id: i64,
+    pub slot: i64,
+    pub upstream: String,
+    pub status: String,
+    pub differences: serde_json::Value,
+    pub error: Option<String>,
+    pub verified_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::block_verifications)]
+pub struct NewBlockVerification<'a> {
+    pub slot: i64,
+    pub upstream: &'a str,
+    pub status: &'a str,
+    pub differences: &'a serde_json::Value,
+    pub error: Option<&'a str>,
+}
+
+#[derive(Queryable, Identifiable, Debug, Clone, Selectable, serde::Serialize)]
+#[diesel(table_name = super::schema::gap_repairs)]
+pub struct GapRepairs {
+    pub id: i64,
+    pub gap_start: i64,
+    pub gap_end: i64,
+    pub upstream: String,
+    pub status: String,
+    pub blocks_found: i64,
+    pub error: Option<String>,
+    pub attempted_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::gap_repairs)]
+pub struct NewGapRepair<'a> {
+    pub gap_start: i64,
+    pub gap_end: i64,
+    pub upstream: &'a str,
+    pub status: &'a str,
+    pub blocks_found: i64,
+    pub error: Option<&'a str>,
 }
 
 #[derive(Queryable, AsChangeset, Identifiable, Debug, Clone, Selectable, Default, Insertable)]
 #[diesel(table_name = super::schema::idls)]
 pub struct Idls {
     pub id: String,
+    /// slot the version was first observed at
     pub begin_height: i64,
+    /// slot the next version was first observed at, None for the current version
     pub end_height: Option<i64>,
     pub idl: serde_json::Value,
+    /// dialect of the idl, ie anchor, codama or shank
+    pub kind: String,
 }
 
 #[derive(Queryable, AsChangeset, Identifiable, Debug, Clone, Selectable, Default, Insertable)]
@@ -51,16 +135,22 @@ pub struct NewIdl {
     pub begin_height: i64,
     pub end_height: Option<i64>,
     pub idl: serde_json::Value,
+    pub kind: String,
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = super::schema::blocks)]
 pub struct NewBlock<'a> {
-    pub number: i64,
+    pub number: Option<i64>,
     pub slot: i64,
     pub time: Option<DateTime<Utc>>,
     pub processed: bool,
     pub data: &'a serde_json::Value,
+    pub source: Option<&'a str>,
+    pub minimized: Option<bool>,
+    pub ingested_at: Option<DateTime<Utc>>,
+    pub encoding_version: Option<i32>,
+    pub height_derived: bool,
 }
 
 #[derive(Insertable)]
@@ -3,12 +3,50 @@
 diesel::table! {
     use diesel::sql_types::*;
 
-    blocks (number) {
-        number -> Int8,
+    blocks (slot) {
+        number -> Nullable<Int8>,
         slot -> Int8,
         time -> Nullable<Timestamptz>,
         processed -> Bool,
         data -> Jsonb,
+        attempts -> Int4,
+        claimed_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        source -> Nullable<Varchar>,
+        minimized -> Nullable<Bool>,
+        ingested_at -> Nullable<Timestamptz>,
+        encoding_version -> Nullable<Int4>,
+        height_derived -> Bool,
+        change_id -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+
+    block_verifications (id) {
+        id -> Int8,
+        slot -> Int8,
+        upstream -> Varchar,
+        status -> Varchar,
+        differences -> Jsonb,
+        error -> Nullable<Text>,
+        verified_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+
+    gap_repairs (id) {
+        id -> Int8,
+        gap_start -> Int8,
+        gap_end -> Int8,
+        upstream -> Varchar,
+        status -> Varchar,
+        blocks_found -> Int8,
+        error -> Nullable<Text>,
+        attempted_at -> Timestamptz,
     }
 }
 
@@ -20,6 +58,8 @@ diesel::table! {
         begin_height -> Int8,
         end_height -> Nullable<Int8>,
         idl -> Jsonb,
+        change_id -> Int8,
+        kind -> Varchar,
     }
 }
 
@@ -31,6 +71,7 @@ diesel::table! {
         last_deployed_slot -> Int8,
         executable_account -> Varchar,
         executable_data -> Bytea,
+        change_id -> Int8,
     }
 }
 
@@ -44,12 +85,40 @@ diesel::table! {
         threshold -> Int8,
         program_version -> Int8,
         voting_members_count -> Int8,
+        change_id -> Int8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+
+    replication_failures (source, table_name, row_key) {
+        source -> Varchar,
+        table_name -> Varchar,
+        row_key -> Varchar,
+        error -> Text,
+        failed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+
+    replication_state (source, table_name) {
+        source -> Varchar,
+        table_name -> Varchar,
+        high_water_mark -> Int8,
+        updated_at -> Timestamptz,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    block_verifications,
     blocks,
+    gap_repairs,
     idls,
     programs,
+    replication_failures,
+    replication_state,
     squads,
 );
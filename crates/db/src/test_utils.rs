@@ -25,8 +25,13 @@ impl TestDb {
             delete_on_drop: true,
         }
     }
+    /// connection to the temporary database, which is not shared with other tests
     pub fn conn(&self) -> PgConnection {
-        PgConnection::establish(self.default_db_url.as_str()).unwrap()
+        PgConnection::establish(&self.url()).unwrap()
+    }
+    /// url of the database returned by [`TestDb::conn`]
+    pub fn url(&self) -> String {
+        format!("{}/{}", self.default_db_url, self.name)
     }
 
     pub fn leak(&mut self) {
@@ -38,6 +43,8 @@ impl TestDb {
         let _ = diesel::delete(super::schema::idls::dsl::idls).execute(&mut conn);
         let _ = diesel::delete(super::schema::squads::dsl::squads).execute(&mut conn);
         let _ = diesel::delete(super::schema::programs::dsl::programs).execute(&mut conn);
+        let _ = diesel::delete(super::schema::block_verifications::dsl::block_verifications).execute(&mut conn);
+        let _ = diesel::delete(super::schema::gap_repairs::dsl::gap_repairs).execute(&mut conn);
     }
     pub fn name(&self) -> String {
         self.name.clone()
@@ -48,7 +55,6 @@ impl Drop for TestDb {
         if !self.delete_on_drop {
             return;
         }
-        self.delete_all_tables();
         let mut conn = PgConnection::establish(&self.default_db_url).unwrap();
         sql_query(format!(
             "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = '{}'",
@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 
-use client::{BlockFilter, Client, SquadsFilter};
-use models::{NewBlock};
+use client::{BlockFilter, BlockOrder, Client, GapRange, IdlChange, ProvenanceFilter, ReplaceOutcome, SquadsFilter};
+use models::{NewBlockVerification, NewGapRepair, Provenance};
 
 use crate::{migrations::run_migrations, test_utils::TestDb};
 
@@ -21,12 +21,13 @@ fn test_blocks() {
         client
             .insert_block(
                 &mut db_conn,
-                i,
+                Some(i),
                 i+1,
                 None,
                 &serde_json::json!({
                     "a": "b"
-                })
+                }),
+                &Provenance::default(),
             )
             .unwrap();
         let res = client.select_block(&mut db_conn, BlockFilter::Number(i)).unwrap();
@@ -39,12 +40,13 @@ fn test_blocks() {
         client
         .insert_block(
             &mut db_conn,
-            i,
+            Some(i),
             i+1,
             None,
             &serde_json::json!({
                 "a": "b"
-            })
+            }),
+            &Provenance::default(),
         )
         .unwrap();
         let res = client.select_block(&mut db_conn, BlockFilter::Number(i)).unwrap();
@@ -58,7 +60,7 @@ fn test_blocks() {
         .unwrap()
         .into_iter()
         .collect();
-    let mut expected_set = (2..101).into_iter().collect::<HashSet<i64>>();
+    let mut expected_set = (2..101).collect::<HashSet<i64>>();
     assert_eq!(expected_set, indexed_blocks);
     // check that setting slot by default worked
     let indexed_blocks: HashSet<i64> = client
@@ -73,7 +75,7 @@ fn test_blocks() {
         .unwrap()
         .into_iter()
         .collect();
-    expected_set.extend((201..301).into_iter().collect::<HashSet<i64>>());
+    expected_set.extend((201..301).collect::<HashSet<i64>>());
     assert_eq!(expected_set, indexed_blocks);
     let indexed_blocks: HashSet<i64> = client
         .indexed_blocks(&mut db_conn)
@@ -176,4 +178,613 @@ fn test_squads() {
     assert_eq!(msig[0].program_version, 4);
 
     drop(test_db);
-}
\ No newline at end of file
+}
+#[test]
+fn test_block_work_queue() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    for i in 1..=10 {
+        client
+            .insert_block(
+                &mut conn,
+                Some(i),
+                i + 100,
+                None,
+                &serde_json::json!({"a": "b"}),
+                &Provenance::default(),
+            )
+            .unwrap();
+    }
+    let lease = chrono::Duration::minutes(5);
+
+    // concurrent workers never receive the same blocks
+    let first = client.claim_unprocessed_blocks(&mut conn, 4, lease, 3).unwrap();
+    let second = client
+        .claim_unprocessed_blocks(&mut test_db.conn(), 4, lease, 3)
+        .unwrap();
+    assert_eq!(first.iter().map(|b| b.slot).collect::<Vec<_>>(), vec![101, 102, 103, 104]);
+    assert_eq!(second.iter().map(|b| b.slot).collect::<Vec<_>>(), vec![105, 106, 107, 108]);
+
+    assert_eq!(client.mark_blocks_processed(&mut conn, &[101, 102, 103, 104]).unwrap(), 4);
+    client.record_block_failure(&mut conn, 105, "failed to decode").unwrap();
+
+    let failed = client.select_failed_blocks(&mut conn, 10).unwrap();
+    assert_eq!(failed, vec![(105, 1, Some("failed to decode".to_string()))]);
+
+    // the failed block is released, while the remaining claimed blocks are still leased
+    let third = client.claim_unprocessed_blocks(&mut conn, 10, lease, 3).unwrap();
+    assert_eq!(third.iter().map(|b| b.slot).collect::<Vec<_>>(), vec![105, 109, 110]);
+
+    // expired leases can be reclaimed, until max attempts is reached
+    let reclaimed = client
+        .claim_unprocessed_blocks(&mut conn, 10, chrono::Duration::zero(), 2)
+        .unwrap();
+    assert_eq!(reclaimed.iter().map(|b| b.slot).collect::<Vec<_>>(), vec![106, 107, 108, 109, 110]);
+    assert!(client
+        .claim_unprocessed_blocks(&mut conn, 10, chrono::Duration::zero(), 2)
+        .unwrap()
+        .is_empty());
+    drop(test_db);
+}
+
+#[test]
+fn test_block_provenance() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    let provenance = Provenance {
+        source: "geyser".to_string(),
+        minimized: Some(true),
+        encoding_version: Some(1),
+    };
+    client
+        .insert_block(&mut conn, Some(1), 2, None, &serde_json::json!({"a": "b"}), &provenance)
+        .unwrap();
+    let block = client.select_block(&mut conn, BlockFilter::Number(1)).unwrap().remove(0);
+    assert_eq!(block.source.as_deref(), Some("geyser"));
+    assert_eq!(block.minimized, Some(true));
+    assert_eq!(block.encoding_version, Some(1));
+    assert!(block.ingested_at.is_some());
+    drop(test_db);
+}
+
+#[test]
+fn test_replace_block() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    let minimized = Provenance {
+        source: "geyser".to_string(),
+        minimized: Some(true),
+        encoding_version: Some(1),
+    };
+    let full = Provenance {
+        source: "bigtable".to_string(),
+        minimized: Some(false),
+        encoding_version: Some(2),
+    };
+    client
+        .insert_block(&mut conn, Some(1), 10, None, &serde_json::json!({"txs": 1}), &minimized)
+        .unwrap();
+
+    let filter = ProvenanceFilter {
+        minimized: Some(true),
+        ..Default::default()
+    };
+    assert_eq!(client.select_slots_by_provenance(&mut conn, 0, 100, &filter).unwrap(), vec![10]);
+
+    let outcome = client
+        .replace_block(&mut conn, Some(1), 10, None, &serde_json::json!({"txs": 2}), &full)
+        .unwrap();
+    let ReplaceOutcome::Replaced(previous) = outcome else {
+        panic!("expected block to be replaced");
+    };
+    assert_eq!(previous.data, serde_json::json!({"txs": 1}));
+    let block = client.select_block(&mut conn, BlockFilter::Slot(10)).unwrap().remove(0);
+    assert_eq!(block.data, serde_json::json!({"txs": 2}));
+    assert_eq!(block.source.as_deref(), Some("bigtable"));
+    assert!(client.select_slots_by_provenance(&mut conn, 0, 100, &filter).unwrap().is_empty());
+
+    assert_eq!(
+        client
+            .replace_block(&mut conn, Some(1), 10, None, &serde_json::json!({"txs": 2}), &full)
+            .unwrap(),
+        ReplaceOutcome::Unchanged
+    );
+    assert_eq!(
+        client
+            .replace_block(&mut conn, Some(2), 11, None, &serde_json::json!({"txs": 3}), &full)
+            .unwrap(),
+        ReplaceOutcome::Inserted
+    );
+    let filter = ProvenanceFilter {
+        encoding_version_below: Some(3),
+        ..Default::default()
+    };
+    assert_eq!(
+        client.select_slots_by_provenance(&mut conn, 0, 100, &filter).unwrap(),
+        vec![10, 11]
+    );
+    drop(test_db);
+}
+
+#[test]
+fn test_block_verifications() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    for i in 1..=10 {
+        client
+            .insert_block(&mut conn, Some(i), i * 10, None, &serde_json::json!({}), &Provenance::default())
+            .unwrap();
+    }
+    assert_eq!(
+        client.select_sampled_slots(&mut conn, 20, 50, 1.0, None).unwrap(),
+        vec![20, 30, 40, 50]
+    );
+    // limited samples are a random subset of the range, not the earliest slots
+    let limited = (0..20)
+        .map(|_| client.select_sampled_slots(&mut conn, 0, 100, 1.0, Some(2)).unwrap())
+        .collect::<Vec<_>>();
+    assert!(limited.iter().all(|slots| slots.len() == 2 && slots[0] < slots[1]));
+    assert!(limited.iter().any(|slots| *slots != vec![10, 20]));
+    assert!(client.select_sampled_slots(&mut conn, 0, 100, 0.0, None).unwrap().is_empty());
+
+    let differences = serde_json::json!([{"kind": "signature_missing_upstream", "signature": "abc"}]);
+    client
+        .insert_block_verification(
+            &mut conn,
+            &NewBlockVerification {
+                slot: 10,
+                upstream: "bigtable",
+                status: "match",
+                differences: &serde_json::json!([]),
+                error: None,
+            },
+        )
+        .unwrap();
+    client
+        .insert_block_verification(
+            &mut conn,
+            &NewBlockVerification {
+                slot: 20,
+                upstream: "bigtable",
+                status: "mismatch",
+                differences: &differences,
+                error: None,
+            },
+        )
+        .unwrap();
+    assert_eq!(client.select_block_verifications(&mut conn, None, 10).unwrap().len(), 2);
+    let mismatches = client
+        .select_block_verifications(&mut conn, Some("mismatch"), 10)
+        .unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].slot, 20);
+    assert_eq!(mismatches[0].differences, differences);
+    drop(test_db);
+}
+
+#[test]
+fn test_block_filters() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    let start_time = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    for i in 1..=10 {
+        client
+            .insert_block(
+                &mut conn,
+                Some(i),
+                i * 10,
+                Some(start_time + chrono::Duration::seconds(i)),
+                &serde_json::json!({}),
+                &Provenance::default(),
+            )
+            .unwrap();
+    }
+    // blocks without a height are never returned by height based filters
+    client
+        .insert_block(&mut conn, None, 5, None, &serde_json::json!({}), &Provenance::default())
+        .unwrap();
+    // re-inserting a stored block is ignored, but conflicting heights are errors
+    client
+        .insert_block(&mut conn, Some(1), 10, None, &serde_json::json!({}), &Provenance::default())
+        .unwrap();
+    assert!(client
+        .insert_block(&mut conn, Some(2), 10, None, &serde_json::json!({}), &Provenance::default())
+        .is_err());
+    assert!(client
+        .insert_block(&mut conn, Some(1), 15, None, &serde_json::json!({}), &Provenance::default())
+        .is_err());
+    let slots = |blocks: Vec<models::Blocks>| blocks.iter().map(|b| b.slot).collect::<Vec<_>>();
+
+    let first = client.select_block(&mut conn, BlockFilter::FirstBlock).unwrap();
+    assert_eq!(slots(first), vec![10]);
+    let last = client.select_block(&mut conn, BlockFilter::LastBlock).unwrap();
+    assert_eq!(slots(last), vec![100]);
+    assert_eq!(client.select_block(&mut conn, BlockFilter::All).unwrap().len(), 11);
+
+    let range = client
+        .select_block(
+            &mut conn,
+            BlockFilter::SlotRange {
+                start: 0,
+                end: 40,
+                order: BlockOrder::Descending,
+                limit: Some(3),
+            },
+        )
+        .unwrap();
+    assert_eq!(slots(range), vec![40, 30, 20]);
+    let range = client
+        .select_block(
+            &mut conn,
+            BlockFilter::NumberRange {
+                start: 8,
+                end: 20,
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+        )
+        .unwrap();
+    assert_eq!(slots(range), vec![80, 90, 100]);
+    let range = client
+        .select_block(
+            &mut conn,
+            BlockFilter::TimeRange {
+                start: start_time + chrono::Duration::seconds(2),
+                end: start_time + chrono::Duration::seconds(4),
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+        )
+        .unwrap();
+    assert_eq!(slots(range), vec![20, 30, 40]);
+
+    // pages continue from the last returned block, and respect the filter limit
+    let pages = client
+        .select_block_pages(&mut conn, BlockFilter::All, 4)
+        .unwrap()
+        .map(|page| slots(page.unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pages,
+        vec![vec![5, 10, 20, 30], vec![40, 50, 60, 70], vec![80, 90, 100]]
+    );
+    let pages = client
+        .select_block_pages(
+            &mut conn,
+            BlockFilter::NumberRange {
+                start: 1,
+                end: 10,
+                order: BlockOrder::Descending,
+                limit: Some(5),
+            },
+            2,
+        )
+        .unwrap()
+        .map(|page| slots(page.unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(pages, vec![vec![100, 90], vec![80, 70], vec![60]]);
+    drop(test_db);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async_client() {
+    let test_db = TestDb::new();
+    let db = async_client::AsyncClient::new(&test_db.url(), 2).unwrap();
+    db.run_migrations().await.unwrap();
+
+    // more tasks than connections, so tasks have to wait for a connection to be returned
+    let tasks = (1..=10)
+        .map(|i| {
+            let db = db.clone();
+            tokio::task::spawn(async move {
+                db.interact(move |client, conn| {
+                    client.insert_block(
+                        conn,
+                        Some(i),
+                        i * 10,
+                        None,
+                        &serde_json::json!({}),
+                        &Provenance::default(),
+                    )
+                })
+                .await
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    let blocks = db
+        .interact(|client, conn| client.select_block(conn, BlockFilter::All))
+        .await
+        .unwrap();
+    assert_eq!(blocks.len(), 10);
+
+    // errors returned by the closure are passed through
+    let err = db
+        .interact(|_, _| -> anyhow::Result<()> { Err(anyhow::anyhow!("query failed")) })
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "query failed");
+    drop(test_db);
+}
+
+#[test]
+fn test_find_gap_ranges() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    // heights 1..=3, 6, 10..=11 and 15 are stored, at slot = height * 10
+    for number in [1, 2, 3, 6, 10, 11, 15] {
+        client
+            .insert_block(
+                &mut conn,
+                Some(number),
+                number * 10,
+                None,
+                &serde_json::json!({}),
+                &Provenance::default(),
+            )
+            .unwrap();
+    }
+    let gap = |gap_start, gap_end, prev_slot, next_slot| GapRange {
+        gap_start,
+        gap_end,
+        prev_slot,
+        next_slot,
+    };
+    let gaps = client.find_gap_ranges(&mut conn, 1, 15).unwrap();
+    assert_eq!(
+        gaps,
+        vec![
+            gap(4, 5, Some(30), Some(60)),
+            gap(7, 9, Some(60), Some(100)),
+            gap(12, 14, Some(110), Some(150)),
+        ]
+    );
+    assert_eq!(gaps.iter().map(|gap| gap.missing()).sum::<i64>(), 8);
+
+    // gaps at the edges of the range are clipped, and bounded by the nearest stored blocks
+    assert_eq!(
+        client.find_gap_ranges(&mut conn, 8, 13).unwrap(),
+        vec![gap(8, 9, Some(60), Some(100)), gap(12, 13, Some(110), Some(150))]
+    );
+    assert_eq!(
+        client.find_gap_ranges(&mut conn, 0, 20).unwrap(),
+        vec![
+            gap(0, 0, None, Some(10)),
+            gap(4, 5, Some(30), Some(60)),
+            gap(7, 9, Some(60), Some(100)),
+            gap(12, 14, Some(110), Some(150)),
+            gap(16, 20, Some(150), None),
+        ]
+    );
+    assert!(client.find_gap_ranges(&mut conn, 1, 3).unwrap().is_empty());
+    assert!(client.find_gap_ranges(&mut conn, 3, 1).is_err());
+
+    // slot ranges extend to the heights adjacent to the nearest stored blocks
+    assert_eq!(client.height_range_for_slots(&mut conn, 45, 105).unwrap(), Some((4, 10)));
+    assert_eq!(client.height_range_for_slots(&mut conn, 0, 20).unwrap(), Some((1, 2)));
+    assert_eq!(client.height_range_for_slots(&mut conn, 200, 300).unwrap(), None);
+    assert_eq!(client.stored_height_range(&mut conn).unwrap(), Some((1, 15)));
+    drop(test_db);
+}
+
+#[test]
+fn test_gap_repairs() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    for (status, blocks_found, error) in [
+        ("failed", 0, Some("no stored block precedes the gap")),
+        ("partially_fetched", 2, None),
+        ("fetched", 3, None),
+    ] {
+        client
+            .insert_gap_repair(
+                &mut conn,
+                &NewGapRepair {
+                    gap_start: 10,
+                    gap_end: 14,
+                    upstream: "rpc",
+                    status,
+                    blocks_found,
+                    error,
+                },
+            )
+            .unwrap();
+    }
+    let repairs = client.select_gap_repairs(&mut conn, None, 10).unwrap();
+    assert_eq!(
+        repairs.iter().map(|repair| repair.status.as_str()).collect::<Vec<_>>(),
+        vec!["fetched", "partially_fetched", "failed"]
+    );
+    let failed = client.select_gap_repairs(&mut conn, Some("failed"), 10).unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].blocks_found, 0);
+    assert_eq!(failed[0].error.as_deref(), Some("no stored block precedes the gap"));
+    drop(test_db);
+}
+
+#[test]
+fn test_slot_coverage() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    let day = |day| {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap() + chrono::Duration::days(day)
+    };
+    // height 4 is missing between slots 13 and 20, and the block at slot 27 has no height
+    for (number, slot, time) in [
+        (Some(1), 10, Some(day(0))),
+        (Some(2), 12, Some(day(0))),
+        (Some(3), 13, Some(day(0))),
+        (Some(5), 20, Some(day(1))),
+        (Some(6), 25, Some(day(1))),
+        (None, 27, None),
+    ] {
+        client
+            .insert_block(&mut conn, number, slot, time, &serde_json::json!({}), &Provenance::default())
+            .unwrap();
+    }
+    let coverage = |stored_blocks, missing_height, known_skipped| client::SlotCoverage {
+        stored_blocks,
+        missing_height,
+        known_skipped,
+    };
+    // slots 11 and 21..=24 are skipped, while slots 14..=19 are unknown
+    assert_eq!(client.slot_coverage(&mut conn, 10, 25).unwrap(), coverage(5, 0, 5));
+    // skipped slots at the edges of the range are bounded by the nearest stored blocks
+    assert_eq!(client.slot_coverage(&mut conn, 11, 22).unwrap(), coverage(3, 0, 3));
+    assert_eq!(client.slot_coverage(&mut conn, 21, 24).unwrap(), coverage(0, 0, 4));
+    assert_eq!(client.slot_coverage(&mut conn, 26, 30).unwrap(), coverage(1, 1, 0));
+    assert!(client.slot_coverage(&mut conn, 30, 26).is_err());
+
+    assert_eq!(client.slot_range_for_times(&mut conn, day(0), day(1)).unwrap(), Some((10, 13)));
+    assert_eq!(client.slot_range_for_times(&mut conn, day(1), day(2)).unwrap(), Some((20, 25)));
+    assert_eq!(client.slot_range_for_times(&mut conn, day(2), day(3)).unwrap(), None);
+    drop(test_db);
+}
+
+#[test]
+fn test_migration_management() {
+    // migrations are reverted, so the shared database can't be used
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    let status = migrations::migration_status(&mut conn).unwrap();
+    assert!(!status.is_empty());
+    assert!(status.iter().all(|migration| !migration.applied));
+
+    let applied = migrations::migrate(&mut conn).unwrap();
+    assert_eq!(
+        applied,
+        status.iter().map(|migration| migration.name.clone()).collect::<Vec<_>>()
+    );
+    assert!(migrations::migrate(&mut conn).unwrap().is_empty());
+    assert!(migrations::migration_status(&mut conn)
+        .unwrap()
+        .iter()
+        .all(|migration| migration.applied));
+
+    // migrations are reverted newest first
+    let reverted = migrations::rollback(&mut conn, 2).unwrap();
+    assert_eq!(reverted, applied.iter().rev().take(2).cloned().collect::<Vec<_>>());
+    assert_eq!(migrations::redo(&mut conn).unwrap(), applied[applied.len() - 3]);
+
+    // every down migration applies cleanly, and the schema can be rebuilt afterwards
+    let reverted = migrations::rollback(&mut conn, usize::MAX).unwrap();
+    assert_eq!(reverted.len(), applied.len() - 2);
+    assert!(migrations::redo(&mut conn).is_err());
+    assert_eq!(migrations::migrate(&mut conn).unwrap(), applied);
+
+    // blocks without a height are kept, failing the revert of optional heights
+    let client = Client {};
+    client
+        .insert_block(&mut conn, None, 5, None, &serde_json::json!({}), &Provenance::default())
+        .unwrap();
+    let err = migrations::rollback(&mut conn, usize::MAX).unwrap_err();
+    assert!(format!("{err:#}").contains("blocks_optional_height"));
+    assert_eq!(client.select_blocks_by_slot_range(&mut conn, 5, 5).unwrap().len(), 1);
+    drop(conn);
+    drop(test_db);
+}
+
+#[test]
+fn test_idl_versions() {
+    let test_db = TestDb::new();
+    let mut conn = test_db.conn();
+    run_migrations(&mut conn);
+    let client = Client {};
+    let program_id = "JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4";
+    let v1 = serde_json::json!({"version": "0.1.0"});
+    let v2 = serde_json::json!({"version": "0.2.0"});
+    let v3 = serde_json::json!({"version": "0.3.0"});
+
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 100, "anchor", v1.clone()).unwrap(),
+        IdlChange::Inserted
+    );
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 150, "anchor", v1.clone()).unwrap(),
+        IdlChange::Unchanged
+    );
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 200, "anchor", v2.clone()).unwrap(),
+        IdlChange::Updated {
+            previous_begin_height: 100
+        }
+    );
+    // changes within the same slot replace the version
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 200, "anchor", v3.clone()).unwrap(),
+        IdlChange::Updated {
+            previous_begin_height: 200
+        }
+    );
+    let versions = |conn: &mut diesel::PgConnection| {
+        client
+            .idl_versions(conn, program_id)
+            .unwrap()
+            .into_iter()
+            .map(|version| (version.begin_height, version.end_height, version.idl))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        versions(&mut conn),
+        vec![(100, Some(200), v1.clone()), (200, None, v3.clone())]
+    );
+
+    // older versions are inserted between the stored versions
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 150, "anchor", v2.clone()).unwrap(),
+        IdlChange::Updated {
+            previous_begin_height: 100
+        }
+    );
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 50, "anchor", v2.clone()).unwrap(),
+        IdlChange::Inserted
+    );
+    assert_eq!(
+        versions(&mut conn),
+        vec![
+            (50, Some(100), v2.clone()),
+            (100, Some(150), v1.clone()),
+            (150, Some(200), v2.clone()),
+            (200, None, v3.clone())
+        ]
+    );
+
+    let idl_at = |slot| client.idl_at(&mut test_db.conn(), program_id, slot).unwrap().map(|idl| idl.idl);
+    assert_eq!(idl_at(49), None);
+    assert_eq!(idl_at(99), Some(v2.clone()));
+    assert_eq!(idl_at(100), Some(v1.clone()));
+    assert_eq!(idl_at(149), Some(v1));
+    assert_eq!(idl_at(199), Some(v2));
+    assert_eq!(idl_at(200), Some(v3.clone()));
+    assert_eq!(idl_at(10_000), Some(v3.clone()));
+    // the same idl in another dialect is a new version
+    assert_eq!(
+        client.insert_or_update_idl(&mut conn, program_id.to_string(), 300, "codama", v3.clone()).unwrap(),
+        IdlChange::Updated {
+            previous_begin_height: 200
+        }
+    );
+    let current = client.idl_at(&mut conn, program_id, 300).unwrap().unwrap();
+    assert_eq!((current.kind.as_str(), current.idl), ("codama", v3));
+    assert!(client.idl_at(&mut conn, "11111111111111111111111111111111", 200).unwrap().is_none());
+    drop(test_db);
+}
@@ -1,4 +1,15 @@
 use clap::{Args, Parser, Subcommand};
+use sb_dl::{
+    services::{
+        coverage::{CoverageBy, CoverageFormat},
+        gaps::GapScanBy,
+        idl_sources::IdlKind,
+        parquet_export::RangeKind,
+        reingest::ReingestFrom,
+        replication::{ReplicatedTable, ReplicationDirection},
+    },
+    sinks::SinkConfig,
+};
 
 #[derive(Parser)]
 #[command(name = "sb_dl", about = "solana block downloader")]
@@ -12,16 +23,68 @@ pub struct Cli {
     #[arg(long, default_value = "config.yaml")]
     pub config: String,
 
+    #[arg(long, help = "if present, serve prometheus metrics on this address")]
+    pub metrics_listen: Option<String>,
+
+    #[arg(
+        long,
+        help = "if present, serve /health and /ready on this address. only used by geyser-stream, backfiller, bigtable-downloader and squads-indexer"
+    )]
+    pub health_listen: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "1000",
+        help = "/ready returns 503 when the last persisted slot trails the last received slot by more than this"
+    )]
+    pub health_max_lag_slots: u64,
+
+    #[arg(
+        long,
+        default_value = "300",
+        help = "/ready returns 503 when no successful write has happened for this many seconds"
+    )]
+    pub health_max_staleness_secs: u64,
+
     // Global flags
     #[arg(long, global = true, default_value = "false")]
     pub no_minimization: bool,
 
-    #[arg(long, global = true, default_value = "failed_blocks")]
+    #[arg(
+        long,
+        global = true,
+        default_value = "failed_blocks",
+        help = "directory containing the retry queue for blocks which failed to persist"
+    )]
     pub failed_blocks_dir: String,
 
     #[arg(long, global = true, default_value = "4")]
     pub threads: u32,
 
+    #[arg(
+        long,
+        global = true,
+        default_value = "postgres",
+        value_delimiter = ',',
+        help = "comma separated list of sinks to persist blocks to: postgres, stdout, jsonl:<dir>, zstd:<dir>"
+    )]
+    pub sinks: Vec<SinkConfig>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "30",
+        help = "seconds to wait for queued blocks to be persisted on exit, remaining blocks are added to the retry queue"
+    )]
+    pub shutdown_timeout: u64,
+
+    #[arg(
+        long,
+        global = true,
+        help = "do not apply pending migrations on startup, and fail if any are pending. use the db commands to manage migrations"
+    )]
+    pub no_auto_migrate: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -34,12 +97,24 @@ pub enum Commands {
         command: ServicesCommands,
     },
 
+    #[command(about = "export stored blocks for analytics")]
+    Export {
+        #[command(subcommand)]
+        command: ExportCommands,
+    },
+
+    #[command(about = "database migration management")]
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+
     #[command(about = "initialize a new config file")]
     NewConfig,
 
     #[command(
         about = "manually import an idl into the database",
-        long_about = "useful for programs that publish anchor idls offchain"
+        long_about = "useful for programs that publish anchor, codama or shank idls offchain"
     )]
     ManualIdlImport {
         #[arg(long, help = "file containing the idl")]
@@ -47,6 +122,43 @@ pub enum Commands {
 
         #[arg(long, help = "program to associate this idl with")]
         program_id: String,
+
+        #[arg(long, help = "slot the idl became active at, defaults to the current slot")]
+        slot: Option<u64>,
+
+        #[arg(long, value_enum, help = "dialect of the idl, detected from the idl when omitted")]
+        kind: Option<IdlKind>,
+    },
+    #[command(
+        about = "import every idl file in a directory",
+        long_about = "recursively imports idl files named <program_id>_<anything>.json in a single transaction, and prints the result of each file as json"
+    )]
+    ImportIdlDir {
+        #[arg(long, default_value = "idls", help = "directory containing the idls")]
+        dir: String,
+
+        #[arg(long, help = "slot the idls became active at, defaults to the current slot")]
+        slot: Option<u64>,
+
+        #[arg(long, help = "validate the idls and report the changes without storing them")]
+        dry_run: bool,
+    },
+    #[command(
+        about = "reconstruct idl history from stored blocks",
+        long_about = "replays anchor idl instructions sent to each program's idl account within the stored blocks, and stores every complete idl found as a version starting at the slot it was written"
+    )]
+    ReconstructIdls {
+        #[arg(long, help = "first slot to scan (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to scan (inclusive)")]
+        end: i64,
+
+        #[arg(long, help = "programs to reconstruct idls for, defaults to every program")]
+        program_id: Vec<String>,
+
+        #[arg(long, default_value = "1000", help = "number of slots to read from postgres at a time")]
+        batch_size: i64,
     },
 
     #[command(about = "generate transfer graph for a single tx")]
@@ -56,18 +168,183 @@ pub enum Commands {
 
         #[arg(long, help = "tx to generate graph for")]
         tx_hash: String,
+
+        #[arg(long, help = "if present, fall back to archived blocks in this directory")]
+        archive_dir: Option<String>,
     },
 
     #[command(about = "generates ordered transfers for an entire block")]
     CreateOrderedTransfersForBlock {
         #[arg(long, help = "slot number to fetch tx from")]
         slot_number: i64,
+
+        #[arg(long, help = "if present, fall back to archived blocks in this directory")]
+        archive_dir: Option<String>,
     },
     #[command(about = "find the ending block for a gap")]
     FindGapEnd {
         #[arg(long, help = "starting number to assume a gap for")]
         gap_start: i64,
     },
+    #[command(
+        about = "move a range of blocks out of postgres into compressed local archive files",
+        long_about = "blocks are only deleted from postgres once they have been written to the archive"
+    )]
+    Archive {
+        #[arg(long, help = "first slot to archive (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to archive (inclusive)")]
+        end: i64,
+
+        #[arg(long, help = "directory to store archive files in")]
+        archive_dir: String,
+
+        #[arg(long, help = "number of slots to archive per batch", default_value = "1000")]
+        batch_size: i64,
+    },
+    #[command(
+        about = "re-fetch stored blocks and overwrite them",
+        long_about = "selects stored blocks in a slot range, optionally filtered by provenance, and replaces them with a fresh copy fetched using the current options"
+    )]
+    Reingest {
+        #[arg(long, help = "first slot to reingest (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to reingest (inclusive)")]
+        end: i64,
+
+        #[arg(long, value_enum, default_value = "bigtable", help = "upstream to re-fetch blocks from")]
+        from: ReingestFrom,
+
+        #[arg(long, help = "only reingest blocks ingested by this source, ie geyser, bigtable, rpc")]
+        source: Option<String>,
+
+        #[arg(long, help = "only reingest blocks with this minimization setting")]
+        minimized: Option<bool>,
+
+        #[arg(long, help = "only reingest blocks with an encoding version below this, or no encoding version")]
+        encoding_version_below: Option<i32>,
+
+        #[arg(from_global)]
+        no_minimization: bool,
+
+        #[arg(from_global)]
+        threads: u32,
+    },
+    #[command(
+        about = "derive the heights of blocks stored without one",
+        long_about = "counts heights forwards and backwards from blocks with a known height, across runs of stored blocks whose parent slot is the preceding stored block"
+    )]
+    DeriveHeights {
+        #[arg(long, help = "first slot to derive heights for (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to derive heights for (inclusive)")]
+        end: i64,
+
+        #[arg(long, default_value = "1000", help = "number of slots to read from postgres at a time")]
+        batch_size: i64,
+    },
+    #[command(
+        about = "compare stored blocks against bigtable or rpc",
+        long_about = "samples stored blocks in a slot range, re-fetches them with the minimization they were stored with, and records a structural diff of block fields, signatures, metas and balances in the block_verifications table"
+    )]
+    VerifyBlocks {
+        #[arg(long, help = "first slot to verify (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to verify (inclusive)")]
+        end: i64,
+
+        #[arg(long, default_value = "1.0", help = "probability of each stored block in the range being verified")]
+        sample_rate: f64,
+
+        #[arg(long, help = "max number of blocks to verify")]
+        limit: Option<i64>,
+
+        #[arg(long, value_enum, default_value = "bigtable", help = "upstream to re-fetch blocks from")]
+        from: ReingestFrom,
+
+        #[arg(from_global)]
+        no_minimization: bool,
+
+        #[arg(from_global)]
+        threads: u32,
+    },
+    #[command(
+        about = "verify stored blocks form a continuous chain",
+        long_about = "walks stored blocks in slot order, checking each block's parent slot, previous blockhash and block height against the preceding stored block, and prints a json report of missing parents, forks and mismatches"
+    )]
+    VerifyChain {
+        #[arg(long, help = "first slot to verify (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "last slot to verify (inclusive)")]
+        end: i64,
+
+        #[arg(long, default_value = "1000", help = "number of slots to read from postgres at a time")]
+        batch_size: i64,
+    },
+    #[command(
+        about = "report how complete the stored blocks are per epoch or day",
+        long_about = "reports the expected slots, stored blocks, known skipped slots, gaps and failed blocks of each epoch or utc day, along with the percentage of slots which are stored or known to be skipped"
+    )]
+    Coverage {
+        #[arg(long, value_enum, default_value = "epoch", help = "whether --from and --to are epochs or YYYY-MM-DD days")]
+        by: CoverageBy,
+
+        #[arg(long, help = "first epoch or day to report on (inclusive)")]
+        from: String,
+
+        #[arg(long, help = "last epoch or day to report on (inclusive), defaults to --from")]
+        to: Option<String>,
+
+        #[arg(long, value_enum, default_value = "table", help = "output format")]
+        format: CoverageFormat,
+
+        #[arg(from_global)]
+        failed_blocks_dir: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ExportCommands {
+    #[command(
+        about = "export blocks, transactions and transfers as day partitioned parquet files",
+        long_about = "writes <output-dir>/{blocks,transactions,transfers}/date=YYYY-MM-DD/part-<start>-<end>.parquet"
+    )]
+    Parquet {
+        #[arg(long, help = "start of the range to export (inclusive)")]
+        start: i64,
+
+        #[arg(long, help = "end of the range to export (inclusive)")]
+        end: i64,
+
+        #[arg(long, value_enum, default_value = "slot", help = "whether start and end are slots or block heights")]
+        by: RangeKind,
+
+        #[arg(long, help = "directory to write parquet files to")]
+        output_dir: String,
+
+        #[arg(long, help = "number of blocks to read from postgres per batch", default_value = "1000")]
+        batch_size: i64,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum DbCommands {
+    #[command(about = "apply all pending migrations")]
+    Migrate,
+    #[command(about = "revert the most recently applied migrations")]
+    Rollback {
+        #[arg(default_value = "1", help = "number of migrations to revert")]
+        n: usize,
+    },
+    #[command(about = "list migrations and whether they are applied")]
+    Status,
+    #[command(about = "revert and re-apply the most recently applied migration")]
+    Redo,
 }
 
 #[derive(Subcommand, Clone)]
@@ -88,6 +365,12 @@ pub enum ServicesCommands {
 
         #[arg(from_global)]
         threads: u32,
+
+        #[arg(from_global)]
+        sinks: Vec<SinkConfig>,
+
+        #[arg(from_global)]
+        shutdown_timeout: u64,
     },
 
     #[command(about = "block backfiller to covers gaps missed by geyser")]
@@ -100,6 +383,12 @@ pub enum ServicesCommands {
 
         #[arg(from_global)]
         threads: u32,
+
+        #[arg(from_global)]
+        sinks: Vec<SinkConfig>,
+
+        #[arg(from_global)]
+        shutdown_timeout: u64,
     },
 
     #[command(about = "stream blocks in real-time using geyser")]
@@ -112,9 +401,15 @@ pub enum ServicesCommands {
 
         #[arg(from_global)]
         threads: u32,
+
+        #[arg(from_global)]
+        sinks: Vec<SinkConfig>,
+
+        #[arg(from_global)]
+        shutdown_timeout: u64,
     },
 
-    #[command(about = "index anchor idl accounts")]
+    #[command(about = "index idls published with anchor or the program-metadata program")]
     IndexIdls,
 
     #[command(about = "index deployed programs")]
@@ -135,8 +430,59 @@ pub enum ServicesCommands {
         #[arg(from_global)]
         threads: u32,
 
-        #[arg(long, help = "limit number of gaps")]
-        limit: i64
+        #[arg(from_global)]
+        sinks: Vec<SinkConfig>,
+
+        #[arg(from_global)]
+        shutdown_timeout: u64,
+
+        #[command(flatten)]
+        scan: GapScanArgs,
+
+        #[arg(long, help = "maximum number of gap ranges to repair")]
+        limit: usize,
+    },
+
+    #[command(
+        about = "continuously detect and repair gaps in block coverage",
+        long_about = "periodically scans for gap ranges and repairs them concurrently, recording the outcome of each attempt in the gap_repairs table. gaps which fail to be repaired are retried with an exponential backoff"
+    )]
+    GapRepairer {
+        #[arg(from_global)]
+        failed_blocks_dir: String,
+
+        #[arg(from_global)]
+        threads: u32,
+
+        #[arg(from_global)]
+        sinks: Vec<SinkConfig>,
+
+        #[arg(from_global)]
+        shutdown_timeout: u64,
+
+        #[command(flatten)]
+        scan: GapScanArgs,
+
+        #[arg(long, value_enum, default_value = "rpc", help = "upstream to fetch missing blocks from")]
+        upstream: ReingestFrom,
+
+        #[arg(long, default_value = "60", help = "duration in seconds between gap scans")]
+        frequency: u64,
+
+        #[arg(long, default_value = "100", help = "maximum number of gap ranges to repair per scan")]
+        max_gaps: usize,
+
+        #[arg(long, default_value = "4", help = "number of gap ranges to repair at once")]
+        concurrency: usize,
+
+        #[arg(long, default_value = "10000", help = "maximum number of slots to fetch per gap range and attempt")]
+        max_slots_per_attempt: i64,
+
+        #[arg(long, default_value = "60", help = "duration in seconds to wait before retrying a failed gap range, doubling on each failure")]
+        base_backoff: u64,
+
+        #[arg(long, default_value = "21600", help = "maximum duration in seconds to wait before retrying a failed gap range")]
+        max_backoff: u64,
     },
 
     #[command(about = "transfer parsing service to push decoded transfers into elasticsearch")]
@@ -168,14 +514,73 @@ pub enum ServicesCommands {
         frequency: u64,
     },
 
-    #[command(about = "import failed blocks")]
+    #[command(
+        about = "import failed blocks",
+        long_about = "moves legacy block_<slot>.json files into the retry queue, and attempts to persist all queued blocks"
+    )]
     ImportFailedBlocks {
         #[arg(from_global)]
         failed_blocks_dir: String,
     },
-    #[command(about = "check for gaps in block data")]
+    #[command(about = "report the depth of the failed blocks retry queue")]
+    RetryQueueStatus {
+        #[arg(from_global)]
+        failed_blocks_dir: String,
+    },
+    #[command(
+        about = "continuously copy rows between the local and remote databases",
+        long_about = "copies new blocks, and new or changed idls, programs and squads, tracking a high-water mark per table in the destination database"
+    )]
+    Replicate {
+        #[arg(long, value_enum, help = "whether to copy from db_url to remotedb_url, or the reverse")]
+        direction: ReplicationDirection,
+
+        #[arg(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            default_value = "blocks,idls,programs,squads",
+            help = "comma separated list of tables to replicate"
+        )]
+        tables: Vec<ReplicatedTable>,
+
+        #[arg(long, help = "number of rows to copy per batch", default_value = "500")]
+        batch_size: i64,
+
+        #[arg(long, help = "seconds to wait between replication runs", default_value = "10")]
+        frequency: u64,
+    },
+    #[command(
+        about = "check for gaps in block data",
+        long_about = "prints the ranges of missing block heights as json, along with the slots of the blocks on either side of each gap"
+    )]
     FindGaps {
-        #[arg(long)]
-        limit: i64
+        #[command(flatten)]
+        scan: GapScanArgs,
+
+        #[arg(long, help = "maximum number of gap ranges to report")]
+        limit: Option<usize>,
     }
 }
+
+/// Range of blocks scanned for gaps
+#[derive(Args, Clone, Debug)]
+pub struct GapScanArgs {
+    #[arg(
+        long,
+        help = "start of the range, defaults to six hours before --to when scanning by slot or time"
+    )]
+    pub from: Option<String>,
+
+    #[arg(
+        long,
+        help = "end of the range, defaults to the current slot, the current time or the highest stored block height"
+    )]
+    pub to: Option<String>,
+
+    #[arg(long, value_enum, default_value = "slot", help = "whether --from and --to are slots, block heights or rfc3339 times")]
+    pub by: GapScanBy,
+
+    #[arg(long, conflicts_with_all = ["from", "to"], help = "scan every stored block height")]
+    pub all: bool,
+}
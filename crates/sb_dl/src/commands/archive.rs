@@ -0,0 +1,24 @@
+use {
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{
+        config::Config,
+        services::archive::{archive_slot_range, Archive},
+    },
+};
+
+pub async fn archive_blocks(
+    start: i64,
+    end: i64,
+    archive_dir: &str,
+    batch_size: i64,
+    config_path: &str,
+) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let archive = Archive::new(archive_dir)?;
+    let archived = archive_slot_range(&mut conn, &archive, start, end, batch_size)?;
+    log::info!("archived {archived} blocks(start={start}, end={end}) to {archive_dir}");
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use {
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{
+        config::Config,
+        services::{
+            coverage::{coverage_report, parse_buckets, render_table, CoverageBy, CoverageFormat},
+            retry_queue::RetryQueue,
+        },
+    },
+};
+
+pub async fn coverage(
+    by: CoverageBy,
+    from: &str,
+    to: Option<&str>,
+    format: CoverageFormat,
+    failed_blocks_dir: &str,
+    config_path: &str,
+) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let failed_slots = RetryQueue::open(failed_blocks_dir)?.queued_slots()?;
+    let buckets = parse_buckets(&mut conn, by, from, to)?;
+    let rows = coverage_report(&mut conn, &buckets, &failed_slots)?;
+    match format {
+        CoverageFormat::Table => print!("{}", render_table(&rows)),
+        CoverageFormat::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+    }
+    Ok(())
+}
@@ -2,13 +2,53 @@ use std::{collections::HashSet, sync::Arc};
 
 use anyhow::{anyhow, Context};
 use clap::ArgMatches;
-use db::{client::{BlockFilter, Client}, migrations::run_migrations};
+use db::{client::{BlockFilter, Client}, migrations::{self, run_migrations}};
 use futures::StreamExt;
 use sb_dl::config::Config;
 use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
 use solana_transaction_status::{EncodedTransaction, UiConfirmedBlock, UiTransactionEncoding};
 use tokio::task::JoinSet;
 
+pub async fn migrate(config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = db::new_connection(&cfg.db_url)?;
+    let applied = migrations::migrate(&mut conn)?;
+    for name in &applied {
+        log::info!("applied migration({name})");
+    }
+    log::info!("applied {} migrations", applied.len());
+    Ok(())
+}
+
+pub async fn rollback(n: usize, config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = db::new_connection(&cfg.db_url)?;
+    let reverted = migrations::rollback(&mut conn, n)?;
+    for name in &reverted {
+        log::info!("reverted migration({name})");
+    }
+    if reverted.len() < n {
+        log::warn!("reverted {} of {n} migrations, no applied migrations remain", reverted.len());
+    }
+    Ok(())
+}
+
+pub async fn migration_status(config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = db::new_connection(&cfg.db_url)?;
+    let status = migrations::migration_status(&mut conn)?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+pub async fn redo(config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = db::new_connection(&cfg.db_url)?;
+    let name = migrations::redo(&mut conn)?;
+    log::info!("redid migration({name})");
+    Ok(())
+}
+
 pub async fn find_gap_end(
     starting_number: i64,
     config_path: &str
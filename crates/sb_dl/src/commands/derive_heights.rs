@@ -0,0 +1,19 @@
+use {
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{config::Config, services::block_heights::derive_block_heights},
+};
+
+pub async fn derive_heights(start: i64, end: i64, batch_size: i64, config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let stats = derive_block_heights(&mut conn, start, end, batch_size)?;
+    log::info!(
+        "derived {} block heights(start={start}, end={end}), {} blocks remain without a height",
+        stats.forward + stats.backward,
+        stats.remaining
+    );
+    println!("{}", serde_json::to_string_pretty(&stats)?);
+    Ok(())
+}
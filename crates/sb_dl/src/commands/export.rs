@@ -0,0 +1,38 @@
+use {
+    crate::cli::ExportCommands,
+    anyhow::anyhow,
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{config::Config, services::parquet_export::ParquetExporter},
+};
+
+pub async fn export_parquet(cmd: ExportCommands, config_path: &str) -> anyhow::Result<()> {
+    let ExportCommands::Parquet {
+        start,
+        end,
+        by,
+        output_dir,
+        batch_size,
+    } = cmd;
+    if end < start {
+        return Err(anyhow!("end({end}) < start({start})"));
+    }
+    let cfg = Config::load(config_path).await?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let stats = ParquetExporter::new(&output_dir, start, end).export(
+        &mut conn,
+        by,
+        start,
+        end,
+        batch_size,
+    )?;
+    log::info!(
+        "exported blocks={} transactions={} transfers={} skipped={} to {output_dir}",
+        stats.blocks,
+        stats.transactions,
+        stats.transfers,
+        stats.skipped
+    );
+    Ok(())
+}
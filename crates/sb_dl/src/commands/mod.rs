@@ -1,10 +1,17 @@
 use tokio::signal::unix::Signal;
 
+pub mod archive;
 pub mod config;
+pub mod coverage;
 pub mod db;
+pub mod derive_heights;
+pub mod export;
+pub mod reingest;
 pub mod services;
 pub mod transfer_graph;
 pub mod utils;
+pub mod verify_blocks;
+pub mod verify_chain;
 
 pub async fn handle_exit(
     mut sig_quit: Signal,
@@ -0,0 +1,40 @@
+use {
+    db::{async_client::AsyncClient, client::ProvenanceFilter},
+    sb_dl::{
+        config::Config,
+        services::{
+            backfill::Backfiller,
+            bigtable::Downloader,
+            reingest::{reingest_slots, ReingestFrom, Upstream},
+        },
+    },
+};
+
+pub async fn reingest(
+    start: i64,
+    end: i64,
+    from: ReingestFrom,
+    filter: ProvenanceFilter,
+    no_minimization: bool,
+    threads: u32,
+    config_path: &str,
+) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
+    db.run_migrations().await?;
+
+    let slots = {
+        let filter = filter.clone();
+        db.interact(move |client, conn| client.select_slots_by_provenance(conn, start, end, &filter))
+            .await?
+    };
+    log::info!("selected {} blocks to reingest(start={start}, end={end}, filter={filter:?})", slots.len());
+
+    let upstream = match from {
+        ReingestFrom::Bigtable => Upstream::Bigtable(Downloader::new(cfg.bigtable).await?),
+        ReingestFrom::Rpc => Upstream::Rpc(Backfiller::new(&cfg.rpc_url)),
+    };
+    let stats = reingest_slots(&db, &upstream, slots, no_minimization, threads as usize).await?;
+    println!("{}", serde_json::to_string_pretty(&stats)?);
+    Ok(())
+}
@@ -1,57 +1,74 @@
 use {
-    super::super::utils::{
-        get_failed_blocks, load_failed_blocks, sanitize_for_postgres, sanitize_value,
-    }, crate::{cli::ServicesCommands, commands::handle_exit}, anyhow::{anyhow, Context}, chrono::prelude::*, clap::ArgMatches, db::{migrations::run_migrations, models::NewBlock}, diesel::{
-        prelude::*,
-        r2d2::{ConnectionManager, Pool, PooledConnection},
-        PgConnection,
-    }, sb_dl::{
+    super::super::utils::load_failed_blocks,
+    crate::{cli::ServicesCommands, commands::handle_exit},
+    anyhow::{anyhow, Context},
+    chrono::prelude::*,
+    clap::ArgMatches,
+    db::{
+        async_client::AsyncClient,
+        models::{NewBlock, Provenance},
+    },
+    sb_dl::{
         config::Config,
+        health, metrics,
         services::{
             backfill::Backfiller,
             bigtable::Downloader,
             geyser::{new_geyser_client, subscribe_blocks},
+            retry_queue::{retry_loop, QueuedBlock, RetryConfig, RetryQueue},
         },
-        types::BlockInfo,
-    }, solana_transaction_status::UiConfirmedBlock, std::{collections::HashSet, sync::Arc}, tokio::{
+        sinks::{new_block_sink, BlockSink, EncodedBlock, SinkConfig},
+        types::{BlockInfo, BlockSource},
+        utils::sanitize_for_postgres,
+    },
+    solana_transaction_status::UiConfirmedBlock,
+    std::time::Duration,
+    std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
+    },
+    tokio::{
         signal::unix::{signal, Signal, SignalKind},
-        sync::Semaphore,
-    }
+        sync::{mpsc, oneshot, Semaphore},
+        task::{JoinHandle, JoinSet},
+        time::Instant,
+    },
 };
 
 /// Starts the big table historical block downloader
 pub async fn bigtable_downloader(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()> {
-    let ServicesCommands::BigtableDownloader { start, limit, no_minimization, failed_blocks_dir, threads } = cmd else {
+    let ServicesCommands::BigtableDownloader {
+        start,
+        limit,
+        no_minimization,
+        failed_blocks_dir,
+        threads,
+        sinks,
+        shutdown_timeout,
+    } = cmd
+    else {
         return Err(anyhow!("invalid command"));
     };
     let cfg = Config::load(config_path).await?;
 
-    // create failed blocks directory, ignoring error (its already created)
-    let _ = tokio::fs::create_dir(&failed_blocks_dir).await;
-
-    // read all failed blocks to append to the already_indexed hash set
-    //
-    // we do this so we can avoid re-downloading the blocks which are stored locally
-    let failed_blocks = get_failed_blocks(&failed_blocks_dir).await.unwrap();
-
-    // load all currently indexed block number to avoid re-downloading already indexed block data
-    let mut already_indexed: HashSet<u64> = {
-        let mut conn = db::new_connection(&cfg.db_url)?;
-
-        // perform db migrations
-        run_migrations(&mut conn);
-
-        let client = db::client::Client {};
-        client
-            .indexed_blocks(&mut conn)
-            .unwrap_or_default()
-            .into_iter()
-            .map(|block| block as u64)
-            .collect()
-    };
-
-    // mark failed blocks as already indexed to avoid redownloading
-    already_indexed.extend(failed_blocks.iter());
+    let postgres =
+        connect_postgres_sink(&cfg, &sinks, &failed_blocks_dir, threads as usize * 2).await?;
+
+    // load all currently indexed block number to avoid re-downloading already indexed block data,
+    // blocks are only tracked when persisting to postgres
+    let mut already_indexed: HashSet<u64> = HashSet::new();
+    if let Some((db, retry_queue)) = &postgres {
+        // mark queued blocks as already indexed to avoid redownloading the blocks which are
+        // stored locally. they are read first, as the retry loop may move them into postgres
+        already_indexed.extend(retry_queue.queued_slots()?);
+        already_indexed.extend(
+            db.interact(|client, conn| client.indexed_blocks(conn))
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .map(|block| block as u64),
+        );
+    }
 
     let downloader = Arc::new(Downloader::new(cfg.bigtable).await?);
 
@@ -62,16 +79,17 @@ pub async fn bigtable_downloader(cmd: ServicesCommands, config_path: &str) -> an
     let sig_int = signal(SignalKind::interrupt())?;
     let sig_term = signal(SignalKind::terminate())?;
 
-    let pool = db::new_connection_pool(&cfg.db_url, threads as u32 *2)?;
+    let retry_queue = postgres
+        .as_ref()
+        .map(|(_, retry_queue)| retry_queue.clone());
+    let sink = new_block_sink(&sinks, postgres)?;
 
     // start the background persistence task
-    tokio::task::spawn(
-        async move { block_persistence_loop(pool, failed_blocks_dir, blocks_rx, threads as usize).await },
-    );
+    let mut persistence = PersistenceHandle::spawn(sink, blocks_rx, threads as usize, retry_queue);
 
     let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
     let (stop_downloader_tx, stop_downloader_rx) = tokio::sync::oneshot::channel();
-    tokio::task::spawn(async move {
+    let producer = tokio::task::spawn(async move {
         log::info!("starting block_indexing. disable_minimization={no_minimization}");
 
         if let Err(err) = downloader
@@ -82,7 +100,7 @@ pub async fn bigtable_downloader(cmd: ServicesCommands, config_path: &str) -> an
                 limit,
                 no_minimization,
                 threads as usize,
-                stop_downloader_rx
+                stop_downloader_rx,
             )
             .await
         {
@@ -93,29 +111,33 @@ pub async fn bigtable_downloader(cmd: ServicesCommands, config_path: &str) -> an
         }
     });
 
-    let err = handle_exit(sig_quit, sig_int, sig_term, finished_rx).await;
-    // stop the downloader task
+    let err = tokio::select! {
+        err = handle_exit(sig_quit, sig_int, sig_term, finished_rx) => err,
+        err = persistence.failed() => Err(err),
+    };
+    let deadline = Instant::now() + Duration::from_secs(shutdown_timeout);
+    // stop the downloader task, allowing blocks which were already fetched to be sent
     let _ = stop_downloader_tx.send(());
-    return err
+    stop_producer(producer, deadline).await;
+    err.and(persistence.drain(deadline).await)
 }
 
 /// Starts the geyser stream block downloader
 pub async fn geyser_stream(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()> {
-    let ServicesCommands::GeyserStream { no_minimization, failed_blocks_dir, threads } = cmd else {
+    let ServicesCommands::GeyserStream {
+        no_minimization,
+        failed_blocks_dir,
+        threads,
+        sinks,
+        shutdown_timeout,
+    } = cmd
+    else {
         return Err(anyhow!("invalid command"));
     };
     let cfg = Config::load(config_path).await?;
-    
-    // create failed blocks directory, ignoring error (its already created)
-    let _ = tokio::fs::create_dir(&failed_blocks_dir).await;
-
-
-    {
-        let mut conn = db::new_connection(&cfg.db_url)?;
 
-        // perform db migrations
-        run_migrations(&mut conn);
-    }
+    let postgres =
+        connect_postgres_sink(&cfg, &sinks, &failed_blocks_dir, threads as usize * 2).await?;
 
     let gc = new_geyser_client(
         &cfg.geyser.endpoint,
@@ -132,17 +154,18 @@ pub async fn geyser_stream(cmd: ServicesCommands, config_path: &str) -> anyhow::
     let sig_int = signal(SignalKind::interrupt())?;
     let sig_term = signal(SignalKind::terminate())?;
 
-    let pool = db::new_connection_pool(&cfg.db_url, threads as u32 *2)?;
+    let retry_queue = postgres
+        .as_ref()
+        .map(|(_, retry_queue)| retry_queue.clone());
+    let sink = new_block_sink(&sinks, postgres)?;
 
     // start the background persistence task
-    tokio::task::spawn(
-        async move { block_persistence_loop(pool, failed_blocks_dir, blocks_rx, threads as usize).await },
-    );
+    let mut persistence = PersistenceHandle::spawn(sink, blocks_rx, threads as usize, retry_queue);
 
     // optional value containing error message encountered during program execution
     let (finished_tx, finished_rx) = tokio::sync::oneshot::channel::<Option<String>>();
 
-    tokio::task::spawn(async move {
+    let producer = tokio::task::spawn(async move {
         log::info!("starting geyser stream. disable_minimization={no_minimization}");
         if let Err(err) = subscribe_blocks(gc, blocks_tx, no_minimization).await {
             let _ = finished_tx.send(Some(format!("geyser stream failed {err:#?}")));
@@ -152,18 +175,30 @@ pub async fn geyser_stream(cmd: ServicesCommands, config_path: &str) -> anyhow::
         }
     });
 
-    handle_exit(sig_quit, sig_int, sig_term, finished_rx).await
+    let err = tokio::select! {
+        err = handle_exit(sig_quit, sig_int, sig_term, finished_rx) => err,
+        err = persistence.failed() => Err(err),
+    };
+    let deadline = Instant::now() + Duration::from_secs(shutdown_timeout);
+    // the stream has no notion of progress, so stop it immediately and drain what was received
+    producer.abort();
+    let _ = producer.await;
+    err.and(persistence.drain(deadline).await)
 }
 
 pub async fn backfiller(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()> {
-    let ServicesCommands::Backfiller { no_minimization, failed_blocks_dir, threads } = cmd else {
+    let ServicesCommands::Backfiller {
+        no_minimization,
+        failed_blocks_dir,
+        threads,
+        sinks,
+        shutdown_timeout,
+    } = cmd
+    else {
         return Err(anyhow!("invalid command"));
     };
     let cfg = Config::load(config_path).await?;
 
-    // create failed blocks directory, ignoring error (its already created)
-    let _ = tokio::fs::create_dir(&failed_blocks_dir).await;
-
     // receives downloaded blocks, which allows us to persist downloaded data while we download and parse other data
     let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel::<BlockInfo>(1000);
 
@@ -171,24 +206,22 @@ pub async fn backfiller(cmd: ServicesCommands, config_path: &str) -> anyhow::Res
     let sig_int = signal(SignalKind::interrupt())?;
     let sig_term = signal(SignalKind::terminate())?;
 
-    {
-        // if we fail to connect to postgres, we should terminate the thread
-        let mut conn = db::new_connection(&cfg.db_url)?;
-
-        run_migrations(&mut conn);
-    }
-    let pool = db::new_connection_pool(&cfg.db_url, threads as u32 *2)?;
+    // if we fail to connect to postgres, we should terminate the thread
+    let postgres =
+        connect_postgres_sink(&cfg, &sinks, &failed_blocks_dir, threads as usize * 2).await?;
+    let retry_queue = postgres
+        .as_ref()
+        .map(|(_, retry_queue)| retry_queue.clone());
+    let sink = new_block_sink(&sinks, postgres)?;
 
     // start the background persistence task
-    tokio::task::spawn(
-        async move { block_persistence_loop(pool, failed_blocks_dir, blocks_rx, threads as usize).await },
-    );
+    let mut persistence = PersistenceHandle::spawn(sink, blocks_rx, threads as usize, retry_queue);
 
     let backfiller = Backfiller::new(&cfg.rpc_url);
 
     let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
 
-    tokio::task::spawn(async move {
+    let producer = tokio::task::spawn(async move {
         log::info!("starting backfiller. disable_minimization={no_minimization}");
         if let Err(err) = backfiller
             .automatic_backfill(blocks_tx, no_minimization)
@@ -201,79 +234,98 @@ pub async fn backfiller(cmd: ServicesCommands, config_path: &str) -> anyhow::Res
         }
     });
 
-    handle_exit(sig_quit, sig_int, sig_term, finished_rx).await
+    let err = tokio::select! {
+        err = handle_exit(sig_quit, sig_int, sig_term, finished_rx) => err,
+        err = persistence.failed() => Err(err),
+    };
+    let deadline = Instant::now() + Duration::from_secs(shutdown_timeout);
+    // recent blocks are backfilled again on the next start, so there is nothing to wait for
+    producer.abort();
+    let _ = producer.await;
+    err.and(persistence.drain(deadline).await)
 }
 
-pub async fn import_failed_blocks(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()
-> {
+/// Connects to postgres and starts draining the retry queue into it, if a postgres sink is
+/// configured. Services which only persist to other sinks never touch the database
+async fn connect_postgres_sink(
+    cfg: &Config,
+    sinks: &[SinkConfig],
+    failed_blocks_dir: &str,
+    pool_size: usize,
+) -> anyhow::Result<Option<(AsyncClient, Arc<RetryQueue>)>> {
+    if !SinkConfig::any_postgres(sinks) {
+        return Ok(None);
+    }
+    let retry_queue = Arc::new(RetryQueue::open(failed_blocks_dir)?);
+    let db = AsyncClient::new(&cfg.db_url, pool_size)?;
+    // perform db migrations
+    db.run_migrations().await?;
+    tokio::task::spawn(retry_loop(
+        retry_queue.clone(),
+        db.clone(),
+        RetryConfig::default(),
+    ));
+    Ok(Some((db, retry_queue)))
+}
 
+/// Moves blocks persisted by older releases as `block_{slot}.json` files into the retry queue,
+/// and then makes a single attempt at draining the queue
+pub async fn import_failed_blocks(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()> {
     let ServicesCommands::ImportFailedBlocks { failed_blocks_dir } = cmd else {
         return Err(anyhow!("invalid command"));
-
     };
 
     let cfg = Config::load(config_path).await?;
+    let retry_queue = Arc::new(RetryQueue::open(&failed_blocks_dir)?);
     let (blocks_tx, mut blocks_rx) = tokio::sync::mpsc::channel::<(u64, serde_json::Value)>(1000);
 
     // if we fail to connect to postgres, we should terminate the thread
-    let mut conn = db::new_connection(&cfg.db_url)?;
-
-    run_migrations(&mut conn);
+    let db = AsyncClient::new(&cfg.db_url, 2)?;
+    db.run_migrations().await?;
 
     let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
     {
         let failed_blocks_dir = failed_blocks_dir.clone();
+        let retry_queue = retry_queue.clone();
         tokio::task::spawn(async move {
-            let client = db::client::Client {};
             while let Some((slot_number, mut block)) = blocks_rx.recv().await {
                 sanitize_for_postgres(&mut block);
 
-                // this is a bit clunky, however in order to get the correct slot number
-                // we first need to deserialize the block into a UiConfirmedBlock object
-                // calculate the slot number, then serialize the block again
-
-                let block: UiConfirmedBlock = match serde_json::from_value(block) {
+                // deserialize the block to recover the block height and time, any block which
+                // can't be queued is left on disk rather than being dropped. blocks without a
+                // height are stored by slot
+                let ui_block: UiConfirmedBlock = match serde_json::from_value(block.clone()) {
                     Ok(block) => block,
                     Err(err) => {
                         log::error!("failed to deserialize block({slot_number}) {err:#?}");
                         continue;
                     }
                 };
-                let block_height = if let Some(height) = block.block_height {
-                    height
-                } else {
-                    log::warn!("missing height for block(slot={slot_number})");
-                    continue;
-                };
-                let time = if let Some(block_time) = block.block_time {
+                let block_height = ui_block.block_height;
+                let time = if let Some(block_time) = ui_block.block_time {
                     DateTime::from_timestamp(block_time, 0)
                 } else {
                     None
                 };
-                let block = match serde_json::to_value(block) {
-                    Ok(block) => block,
-                    Err(err) => {
-                        log::error!("failed to serialize block{slot_number}) {err:#?}");
-                        continue;
-                    }
-                };
-                if let Err(err) = client.insert_block(
-                    &mut conn,
-                    block_height as i64,
-                    slot_number as i64,
+                if let Err(err) = retry_queue.push(&QueuedBlock::new(
+                    slot_number,
+                    block_height,
                     time,
-                    &block
-                ) {
-                    log::error!("failed to insert block({slot_number}) {err:#?}");
-                } else {
-                    log::info!("inserted block({slot_number})");
-                    if let Err(err) = tokio::fs::remove_file(format!(
-                        "{failed_blocks_dir}/block_{slot_number}.json"
-                    ))
-                    .await
-                    {
-                        log::error!("failed to remove persisted block({slot_number}) {err:#?}");
-                    }
+                    "imported from failed blocks dir".to_string(),
+                    block,
+                    Provenance {
+                        source: BlockSource::FailedBlocksImport.as_str().to_string(),
+                        // the encoding and minimization used by older releases is unknown
+                        minimized: None,
+                        encoding_version: None,
+                    },
+                )) {
+                    log::error!("failed to queue block({slot_number}) {err:#?}");
+                } else if let Err(err) =
+                    tokio::fs::remove_file(format!("{failed_blocks_dir}/block_{slot_number}.json"))
+                        .await
+                {
+                    log::error!("failed to remove persisted block({slot_number}) {err:#?}");
                 }
             }
 
@@ -285,90 +337,439 @@ pub async fn import_failed_blocks(cmd: ServicesCommands, config_path: &str) -> a
 
     let _ = finished_rx.await;
 
+    let stats = retry_queue.drain_once(&db, &RetryConfig::default()).await?;
+    log::info!(
+        "drained retry queue(persisted={}, requeued={})",
+        stats.persisted,
+        stats.requeued
+    );
+
     Ok(())
 }
 
-// shared logic responsible for persisting blocks to the database
-pub async fn block_persistence_loop(
-    pool: Pool<ConnectionManager<PgConnection>>,
-    failed_blocks_dir: String,
+/// Reports the depth of the retry queue
+pub async fn retry_queue_status(cmd: ServicesCommands) -> anyhow::Result<()> {
+    let ServicesCommands::RetryQueueStatus { failed_blocks_dir } = cmd else {
+        return Err(anyhow!("invalid command"));
+    };
+    let status = RetryQueue::open(&failed_blocks_dir)?.status()?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+/// Handle to a running [`block_persistence_loop`], used to drain it on shutdown
+pub struct PersistenceHandle {
+    task: JoinHandle<anyhow::Result<()>>,
+    deadline_tx: oneshot::Sender<()>,
+    failed_rx: mpsc::Receiver<String>,
+}
+
+impl PersistenceHandle {
+    /// Starts the persistence loop. Blocks which are not persisted before the shutdown deadline
+    /// are written to `retry_queue`, which is only set when persisting to postgres
+    pub fn spawn(
+        sink: Arc<dyn BlockSink>,
+        blocks_rx: tokio::sync::mpsc::Receiver<BlockInfo>,
+        threads: usize,
+        retry_queue: Option<Arc<RetryQueue>>,
+    ) -> Self {
+        let (deadline_tx, deadline_rx) = oneshot::channel();
+        let (failed_tx, failed_rx) = mpsc::channel(1);
+        let task = tokio::task::spawn(block_persistence_loop(
+            sink,
+            blocks_rx,
+            threads,
+            retry_queue,
+            deadline_rx,
+            failed_tx,
+        ));
+        Self {
+            task,
+            deadline_tx,
+            failed_rx,
+        }
+    }
+    /// Resolves with the error of the first block which failed to persist. Sinks other than
+    /// postgres have no retry path, so services stop instead of silently dropping blocks
+    pub async fn failed(&mut self) -> anyhow::Error {
+        match self.failed_rx.recv().await {
+            Some(err) => anyhow!(err),
+            // the persistence loop exited without a failure
+            None => std::future::pending().await,
+        }
+    }
+    /// Waits until every block sent to the persistence loop has been persisted, or `deadline` passes.
+    ///
+    /// All senders of the blocks channel must be dropped beforehand, otherwise this always waits
+    /// until the deadline. Returns an error if a block failed to persist while draining, or if
+    /// blocks left at the deadline could not be queued for retry.
+    pub async fn drain(mut self, deadline: Instant) -> anyhow::Result<()> {
+        let res = match tokio::time::timeout_at(deadline, &mut self.task).await {
+            Ok(res) => {
+                log::info!("block persistence drained");
+                res
+            }
+            Err(_) => {
+                log::warn!(
+                    "block persistence failed to drain before deadline, queueing remaining blocks"
+                );
+                let _ = self.deadline_tx.send(());
+                self.task.await
+            }
+        };
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err(err),
+            Err(err) => log::error!("block persistence task failed {err:#?}"),
+        }
+        match self.failed_rx.try_recv() {
+            Ok(err) => Err(anyhow!(err)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Stops a producer task, giving it until `deadline` to exit on its own before aborting it
+async fn stop_producer(mut producer: JoinHandle<()>, deadline: Instant) {
+    if tokio::time::timeout_at(deadline, &mut producer)
+        .await
+        .is_err()
+    {
+        log::warn!("producer failed to stop before deadline, aborting");
+        producer.abort();
+        let _ = producer.await;
+    }
+}
+
+/// blocks handed to persistence tasks which have not finished, keyed by slot
+type InFlightBlocks = Arc<Mutex<HashMap<u64, Arc<BlockInfo>>>>;
+
+// shared logic responsible for persisting blocks to the configured sinks
+//
+// the loop exits once all senders are dropped and in-flight blocks are persisted, or when
+// `deadline_rx` fires, in which case unfinished persistence tasks are aborted, and their blocks
+// along with blocks remaining in the channel are queued for retry
+//
+// the first block which fails to persist is reported through `failed_tx`, and unpersisted blocks
+// which could not be queued are returned as an error
+async fn block_persistence_loop(
+    sink: Arc<dyn BlockSink>,
     mut blocks_rx: tokio::sync::mpsc::Receiver<BlockInfo>,
     threads: usize,
-) {
+    retry_queue: Option<Arc<RetryQueue>>,
+    mut deadline_rx: oneshot::Receiver<()>,
+    failed_tx: mpsc::Sender<String>,
+) -> anyhow::Result<()> {
     let semaphore = Arc::new(Semaphore::new(threads));
-
-    let client = db::client::Client {};
-
-    while let Some(block_info) = blocks_rx.recv().await {
-        match semaphore.clone().acquire_owned().await {
+    let in_flight = InFlightBlocks::default();
+    let mut tasks = JoinSet::new();
+
+    log::info!("persisting blocks to {}", sink.name());
+
+    loop {
+        let block_info = tokio::select! {
+            biased;
+            _ = &mut deadline_rx => None,
+            block_info = blocks_rx.recv() => match block_info {
+                Some(block_info) => Some(block_info),
+                None => break,
+            },
+        };
+        let Some(block_info) = block_info else {
+            return queue_unpersisted(
+                &mut blocks_rx,
+                None,
+                tasks,
+                &in_flight,
+                retry_queue.as_deref(),
+            )
+            .await;
+        };
+        metrics::PERSISTENCE_CHANNEL_DEPTH.set(blocks_rx.len() as i64);
+        let timer = metrics::SEMAPHORE_WAIT_SECONDS.start_timer();
+        let permit = tokio::select! {
+            biased;
+            _ = &mut deadline_rx => None,
+            permit = semaphore.clone().acquire_owned() => Some(permit),
+        };
+        let Some(permit) = permit else {
+            return queue_unpersisted(
+                &mut blocks_rx,
+                Some(block_info),
+                tasks,
+                &in_flight,
+                retry_queue.as_deref(),
+            )
+            .await;
+        };
+        timer.observe_duration();
+        match permit {
             Ok(permit) => {
-                match pool.get() {
-                    Ok(mut conn) => {
-                        let failed_blocks_dir = failed_blocks_dir.clone();
-                        tokio::task::spawn(async move {
-                            process_block(block_info, &mut conn, failed_blocks_dir, client).await;
-                            drop(permit);
-                        });
+                let block_info = Arc::new(block_info);
+                if let Ok(mut in_flight) = in_flight.lock() {
+                    in_flight.insert(block_info.slot, block_info.clone());
+                }
+                let sink = sink.clone();
+                let in_flight = in_flight.clone();
+                let failed_tx = failed_tx.clone();
+                tasks.spawn(async move {
+                    if let Err(err) = process_block(&block_info, &*sink).await {
+                        // only the first failure is reported
+                        let _ = failed_tx.try_send(format!("{err:#}"));
                     }
-                    Err(err) => {
-                        log::error!("failed to get pool connection {err:#?}");
+                    if let Ok(mut in_flight) = in_flight.lock() {
+                        // the slot may have been sent again while this block was being persisted
+                        if in_flight
+                            .get(&block_info.slot)
+                            .is_some_and(|other| Arc::ptr_eq(other, &block_info))
+                        {
+                            in_flight.remove(&block_info.slot);
+                        }
                     }
-                }
+                    drop(permit);
+                });
+                // reap finished tasks
+                while tasks.try_join_next().is_some() {}
             }
             Err(err) => {
                 log::error!("failed to acquire permit {err:#?}");
-                return;
+                return Ok(());
             }
         }
     }
-}
 
+    // all producers have exited, wait for in-flight blocks to be persisted
+    let drained = tokio::select! {
+        biased;
+        _ = &mut deadline_rx => false,
+        _ = async { while tasks.join_next().await.is_some() {} } => true,
+    };
+    if !drained {
+        return queue_unpersisted(
+            &mut blocks_rx,
+            None,
+            tasks,
+            &in_flight,
+            retry_queue.as_deref(),
+        )
+        .await;
+    }
+    Ok(())
+}
 
-async fn process_block(block_info: BlockInfo, conn: &mut PgConnection, failed_blocks_dir: String, client: db::client::Client) {
+/// Writes blocks which could not be persisted before the shutdown deadline to the retry queue.
+///
+/// Unfinished persistence tasks are aborted first, so that their blocks can be queued. The retry
+/// queue only drains into postgres, so without one the blocks are reported as lost instead
+async fn queue_unpersisted(
+    blocks_rx: &mut tokio::sync::mpsc::Receiver<BlockInfo>,
+    pending: Option<BlockInfo>,
+    mut tasks: JoinSet<()>,
+    in_flight: &InFlightBlocks,
+    retry_queue: Option<&RetryQueue>,
+) -> anyhow::Result<()> {
+    tasks.abort_all();
+    while tasks.join_next().await.is_some() {}
+    let in_flight = match in_flight.lock() {
+        Ok(mut in_flight) => in_flight
+            .drain()
+            .map(|(_, block_info)| block_info)
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            log::error!("in-flight blocks lock poisoned {err:#?}");
+            vec![]
+        }
+    };
+    if !in_flight.is_empty() {
+        log::warn!(
+            "{} blocks were still being persisted at the shutdown deadline",
+            in_flight.len()
+        );
+    }
+    blocks_rx.close();
+    let Some(retry_queue) = retry_queue else {
+        let mut lost = in_flight
+            .iter()
+            .map(|block_info| block_info.slot)
+            .chain(pending.map(|block_info| block_info.slot))
+            .chain(std::iter::from_fn(|| {
+                blocks_rx.try_recv().ok().map(|block_info| block_info.slot)
+            }))
+            .collect::<Vec<_>>();
+        if lost.is_empty() {
+            return Ok(());
+        }
+        lost.sort_unstable();
+        return Err(anyhow!(
+            "{} blocks were not persisted before the shutdown deadline, slots {lost:?}",
+            lost.len()
+        ));
+    };
+    let mut queued = 0;
+    for block_info in in_flight
+        .into_iter()
+        .chain(pending.map(Arc::new))
+        .chain(std::iter::from_fn(|| {
+            blocks_rx.try_recv().ok().map(Arc::new)
+        }))
+    {
+        let slot = block_info.slot;
+        let block = match EncodedBlock::try_from(&*block_info) {
+            Ok(block) => block,
+            Err(err) => {
+                log::error!("failed to encode block({slot}) {err:#?}");
+                continue;
+            }
+        };
+        if let Err(err) = retry_queue.push(&QueuedBlock::new(
+            slot,
+            block.block_height,
+            block.time,
+            "not persisted before shutdown".to_string(),
+            block.data,
+            block.provenance,
+        )) {
+            log::error!("failed to queue block({slot}) {err:#?}");
+        } else {
+            metrics::BLOCKS_QUEUED.inc();
+            queued += 1;
+        }
+    }
+    log::warn!("queued {queued} unpersisted blocks for retry");
+    Ok(())
+}
 
+/// Persists a single block, returning an error if any sink failed to persist it
+async fn process_block(block_info: &BlockInfo, sink: &dyn BlockSink) -> anyhow::Result<()> {
     let slot = block_info.slot;
+    let source = block_info.source.as_str();
 
-    match serde_json::to_value(block_info.block) {
-        Ok(mut block) => {
-            // sanitize the values first
-            // escape invalid unicode points
-            sanitize_value(&mut block);
-            // replace escaped unicode points with empty string
-            sanitize_for_postgres(&mut block);
-            let err = client.insert_block(
-                conn,
-                block_info.block_height as i64,
-                slot as i64,
-                block_info.time,
-                &block
-            );
-            if let Err(err) = err {
-                // block persistence failed despite sanitization persist the data locally
-                log::warn!("block({slot}) persistence failed {err:#?}");
-                match serde_json::to_string(&block) {
-                    Ok(block_str) => {
-                        if let Err(err) = tokio::fs::write(
-                            format!("{failed_blocks_dir}/block_{slot}.json"),
-                            block_str,
-                        )
-                        .await
-                        {
-                            log::error!("failed to store failed block({slot}) {err:#?}");
-                        } else {
-                            log::warn!("block({slot}) failed to persist, saved to {failed_blocks_dir}/block_{slot}.json");
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("failed to serialize block({slot}) {err:#?}");
-                    }
-                }
-            } else {
-                log::info!("persisted block({slot})");
-                drop(block);
-            }
-        }
+    let block = match EncodedBlock::try_from(block_info) {
+        Ok(block) => block,
         Err(err) => {
-            log::error!("failed to serialize block({slot}) {err:#?}");
+            // encoding is deterministic, so the block would fail again on every attempt
+            log::error!("failed to encode block({slot}) {err:#?}");
+            metrics::BLOCKS_FAILED.with_label_values(&[source]).inc();
+            return Ok(());
         }
+    };
+    if let Err(err) = sink.persist(&block).await {
+        log::error!("block({slot}) persistence failed {err:#?}");
+        metrics::BLOCKS_FAILED.with_label_values(&[source]).inc();
+        return Err(err.context(format!("block({slot}) persistence failed")));
+    }
+    log::info!("persisted block({slot})");
+    metrics::record_persisted(source, slot, block.block_height);
+    health::record_write(Some(slot));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, futures::future::BoxFuture};
+
+    /// a sink whose writes never complete
+    struct StalledSink;
+
+    impl BlockSink for StalledSink {
+        fn name(&self) -> String {
+            "stalled".to_string()
+        }
+        fn persist<'a>(&'a self, _block: &'a EncodedBlock) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    /// a sink whose writes always fail
+    struct FailingSink;
+
+    impl BlockSink for FailingSink {
+        fn name(&self) -> String {
+            "failing".to_string()
+        }
+        fn persist<'a>(&'a self, _block: &'a EncodedBlock) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async { Err(anyhow!("disk full")) })
+        }
+    }
+
+    fn block_info(slot: u64) -> BlockInfo {
+        let block: UiConfirmedBlock = serde_json::from_value(serde_json::json!({
+            "previousBlockhash": "11111111111111111111111111111111",
+            "blockhash": "11111111111111111111111111111111",
+            "parentSlot": slot - 1,
+            "blockHeight": slot,
+        }))
+        .unwrap();
+        BlockInfo {
+            block_height: Some(slot),
+            slot,
+            time: None,
+            block,
+            source: BlockSource::Rpc,
+            minimized: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_queues_in_flight_blocks() {
+        let dir =
+            std::env::temp_dir().join(format!("sb_dl_persistence_drain_{}", std::process::id()));
+        let retry_queue = Arc::new(RetryQueue::open(&dir).unwrap());
+        let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel(10);
+        let handle = PersistenceHandle::spawn(
+            Arc::new(StalledSink),
+            blocks_rx,
+            2,
+            Some(retry_queue.clone()),
+        );
+        for slot in 1..=5 {
+            blocks_tx.send(block_info(slot)).await.unwrap();
+        }
+        drop(blocks_tx);
+
+        // two blocks are stuck in the sink, and the rest are waiting for a permit or in the channel
+        handle
+            .drain(Instant::now() + Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(
+            retry_queue.queued_slots().unwrap(),
+            (1..=5).collect::<HashSet<_>>()
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_drain_reports_lost_blocks_without_retry_queue() {
+        let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel(10);
+        let handle = PersistenceHandle::spawn(Arc::new(StalledSink), blocks_rx, 2, None);
+        for slot in 1..=3 {
+            blocks_tx.send(block_info(slot)).await.unwrap();
+        }
+        drop(blocks_tx);
+
+        let err = handle
+            .drain(Instant::now() + Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("slots [1, 2, 3]"));
+    }
+
+    #[tokio::test]
+    async fn test_sink_failure_is_reported() {
+        let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel(10);
+        let mut handle = PersistenceHandle::spawn(Arc::new(FailingSink), blocks_rx, 2, None);
+        blocks_tx.send(block_info(1)).await.unwrap();
+
+        let err = tokio::time::timeout(Duration::from_secs(5), handle.failed())
+            .await
+            .unwrap();
+        assert!(format!("{err:#}").contains("block(1) persistence failed"));
+        drop(blocks_tx);
+        handle
+            .drain(Instant::now() + Duration::from_secs(5))
+            .await
+            .unwrap();
     }
 }
@@ -1,43 +1,134 @@
-use std::str::FromStr;
+use std::{collections::HashSet, path::Path, str::FromStr};
 
-use db::{migrations::run_migrations, new_connection};
-use sb_dl::{config::Config, services::idl_indexer::IdlIndexer};
+use db::{async_client::AsyncClient, client::IdlChange, migrations::run_migrations, new_connection};
+use sb_dl::{
+    config::Config,
+    metrics::record_indexer_run,
+    services::{
+        idl_history::reconstruct_idl_history,
+        idl_import::{self, IdlFileStatus},
+        idl_indexer::IdlIndexer,
+        idl_sources::{normalize_idl, IdlKind},
+    },
+};
+use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
 
 pub async fn index_idls(config_path: &str) -> anyhow::Result<()> {
     let cfg = Config::load(config_path).await?;
+    let start = std::time::Instant::now();
     let idl_indexer = IdlIndexer::new(&cfg.rpc_url).await?;
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
     let program_ids = {
-        let mut conn = new_connection(&cfg.db_url)?;
-        run_migrations(&mut conn);
-        db::client::Client {}
-            .indexed_program_ids(&mut conn)?
+        db.run_migrations().await?;
+        db.interact(|client, conn| client.indexed_program_ids(conn))
+            .await?
             .into_iter()
             .filter_map(|id| Pubkey::from_str(&id).ok())
             .collect::<Vec<_>>()
     };
-    let idls = idl_indexer.get_idl_accounts(&program_ids).await?;
-    let mut conn = new_connection(&cfg.db_url)?;
-    let client = db::client::Client {};
+    let idls = match idl_indexer.get_idl_accounts(&program_ids).await {
+        Ok(idls) => idls,
+        Err(err) => {
+            record_indexer_run("idl", start.elapsed(), 0, false);
+            return Err(err);
+        }
+    };
+    let mut inserted = 0;
     for idl in idls {
-        if let Err(err) =
-            client.insert_or_update_idl(&mut conn, idl.program_id.to_string(), 0, None, idl.idl)
+        let program_id = idl.program_id;
+        match db
+            .interact(move |client, conn| {
+                client.insert_or_update_idl(conn, program_id.to_string(), idl.slot as i64, idl.kind.as_str(), idl.idl)
+            })
+            .await
         {
-            log::error!("failed to insert idl(pid={}) {err:#?}", idl.program_id);
+            Ok(IdlChange::Unchanged) => (),
+            Ok(change) => {
+                log::info!("stored {} idl(pid={program_id}, slot={}) {change:?}", idl.kind.as_str(), idl.slot);
+                inserted += 1;
+            }
+            Err(err) => log::error!("failed to insert idl(pid={program_id}) {err:#?}"),
         }
     }
+    record_indexer_run("idl", start.elapsed(), inserted, true);
     Ok(())
 }
 
 pub async fn manual_idl_import(
     input: &str,
     program_id: &str,
+    slot: Option<u64>,
+    kind: Option<IdlKind>,
     config_path: &str,
 ) -> anyhow::Result<()> {
     let cfg = Config::load(config_path).await?;
     let idl: serde_json::Value = serde_json::from_str(&tokio::fs::read_to_string(input).await?)?;
+    let idl = normalize_idl(&Pubkey::from_str(program_id)?, kind, idl)?;
+    // without a slot, the idl is assumed to have changed now
+    let slot = match slot {
+        Some(slot) => slot,
+        None => RpcClient::new(cfg.rpc_url.clone()).get_slot().await?,
+    };
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
+    db.run_migrations().await?;
+    let program_id = program_id.to_string();
+    let kind = idl.kind;
+    let change = db
+        .interact(move |client, conn| {
+            client.insert_or_update_idl(conn, program_id, slot as i64, kind.as_str(), idl.idl)
+        })
+        .await?;
+    log::info!("imported {} idl(slot={slot}) {change:?}", kind.as_str());
+    Ok(())
+}
+
+pub async fn import_idl_dir(dir: &str, slot: Option<u64>, dry_run: bool, config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let slot = match slot {
+        Some(slot) => slot,
+        None => RpcClient::new(cfg.rpc_url.clone()).get_slot().await?,
+    };
     let mut conn = new_connection(&cfg.db_url)?;
-    let client = db::client::Client {};
-    client.insert_or_update_idl(&mut conn, program_id.to_string(), 0, None, idl)?;
+    run_migrations(&mut conn);
+
+    let report = idl_import::import_idl_dir(&mut conn, Path::new(dir), slot, dry_run)?;
+    for file in report.files.iter().filter(|file| file.status == IdlFileStatus::Invalid) {
+        log::warn!("skipped idl({}) {}", file.path, file.error.as_deref().unwrap_or_default());
+    }
+    log::info!(
+        "imported idls(dir={dir}, slot={slot}, dry_run={dry_run}), inserted={}, updated={}, unchanged={}, invalid={}",
+        report.inserted,
+        report.updated,
+        report.unchanged,
+        report.invalid
+    );
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub async fn reconstruct_idls(
+    start: i64,
+    end: i64,
+    program_ids: &[String],
+    batch_size: i64,
+    config_path: &str,
+) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let programs = program_ids
+        .iter()
+        .map(|program_id| Pubkey::from_str(program_id))
+        .collect::<Result<HashSet<_>, _>>()?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let stats = reconstruct_idl_history(&mut conn, start, end, batch_size, &programs)?;
+    log::info!(
+        "scanned {} blocks(start={start}, end={end}), stored {} of {} idl versions found",
+        stats.blocks,
+        stats.versions_stored,
+        stats.versions_found
+    );
+    println!("{}", serde_json::to_string_pretty(&stats)?);
     Ok(())
 }
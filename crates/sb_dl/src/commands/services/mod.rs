@@ -3,5 +3,6 @@ pub mod idl_indexer;
 pub mod program_indexer;
 pub mod transfer_api;
 pub mod repair_gaps;
+pub mod replicate;
 pub mod transfer_parser;
 pub mod squads_indexer;
\ No newline at end of file
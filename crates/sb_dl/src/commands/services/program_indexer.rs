@@ -1,26 +1,38 @@
-use db::{migrations::run_migrations, new_connection};
-use sb_dl::{config::Config, services::program_indexer::ProgramIndexer};
+use db::async_client::AsyncClient;
+use sb_dl::{config::Config, metrics::record_indexer_run, services::program_indexer::ProgramIndexer};
 
 pub async fn index_programs(config_path: &str) -> anyhow::Result<()> {
     let cfg = Config::load(config_path).await?;
+    let start = std::time::Instant::now();
     let p_indexer = ProgramIndexer::new(&cfg.rpc_url).await?;
-    {
-        let mut conn = new_connection(&cfg.db_url)?;
-        run_migrations(&mut conn);
-    }
-    let programs = p_indexer.get_programs().await?;
-    let mut conn = new_connection(&cfg.db_url)?;
-    let client = db::client::Client {};
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
+    db.run_migrations().await?;
+    let programs = match p_indexer.get_programs().await {
+        Ok(programs) => programs,
+        Err(err) => {
+            record_indexer_run("program", start.elapsed(), 0, false);
+            return Err(err);
+        }
+    };
+    let mut inserted = 0;
     for program in programs {
-        if let Err(err) = client.insert_or_update_program(
-            &mut conn,
-            program.program_id.to_string(),
-            program.deployed_slot as i64,
-            program.executable_account.to_string(),
-            program.program_data,
-        ) {
+        if let Err(err) = db
+            .interact(move |client, conn| {
+                client.insert_or_update_program(
+                    conn,
+                    program.program_id.to_string(),
+                    program.deployed_slot as i64,
+                    program.executable_account.to_string(),
+                    program.program_data,
+                )
+            })
+            .await
+        {
             log::error!("failed to insert program {err:#?}");
+        } else {
+            inserted += 1;
         }
     }
+    record_indexer_run("program", start.elapsed(), inserted, true);
     Ok(())
 }
@@ -1,37 +1,107 @@
 use std::time::Duration;
 use chrono::prelude::*;
 use anyhow::{anyhow, Context};
-use db::{client::{BlockFilter, Client}, migrations::run_migrations, new_connection};
-use sb_dl::{config::Config, services::backfill::Backfiller, types::BlockInfo};
+use db::{async_client::AsyncClient, client::GapRange};
+use sb_dl::{
+    config::Config,
+    services::{
+        backfill::Backfiller,
+        bigtable::Downloader,
+        gap_repair::{gap_repair_loop, GapRepairConfig},
+        gaps::{find_gap_ranges, ScanRange},
+        reingest::{ReingestFrom, Upstream},
+        retry_queue::{retry_loop, RetryConfig, RetryQueue},
+    },
+    sinks::{new_block_sink, SinkConfig},
+    types::{BlockInfo, BlockSource},
+};
+use std::sync::Arc;
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::clock::DEFAULT_SLOTS_PER_EPOCH;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::cli::ServicesCommands;
+use crate::{
+    cli::{GapScanArgs, ServicesCommands},
+    commands::handle_exit,
+};
 
-use super::downloaders::block_persistence_loop;
+use super::downloaders::PersistenceHandle;
 
-const SLOTS_PER_SIX_HOURS: u64 = ((DEFAULT_SLOTS_PER_EPOCH / 2) / 24) *  6;
+/// Resolves the scanned range to block heights, returning the gap ranges within it
+async fn scan_gaps(
+    db: &AsyncClient,
+    scan: &GapScanArgs,
+    current_slot: Option<u64>,
+) -> anyhow::Result<(ScanRange, Option<(i64, i64)>, Vec<GapRange>)> {
+    let range = ScanRange::parse(
+        scan.by,
+        scan.from.as_deref(),
+        scan.to.as_deref(),
+        scan.all,
+        current_slot.map(|slot| slot as i64),
+        Utc::now(),
+    )?;
+    let (heights, gaps) = find_gap_ranges(db, range).await?;
+    Ok((range, heights, gaps))
+}
+
+/// Opens the retry queue and starts draining it into postgres, if a postgres sink is configured
+fn start_retry_queue(
+    db: &AsyncClient,
+    sinks: &[SinkConfig],
+    failed_blocks_dir: &str,
+) -> anyhow::Result<Option<Arc<RetryQueue>>> {
+    if !SinkConfig::any_postgres(sinks) {
+        return Ok(None);
+    }
+    let retry_queue = Arc::new(RetryQueue::open(failed_blocks_dir)?);
+    tokio::task::spawn(retry_loop(retry_queue.clone(), db.clone(), RetryConfig::default()));
+    Ok(Some(retry_queue))
+}
 
 pub async fn find_gaps(
     cmd: ServicesCommands,
     config_path: &str
 ) -> anyhow::Result<()> {
-    let ServicesCommands::FindGaps {limit} = cmd else {
+    let ServicesCommands::FindGaps {scan, limit} = cmd else {
         return Err(anyhow!("invalid command"));
     };
     let cfg = Config::load(config_path).await?;
-    let rpc = RpcClient::new(cfg.rpc_url.clone());
 
-    let current_slot = rpc.get_slot().await?;
-    
-    let mut conn = new_connection(&cfg.db_url)?;
-    run_migrations(&mut conn);
+    let current_slot = if ScanRange::needs_current_slot(scan.by, scan.to.as_deref(), scan.all) {
+        Some(RpcClient::new(cfg.rpc_url.clone()).get_slot().await?)
+    } else {
+        None
+    };
     
-    let client = Client{};
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
+    db.run_migrations().await?;
     
-    let gaps = client.find_gaps(&mut conn, (current_slot - SLOTS_PER_SIX_HOURS) as i64, current_slot as i64, Some(limit))?;
+    let (range, heights, mut gaps) = scan_gaps(&db, &scan, current_slot).await?;
+    let missing_blocks = gaps.iter().map(|gap| gap.missing()).sum::<i64>();
+    let gap_ranges = gaps.len();
+    if let Some(limit) = limit {
+        gaps.truncate(limit);
+    }
     
-    log::info!("found gaps {gaps:#?}");
+    // blocks stored without a height show up as gaps until their height is derived or repaired
+    let missing_height = match range.slot_range() {
+        Some((start, end)) => Some(db.interact(move |client, conn| client.count_blocks_missing_height(conn, start, end)).await?),
+        None => None,
+    };
+    if let Some(missing_height @ 1..) = missing_height {
+        log::warn!("found {missing_height} blocks without a height, run derive-heights to fill them in");
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&serde_json::json!({
+            "start_height": heights.map(|(start, _)| start),
+            "end_height": heights.map(|(_, end)| end),
+            "gap_ranges": gap_ranges,
+            "missing_blocks": missing_blocks,
+            "missing_height": missing_height,
+            "gaps": gaps,
+        }))?
+    );
     Ok(())
 }
 
@@ -39,7 +109,7 @@ pub async fn repair_gaps(
     cmd: ServicesCommands,
     config_path: &str
 ) -> anyhow::Result<()> {
-    let ServicesCommands::RepairGaps { limit, failed_blocks_dir, threads } = cmd else {
+    let ServicesCommands::RepairGaps { scan, limit, failed_blocks_dir, threads, sinks, shutdown_timeout } = cmd else {
         return Err(anyhow!("invalid command"));
     };
 
@@ -49,70 +119,166 @@ pub async fn repair_gaps(
         let rpc = RpcClient::new(cfg.rpc_url.clone());
         rpc.get_slot().await?
     };
-    let conn_pool = db::new_connection_pool(&cfg.db_url, threads as u32 * 2)?;
-
+    let db = AsyncClient::new(&cfg.db_url, threads as usize * 2)?;
 
-    {
-        let mut conn = conn_pool.get()?;
-        run_migrations(&mut conn);
-    }
+    db.run_migrations().await?;
 
     let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel::<BlockInfo>(1000);
 
 
-    {
-
-        let conn_pool = conn_pool.clone();
-        // start the background persistence task
-        tokio::task::spawn(
-            async move { block_persistence_loop(conn_pool, failed_blocks_dir, blocks_rx, threads as usize).await },
-        );
-    }
-
-    let mut conn = conn_pool.get()?;
+    let persistence = {
+        // start the background persistence and retry tasks
+        let retry_queue = start_retry_queue(&db, &sinks, &failed_blocks_dir)?;
+        let sink = new_block_sink(
+            &sinks,
+            retry_queue.clone().map(|retry_queue| (db.clone(), retry_queue)),
+        )?;
+        PersistenceHandle::spawn(sink, blocks_rx, threads as usize, retry_queue)
+    };
 
     let backfiller = Backfiller::new(&cfg.rpc_url);
-    let client = Client{};
-    let gaps = client.find_gaps(&mut conn, (current_slot - SLOTS_PER_SIX_HOURS) as i64, current_slot as i64, Some(limit))?;
+    let (_, _, mut gaps) = scan_gaps(&db, &scan, Some(current_slot)).await?;
     log::info!("found {} gaps", gaps.len());
+    gaps.truncate(limit);
 
-    // start trying to repair gaps at the block immediately preceeding the current missing block
-    for missing_block in gaps {
-        // get block info for the previous block which isn't missing
-        let blocks = client.select_block(&mut conn, BlockFilter::Number(missing_block - 1))?;
-        if blocks.is_empty() {
+    // walk the slots between the stored blocks on either side of each gap, skipped slots return an error
+    for gap in gaps {
+        let Some(prev_slot) = gap.prev_slot else {
+            log::warn!("no stored block precedes gap({}..={}), skipping", gap.gap_start, gap.gap_end);
             continue;
-        }
-        log::info!("guessing_slot(block={}, slot={:?})", blocks[0].number, blocks[0].slot);
-        let mut possible_slot = blocks[0].slot + 1;
-        loop {
+        };
+        // gaps at the end of the stored range are bounded by the current slot
+        let end_slot = gap.next_slot.map_or(current_slot as i64, |next_slot| next_slot - 1);
+        log::info!("repairing gap({}..={}) slots({}..={end_slot})", gap.gap_start, gap.gap_end, prev_slot + 1);
+        let mut found = 0;
+        for possible_slot in prev_slot + 1..=end_slot {
+            if found == gap.missing() {
+                break;
+            }
             if let Ok(block) = backfiller.get_block(possible_slot as u64, false).await {
                 log::info!("found missing block({possible_slot})");
-                let Some(block_height) = block.block_height else {
-                    log::warn!("missing block height");
-                    break;
-                };
+                found += 1;
+                let block_height = block.block_height;
+                if block_height.is_none() {
+                    log::warn!("missing block height for block({possible_slot}), storing by slot");
+                }
                 let time = if let Some(block_time) = block.block_time {
                     DateTime::from_timestamp(block_time, 0)
                 } else {
                     None
                 };
                 if let Err(err) = blocks_tx.send(BlockInfo {
-                    block_height: block_height,
+                    block_height,
                     slot: possible_slot as u64,
                     time,
                     block,
+                    source: BlockSource::Rpc,
+                    // blocks are always fetched with minimization enabled
+                    minimized: true,
                 }).await {
                     log::error!("failed to send block {err:#?}");
                 }
-                break;
             } else {
-                log::warn!("invalid slot({possible_slot}), trying next number...");
-                possible_slot += 1;
+                log::debug!("invalid slot({possible_slot}), trying next number...");
             }
         }
-        
+        if found < gap.missing() {
+            log::warn!("only found {found} of {} missing blocks in gap({}..={})", gap.missing(), gap.gap_start, gap.gap_end);
+        }
     }
-    
-    Ok(())
-}
\ No newline at end of file
+
+    // wait for the repaired blocks to be persisted before exiting
+    drop(blocks_tx);
+    persistence
+        .drain(tokio::time::Instant::now() + Duration::from_secs(shutdown_timeout))
+        .await
+}
+
+pub async fn gap_repairer(
+    cmd: ServicesCommands,
+    config_path: &str
+) -> anyhow::Result<()> {
+    let ServicesCommands::GapRepairer {
+        failed_blocks_dir,
+        threads,
+        sinks,
+        shutdown_timeout,
+        scan,
+        upstream,
+        frequency,
+        max_gaps,
+        concurrency,
+        max_slots_per_attempt,
+        base_backoff,
+        max_backoff,
+    } = cmd else {
+        return Err(anyhow!("invalid command"));
+    };
+    let repair_cfg = GapRepairConfig {
+        interval: Duration::from_secs(frequency),
+        max_gaps,
+        concurrency,
+        max_slots_per_attempt,
+        base_backoff: Duration::from_secs(base_backoff),
+        max_backoff: Duration::from_secs(max_backoff),
+    };
+    // the range is resolved again on every scan, so relative ranges follow the chain tip
+    let scan_range = move |current_slot: i64| {
+        ScanRange::parse(
+            scan.by,
+            scan.from.as_deref(),
+            scan.to.as_deref(),
+            scan.all,
+            Some(current_slot),
+            Utc::now(),
+        )
+    };
+    // fail on invalid arguments before starting any tasks
+    scan_range(i64::MAX).with_context(|| "invalid scan range")?;
+
+    let cfg = Config::load(config_path).await?;
+    let rpc = RpcClient::new(cfg.rpc_url.clone());
+    let upstream = match upstream {
+        ReingestFrom::Bigtable => Upstream::Bigtable(Downloader::new(cfg.bigtable).await?),
+        ReingestFrom::Rpc => Upstream::Rpc(Backfiller::new(&cfg.rpc_url)),
+    };
+
+    let sig_quit = signal(SignalKind::quit())?;
+    let sig_int = signal(SignalKind::interrupt())?;
+    let sig_term = signal(SignalKind::terminate())?;
+
+    // gap scans and repair records share the pool with the sink
+    let db = AsyncClient::new(&cfg.db_url, threads as usize * 2 + 1)?;
+    db.run_migrations().await?;
+
+    let (blocks_tx, blocks_rx) = tokio::sync::mpsc::channel::<BlockInfo>(1000);
+    // start the background persistence and retry tasks
+    let retry_queue = start_retry_queue(&db, &sinks, &failed_blocks_dir)?;
+    let sink = new_block_sink(
+        &sinks,
+        retry_queue.clone().map(|retry_queue| (db.clone(), retry_queue)),
+    )?;
+    let mut persistence =
+        PersistenceHandle::spawn(sink, blocks_rx, threads as usize, retry_queue);
+
+    let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+    let producer = tokio::task::spawn(async move {
+        log::info!("starting gap repairer. upstream={}", upstream.source().as_str());
+        if let Err(err) = gap_repair_loop(db, rpc, upstream, blocks_tx, scan_range, repair_cfg).await {
+            let _ = finished_tx.send(Some(format!("gap repairer failed {err:#?}")));
+        } else {
+            log::info!("gap repairer finished");
+            let _ = finished_tx.send(None);
+        }
+    });
+
+    let err = tokio::select! {
+        err = handle_exit(sig_quit, sig_int, sig_term, finished_rx) => err,
+        err = persistence.failed() => Err(err),
+    };
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(shutdown_timeout);
+    // interrupted repairs are retried on the next start, as their gaps are found again
+    producer.abort();
+    let _ = producer.await;
+    err.and(persistence.drain(deadline).await)
+}
@@ -0,0 +1,97 @@
+use {
+    crate::{cli::ServicesCommands, commands::handle_exit},
+    anyhow::anyhow,
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{
+        config::Config,
+        services::replication::{ReplicationDirection, Replicator},
+    },
+    std::time::Duration,
+    tokio::signal::unix::{signal, SignalKind},
+};
+
+/// Continuously copies new rows between the local and remote databases
+pub async fn replicate(cmd: ServicesCommands, config_path: &str) -> anyhow::Result<()> {
+    let ServicesCommands::Replicate {
+        direction,
+        tables,
+        batch_size,
+        frequency,
+    } = cmd
+    else {
+        return Err(anyhow!("invalid command"));
+    };
+    let cfg = Config::load(config_path).await?;
+    let (src_url, dst_url) = match direction {
+        ReplicationDirection::LocalToRemote => (cfg.db_url.clone(), cfg.remotedb_url.clone()),
+        ReplicationDirection::RemoteToLocal => (cfg.remotedb_url.clone(), cfg.db_url.clone()),
+    };
+    if src_url == dst_url {
+        return Err(anyhow!("source and destination databases are the same"));
+    }
+
+    let mut src = new_connection(&src_url)?;
+    let mut dst = new_connection(&dst_url)?;
+    // the source needs the change_id columns, and the destination the replication state
+    run_migrations(&mut src);
+    run_migrations(&mut dst);
+
+    let replicator = Replicator::new(direction, batch_size)?;
+
+    let sig_quit = signal(SignalKind::quit())?;
+    let sig_int = signal(SignalKind::interrupt())?;
+    let sig_term = signal(SignalKind::terminate())?;
+
+    let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+
+    tokio::task::spawn(async move {
+        log::info!("starting replication(direction={direction:?}, tables={tables:?})");
+        let mut ticker = tokio::time::interval(Duration::from_secs(frequency));
+        loop {
+            ticker.tick().await;
+            // replication runs synchronous transactions spanning both databases, so each tick runs
+            // on the blocking thread pool with the connections moved in and out of it
+            let tables = tables.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                let mut res = Ok(());
+                for table in tables.iter() {
+                    match replicator.replicate_table(&mut src, &mut dst, *table) {
+                        Ok(0) => {}
+                        Ok(copied) => log::info!("replicated {copied} {} rows", table.table_name()),
+                        Err(err) => {
+                            log::error!("failed to replicate {} {err:#?}", table.table_name());
+                            res = Err(err);
+                            break;
+                        }
+                    }
+                }
+                (src, dst, res)
+            })
+            .await;
+            if let Err(err) = &res {
+                log::error!("replication task failed {err:#?}");
+            }
+            match res {
+                Ok((new_src, new_dst, Ok(()))) => {
+                    src = new_src;
+                    dst = new_dst;
+                }
+                // connections may be broken, so reconnect before the next tick
+                Ok((_, _, Err(_))) | Err(_) => {
+                    match (new_connection(&src_url), new_connection(&dst_url)) {
+                        (Ok(new_src), Ok(new_dst)) => {
+                            src = new_src;
+                            dst = new_dst;
+                        }
+                        (Err(err), _) | (_, Err(err)) => {
+                            let _ = finished_tx.send(Some(format!("failed to reconnect {err:#?}")));
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    handle_exit(sig_quit, sig_int, sig_term, finished_rx).await
+}
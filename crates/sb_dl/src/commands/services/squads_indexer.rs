@@ -1,9 +1,11 @@
 use anyhow::anyhow;
 use chrono::prelude::*;
-use db::{client::Client, migrations::run_migrations, new_connection};
+use db::async_client::AsyncClient;
 use diesel::Connection;
 use sb_dl::{
     config::Config,
+    health::{self, UpstreamState},
+    metrics::record_indexer_run,
     programs::squads::{
         v3::MultisigV3,
         v4::{MultisigV4, Permission as PermissionV4},
@@ -23,20 +25,28 @@ pub async fn index_multisigs(cmd: ServicesCommands, config_path: &str) -> anyhow
 
     let cfg = Config::load(config_path).await?;
     let indexer = SquadsIndexer::new(cfg.rpc_url.clone());
-    let mut conn = new_connection(&cfg.db_url)?;
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
 
-    run_migrations(&mut conn);
+    db.run_migrations().await?;
 
     let mut ticker = tokio::time::interval(frequency);
 
-    let client = Client {};
-
     loop {
         ticker.tick().await;
-        let v4_msigs = indexer.fetch_multisigs_v4().await?;
+        let run_start = std::time::Instant::now();
+        let v4_msigs = match indexer.fetch_multisigs_v4().await {
+            Ok(msigs) => msigs,
+            Err(err) => {
+                health::set_upstream(UpstreamState::Disconnected);
+                record_indexer_run("squads", run_start.elapsed(), 0, false);
+                return Err(err);
+            }
+        };
+        let mut records = v4_msigs.len();
+        let mut persisted = true;
         log::info!("found {} v4 multisig accounts", v4_msigs.len());
         let start = Utc::now();
-        if let Err(err) = conn.transaction::<_, anyhow::Error, _>(|conn| {
+        if let Err(err) = db.interact(move |client, conn| conn.transaction::<_, anyhow::Error, _>(|conn| {
             for (account, msig_info) in v4_msigs.into_iter() {
                 let vault = MultisigV4::derive_vault_pda(&account, 0).0;
                 if let Err(err) = client.insert_or_update_squads(
@@ -66,18 +76,28 @@ pub async fn index_multisigs(cmd: ServicesCommands, config_path: &str) -> anyhow
                 }
             }
             Ok(())
-        }) {
+        })).await {
             log::error!("failed to insert v4 multisigs {err:#?}");
+            persisted = false;
         }
         log::info!(
             "took {} seconds to insert v4 records",
             Utc::now().signed_duration_since(start).num_seconds()
         );
 
-        let v3_msigs = indexer.fetch_multisigs_v3().await?;
+        let v3_msigs = match indexer.fetch_multisigs_v3().await {
+            Ok(msigs) => msigs,
+            Err(err) => {
+                health::set_upstream(UpstreamState::Disconnected);
+                record_indexer_run("squads", run_start.elapsed(), records, false);
+                return Err(err);
+            }
+        };
+        records += v3_msigs.len();
+        health::set_upstream(UpstreamState::Connected);
         log::info!("found {} v3 multisig accounts", v3_msigs.len());
         let start = Utc::now();
-        if let Err(err) = conn.transaction::<_, anyhow::Error, _>(|conn| {
+        if let Err(err) = db.interact(move |client, conn| conn.transaction::<_, anyhow::Error, _>(|conn| {
             for (account, msig_info) in v3_msigs.into_iter() {
                 // vault index 0 is reserved for internal usage only
                 let vaults = (1..=msig_info.authority_index)
@@ -105,13 +125,18 @@ pub async fn index_multisigs(cmd: ServicesCommands, config_path: &str) -> anyhow
                 }
             }
             Ok(())
-        }) {
+        })).await {
             log::error!("failed to insert v3 multisigs {err:#?}");
+            persisted = false;
         }
         log::info!(
             "took {} seconds to insert v3 records",
             Utc::now().signed_duration_since(start).num_seconds()
         );
+        record_indexer_run("squads", run_start.elapsed(), records, true);
+        if persisted {
+            health::record_write(None);
+        }
     }
     Ok(())
 }
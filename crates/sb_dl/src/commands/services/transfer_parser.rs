@@ -1,10 +1,13 @@
 use anyhow::{anyhow, Context};
-use db::{client::{BlockFilter, Client}, migrations::run_migrations, models::{Blocks}};
+use db::{async_client::AsyncClient, client::{BlockFilter, BlockOrder}, models::{Blocks}};
 use sb_dl::{config::Config, services::{transfer_flow_api::serve_api, transfer_parser::TransferParser}};
 use tokio::signal::unix::{signal, SignalKind};
 
 use crate::{cli::ServicesCommands, commands::handle_exit};
 
+/// number of blocks loaded from postgres at a time
+const BLOCKS_PAGE_SIZE: i64 = 100;
+
 pub async fn transfer_parser(
     cmd: ServicesCommands,
     config_path: &str,
@@ -22,28 +25,27 @@ pub async fn transfer_parser(
     } else {
         &cfg.db_url
     };
-    let mut conn = db::new_connection(db_url)?;
-    run_migrations(&mut conn);
+    let db = AsyncClient::new(db_url, 1)?;
+    db.run_migrations().await?;
 
-    let client = Client{};
     log::info!("fetching blocks");
-    for block in start..=end {
-        match client.select_block(
-            &mut conn,
-            BlockFilter::Number(block),
-        ) {
-            Ok(mut blocks) => if blocks.is_empty() {
-                log::warn!("failed to find block({block})");
-                continue;
-            } else {
-                let block_number = blocks[0].number;
-                tx_parser.start(vec![std::mem::take(&mut blocks[0])]).await.with_context(|| "indexing failed")?;
-                log::info!("indexed block({block_number})");
-            }
-            Err(err) => {
-                log::debug!("failed to query db {err:#?}");
-            }
-        }
+    let mut next = start;
+    while next <= end {
+        let blocks = db
+            .interact(move |client, conn| {
+                client.select_block(
+                    conn,
+                    BlockFilter::NumberRange { start: next, end, order: BlockOrder::Ascending, limit: Some(BLOCKS_PAGE_SIZE) },
+                )
+            })
+            .await
+            .with_context(|| "failed to query db")?;
+        let (Some(first), Some(last)) = (blocks.first().and_then(|block| block.number), blocks.last().and_then(|block| block.number)) else {
+            break;
+        };
+        tx_parser.start(blocks).await.with_context(|| "indexing failed")?;
+        log::info!("indexed blocks({first}..={last})");
+        next = last + 1;
     }
     Ok(())
 }
@@ -3,6 +3,7 @@ use {
     db::{client::BlockFilter, new_connection},
     sb_dl::{
         config::Config,
+        services::archive::{Archive, ArchivedClient},
         transfer_flow::{
             create_ordered_transfer_for_block, prepare_transfer_flow_for_tx_hash,
             transfer_graph::prepare_transfer_graph,
@@ -21,13 +22,13 @@ pub struct TokenOwnerInfo {
 pub async fn create_transfer_graph_for_tx(
     slot_number: i64,
     tx_hash: &str,
+    archive_dir: Option<&str>,
     config_path: &str,
 ) -> anyhow::Result<()> {
     log::warn!("assumes querying blocks table");
     let cfg: Config = Config::load(config_path).await?;
     let mut db_conn = new_connection(&cfg.db_url)?;
-    let client = db::client::Client {};
-    let mut block = client.select_block(&mut db_conn, BlockFilter::Slot(slot_number))?;
+    let mut block = select_block(&mut db_conn, slot_number, archive_dir)?;
     let block = if block.is_empty() {
         return Err(anyhow!("no block found"));
     } else {
@@ -45,13 +46,13 @@ pub async fn create_transfer_graph_for_tx(
 
 pub async fn create_ordered_transfers_for_entire_block(
     slot_number: i64,
+    archive_dir: Option<&str>,
     config_path: &str,
 ) -> anyhow::Result<()> {
     log::warn!("assumes querying blocks table");
     let cfg: Config = Config::load(config_path).await?;
     let mut db_conn = new_connection(&cfg.db_url)?;
-    let client = db::client::Client {};
-    let mut block = client.select_block(&mut db_conn, BlockFilter::Slot(slot_number))?;
+    let mut block = select_block(&mut db_conn, slot_number, archive_dir)?;
     let block = if block.is_empty() {
         return Err(anyhow!("no block found"));
     } else {
@@ -66,3 +67,17 @@ pub async fn create_ordered_transfers_for_entire_block(
     );
     return Ok(());
 }
+
+/// selects the block at `slot_number`, falling back to the archive if `archive_dir` is set
+fn select_block(
+    conn: &mut diesel::PgConnection,
+    slot_number: i64,
+    archive_dir: Option<&str>,
+) -> anyhow::Result<Vec<db::models::Blocks>> {
+    let client = db::client::Client {};
+    match archive_dir {
+        Some(archive_dir) => ArchivedClient::new(client, Archive::new(archive_dir)?)
+            .select_block(conn, BlockFilter::Slot(slot_number)),
+        None => client.select_block(conn, BlockFilter::Slot(slot_number)),
+    }
+}
@@ -0,0 +1,45 @@
+use {
+    anyhow::anyhow,
+    db::async_client::AsyncClient,
+    sb_dl::{
+        config::Config,
+        services::{
+            backfill::Backfiller,
+            bigtable::Downloader,
+            reingest::{ReingestFrom, Upstream},
+            verify_blocks::verify_slots,
+        },
+    },
+};
+
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_blocks(
+    start: i64,
+    end: i64,
+    sample_rate: f64,
+    limit: Option<i64>,
+    from: ReingestFrom,
+    no_minimization: bool,
+    threads: u32,
+    config_path: &str,
+) -> anyhow::Result<()> {
+    if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+        return Err(anyhow!("sample_rate must be within (0, 1]"));
+    }
+    let cfg = Config::load(config_path).await?;
+    let db = AsyncClient::new(&cfg.db_url, 1)?;
+    db.run_migrations().await?;
+
+    let slots = db
+        .interact(move |client, conn| client.select_sampled_slots(conn, start, end, sample_rate, limit))
+        .await?;
+    log::info!("selected {} blocks to verify(start={start}, end={end}, sample_rate={sample_rate})", slots.len());
+
+    let upstream = match from {
+        ReingestFrom::Bigtable => Upstream::Bigtable(Downloader::new(cfg.bigtable).await?),
+        ReingestFrom::Rpc => Upstream::Rpc(Backfiller::new(&cfg.rpc_url)),
+    };
+    let stats = verify_slots(&db, &upstream, slots, no_minimization, threads as usize).await?;
+    println!("{}", serde_json::to_string_pretty(&stats)?);
+    Ok(())
+}
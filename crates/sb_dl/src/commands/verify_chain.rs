@@ -0,0 +1,19 @@
+use {
+    db::{migrations::run_migrations, new_connection},
+    sb_dl::{config::Config, services::verify_chain},
+};
+
+pub async fn verify_chain(start: i64, end: i64, batch_size: i64, config_path: &str) -> anyhow::Result<()> {
+    let cfg = Config::load(config_path).await?;
+    let mut conn = new_connection(&cfg.db_url)?;
+    run_migrations(&mut conn);
+
+    let report = verify_chain::verify_chain(&mut conn, start, end, batch_size)?;
+    log::info!(
+        "verified {} blocks(start={start}, end={end}), found {} issues",
+        report.blocks_checked,
+        report.issues.len()
+    );
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
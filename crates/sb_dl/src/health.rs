@@ -0,0 +1,252 @@
+//! Health and readiness reporting for long-running services, exposed when `--health-listen` is set.
+//!
+//! `/health` always returns 200 while the process is able to serve requests, along with the
+//! current progress report. `/ready` returns the same report, but responds with 503 when the
+//! upstream is disconnected, or when lag or staleness exceed the configured thresholds.
+
+use {
+    anyhow::Context,
+    axum::{extract::State, http::StatusCode, routing::get, Json, Router},
+    lazy_static::lazy_static,
+    serde::Serialize,
+    std::{
+        sync::{
+            atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamState {
+    /// no connection attempt has completed yet
+    Unknown,
+    Connected,
+    Disconnected,
+}
+
+impl From<u8> for UpstreamState {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Connected,
+            2 => Self::Disconnected,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl From<UpstreamState> for u8 {
+    fn from(value: UpstreamState) -> Self {
+        match value {
+            UpstreamState::Unknown => 0,
+            UpstreamState::Connected => 1,
+            UpstreamState::Disconnected => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HealthThresholds {
+    /// max number of slots the last persisted slot may trail the last received slot by
+    pub max_lag_slots: u64,
+    /// max amount of time since the last successful write
+    pub max_staleness: Duration,
+}
+
+struct HealthState {
+    // slots are stored offset by one so that zero means "never recorded"
+    last_received_slot: AtomicU64,
+    last_persisted_slot: AtomicU64,
+    /// unix timestamp of the last successful write, or process start if nothing was written yet
+    last_write: AtomicI64,
+    wrote: AtomicBool,
+    upstream: AtomicU8,
+}
+
+lazy_static! {
+    static ref STATE: HealthState = HealthState {
+        last_received_slot: AtomicU64::new(0),
+        last_persisted_slot: AtomicU64::new(0),
+        last_write: AtomicI64::new(chrono::Utc::now().timestamp()),
+        wrote: AtomicBool::new(false),
+        upstream: AtomicU8::new(UpstreamState::Unknown.into()),
+    };
+}
+
+/// Records a block received from upstream
+pub fn record_received(slot: u64) {
+    STATE
+        .last_received_slot
+        .fetch_max(slot.saturating_add(1), Ordering::SeqCst);
+}
+
+/// Records a successful write. Services which do not persist blocks pass `None`
+pub fn record_write(slot: Option<u64>) {
+    if let Some(slot) = slot {
+        STATE
+            .last_persisted_slot
+            .fetch_max(slot.saturating_add(1), Ordering::SeqCst);
+    }
+    STATE
+        .last_write
+        .store(chrono::Utc::now().timestamp(), Ordering::SeqCst);
+    STATE.wrote.store(true, Ordering::SeqCst);
+}
+
+/// Updates the state of the upstream connection (geyser, bigtable, rpc)
+pub fn set_upstream(state: UpstreamState) {
+    STATE.upstream.store(state.into(), Ordering::SeqCst);
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub upstream: UpstreamState,
+    pub last_received_slot: Option<u64>,
+    pub last_persisted_slot: Option<u64>,
+    /// seconds since the last successful write, or since startup if nothing was written yet
+    pub seconds_since_last_write: u64,
+    pub has_written: bool,
+    pub lag_slots: Option<u64>,
+    /// reasons the service is not ready, empty when ready
+    pub reasons: Vec<String>,
+}
+
+impl HealthReport {
+    pub fn current(thresholds: &HealthThresholds) -> Self {
+        let slot = |value: u64| value.checked_sub(1);
+        let last_received_slot = slot(STATE.last_received_slot.load(Ordering::SeqCst));
+        let last_persisted_slot = slot(STATE.last_persisted_slot.load(Ordering::SeqCst));
+        let seconds_since_last_write = chrono::Utc::now()
+            .timestamp()
+            .saturating_sub(STATE.last_write.load(Ordering::SeqCst))
+            .max(0) as u64;
+        let upstream = UpstreamState::from(STATE.upstream.load(Ordering::SeqCst));
+        Self::evaluate(
+            thresholds,
+            upstream,
+            last_received_slot,
+            last_persisted_slot,
+            seconds_since_last_write,
+            STATE.wrote.load(Ordering::SeqCst),
+        )
+    }
+    fn evaluate(
+        thresholds: &HealthThresholds,
+        upstream: UpstreamState,
+        last_received_slot: Option<u64>,
+        last_persisted_slot: Option<u64>,
+        seconds_since_last_write: u64,
+        has_written: bool,
+    ) -> Self {
+        let lag_slots = match (last_received_slot, last_persisted_slot) {
+            (Some(received), Some(persisted)) => Some(received.saturating_sub(persisted)),
+            (Some(received), None) => Some(received),
+            _ => None,
+        };
+        let mut reasons = vec![];
+        if upstream == UpstreamState::Disconnected {
+            reasons.push("upstream disconnected".to_string());
+        }
+        if let (Some(lag), Some(_)) = (lag_slots, last_persisted_slot) {
+            if lag > thresholds.max_lag_slots {
+                reasons.push(format!(
+                    "lag({lag}) exceeds max_lag_slots({})",
+                    thresholds.max_lag_slots
+                ));
+            }
+        }
+        if seconds_since_last_write > thresholds.max_staleness.as_secs() {
+            reasons.push(format!(
+                "no successful write for {seconds_since_last_write}s, exceeds max_staleness({}s)",
+                thresholds.max_staleness.as_secs()
+            ));
+        }
+        Self {
+            ready: reasons.is_empty(),
+            upstream,
+            last_received_slot,
+            last_persisted_slot,
+            seconds_since_last_write,
+            has_written,
+            lag_slots,
+            reasons,
+        }
+    }
+}
+
+/// Serves `/health` and `/ready` on `listen_url`
+pub async fn serve_health(listen_url: String, thresholds: HealthThresholds) -> anyhow::Result<()> {
+    // staleness is measured from startup until the first write
+    lazy_static::initialize(&STATE);
+    let router = Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .with_state(Arc::new(thresholds));
+    let listener = tokio::net::TcpListener::bind(&listen_url)
+        .await
+        .with_context(|| format!("failed to bind health listener {listen_url}"))?;
+    log::info!("serving health checks on {listen_url}");
+    axum::serve(listener, router)
+        .await
+        .with_context(|| "health server failed")
+}
+
+async fn health(State(thresholds): State<Arc<HealthThresholds>>) -> Json<HealthReport> {
+    Json(HealthReport::current(&thresholds))
+}
+
+async fn ready(
+    State(thresholds): State<Arc<HealthThresholds>>,
+) -> (StatusCode, Json<HealthReport>) {
+    let report = HealthReport::current(&thresholds);
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_health_report_thresholds() {
+        let thresholds = HealthThresholds {
+            max_lag_slots: 100,
+            max_staleness: Duration::from_secs(60),
+        };
+        let report = HealthReport::evaluate(
+            &thresholds,
+            UpstreamState::Connected,
+            Some(1_000),
+            Some(950),
+            10,
+            true,
+        );
+        assert!(report.ready);
+        assert_eq!(report.lag_slots, Some(50));
+
+        // lagging too far behind upstream
+        let report = HealthReport::evaluate(
+            &thresholds,
+            UpstreamState::Connected,
+            Some(1_000),
+            Some(800),
+            10,
+            true,
+        );
+        assert!(!report.ready);
+        assert_eq!(report.reasons.len(), 1);
+
+        // stale and disconnected
+        let report =
+            HealthReport::evaluate(&thresholds, UpstreamState::Disconnected, None, None, 61, false);
+        assert!(!report.ready);
+        assert_eq!(report.reasons.len(), 2);
+        assert_eq!(report.lag_slots, None);
+    }
+}
@@ -1,6 +1,9 @@
 pub mod config;
+pub mod health;
+pub mod metrics;
 pub mod parsable_instructions;
 pub mod services;
+pub mod sinks;
 pub mod transfer_flow;
 pub mod types;
 pub mod utils;
@@ -4,8 +4,15 @@ pub mod commands;
 use {
     anyhow::{anyhow, Result},
     clap::{value_parser, Arg, ArgMatches, Command, Parser},
-    cli::{Commands, ServicesCommands},
-    sb_dl::utils::init_log,
+    cli::{Commands, DbCommands, ExportCommands, ServicesCommands},
+    db::client::ProvenanceFilter,
+    sb_dl::{
+        config::Config,
+        health::{serve_health, HealthThresholds},
+        metrics::serve_metrics,
+        utils::init_log,
+    },
+    std::time::Duration,
 };
 
 #[tokio::main]
@@ -23,6 +30,44 @@ async fn main() -> Result<()> {
         }
     }
     let guard = init_log(&app.log_level, &app.log_file);
+    // production services should not apply ddl implicitly
+    db::migrations::set_auto_migrate(!app.no_auto_migrate);
+    if let Some(listen_url) = app.metrics_listen.clone() {
+        // the rpc is used to report lag behind the chain tip, and is optional
+        let rpc_url = Config::load(&app.config)
+            .await
+            .ok()
+            .map(|cfg| cfg.rpc_url)
+            .filter(|rpc_url| !rpc_url.is_empty());
+        tokio::task::spawn(async move {
+            if let Err(err) = serve_metrics(listen_url, rpc_url).await {
+                log::error!("{err:#?}");
+            }
+        });
+    }
+    if let Some(listen_url) = app.health_listen.clone() {
+        if matches!(
+            app.command,
+            Commands::Services {
+                command: ServicesCommands::GeyserStream { .. }
+                    | ServicesCommands::Backfiller { .. }
+                    | ServicesCommands::BigtableDownloader { .. }
+                    | ServicesCommands::SquadsIndexer { .. }
+            }
+        ) {
+            let thresholds = HealthThresholds {
+                max_lag_slots: app.health_max_lag_slots,
+                max_staleness: Duration::from_secs(app.health_max_staleness_secs),
+            };
+            tokio::task::spawn(async move {
+                if let Err(err) = serve_health(listen_url, thresholds).await {
+                    log::error!("{err:#?}");
+                }
+            });
+        } else {
+            log::warn!("--health-listen is not supported by this command, ignoring");
+        }
+    }
     let res  = match &app.command {
         Commands::Services { command } => match command {
             ServicesCommands::BigtableDownloader { .. } => {
@@ -48,6 +93,9 @@ async fn main() -> Result<()> {
             ServicesCommands::RepairGaps { .. } => {
                 commands::services::repair_gaps::repair_gaps(command.clone(), &app.config).await
             }
+            ServicesCommands::GapRepairer { .. } => {
+                commands::services::repair_gaps::gap_repairer(command.clone(), &app.config).await
+            }
             ServicesCommands::TransferParser { .. } => {
                 commands::services::transfer_parser::transfer_parser(command.clone(), &app.config)
                     .await
@@ -60,28 +108,69 @@ async fn main() -> Result<()> {
                 commands::services::downloaders::import_failed_blocks(command.clone(), &app.config)
                     .await
             }
+            ServicesCommands::RetryQueueStatus { .. } => {
+                commands::services::downloaders::retry_queue_status(command.clone()).await
+            }
+            ServicesCommands::Replicate { .. } => {
+                commands::services::replicate::replicate(command.clone(), &app.config).await
+            }
             ServicesCommands::FindGaps {..} => {
                 commands::services::repair_gaps::find_gaps(command.clone(), &app.config).await
             }
         },
+        Commands::Export { command } => match command {
+            ExportCommands::Parquet { .. } => {
+                commands::export::export_parquet(command.clone(), &app.config).await
+            }
+        },
+        Commands::Db { command } => match command {
+            DbCommands::Migrate => commands::db::migrate(&app.config).await,
+            DbCommands::Rollback { n } => commands::db::rollback(*n, &app.config).await,
+            DbCommands::Status => commands::db::migration_status(&app.config).await,
+            DbCommands::Redo => commands::db::redo(&app.config).await,
+        },
         Commands::NewConfig => commands::config::new_config(&app.config).await,
-        Commands::ManualIdlImport { input, program_id } => {
-            commands::services::idl_indexer::manual_idl_import(input, program_id, &app.config).await
+        Commands::ManualIdlImport {
+            input,
+            program_id,
+            slot,
+            kind,
+        } => {
+            commands::services::idl_indexer::manual_idl_import(input, program_id, *slot, *kind, &app.config)
+                .await
+        }
+        Commands::ImportIdlDir { dir, slot, dry_run } => {
+            commands::services::idl_indexer::import_idl_dir(dir, *slot, *dry_run, &app.config).await
+        }
+        Commands::ReconstructIdls {
+            start,
+            end,
+            program_id,
+            batch_size,
+        } => {
+            commands::services::idl_indexer::reconstruct_idls(*start, *end, program_id, *batch_size, &app.config)
+                .await
         }
         Commands::CreateTransferGraphForTx {
             slot_number,
             tx_hash,
+            archive_dir,
         } => {
             commands::transfer_graph::create_transfer_graph_for_tx(
                 *slot_number,
                 tx_hash,
+                archive_dir.as_deref(),
                 &app.config,
             )
             .await
         }
-        Commands::CreateOrderedTransfersForBlock { slot_number } => {
+        Commands::CreateOrderedTransfersForBlock {
+            slot_number,
+            archive_dir,
+        } => {
             commands::transfer_graph::create_ordered_transfers_for_entire_block(
                 *slot_number,
+                archive_dir.as_deref(),
                 &app.config,
             )
             .await
@@ -89,6 +178,81 @@ async fn main() -> Result<()> {
         Commands::FindGapEnd {
             gap_start,
         } => commands::db::find_gap_end(*gap_start, &app.config).await,
+        Commands::Archive {
+            start,
+            end,
+            archive_dir,
+            batch_size,
+        } => {
+            commands::archive::archive_blocks(*start, *end, archive_dir, *batch_size, &app.config)
+                .await
+        }
+        Commands::Reingest {
+            start,
+            end,
+            from,
+            source,
+            minimized,
+            encoding_version_below,
+            no_minimization,
+            threads,
+        } => {
+            commands::reingest::reingest(
+                *start,
+                *end,
+                *from,
+                ProvenanceFilter {
+                    source: source.clone(),
+                    minimized: *minimized,
+                    encoding_version_below: *encoding_version_below,
+                },
+                *no_minimization,
+                *threads,
+                &app.config,
+            )
+            .await
+        }
+        Commands::DeriveHeights {
+            start,
+            end,
+            batch_size,
+        } => commands::derive_heights::derive_heights(*start, *end, *batch_size, &app.config).await,
+        Commands::VerifyBlocks {
+            start,
+            end,
+            sample_rate,
+            limit,
+            from,
+            no_minimization,
+            threads,
+        } => {
+            commands::verify_blocks::verify_blocks(
+                *start,
+                *end,
+                *sample_rate,
+                *limit,
+                *from,
+                *no_minimization,
+                *threads,
+                &app.config,
+            )
+            .await
+        }
+        Commands::VerifyChain {
+            start,
+            end,
+            batch_size,
+        } => commands::verify_chain::verify_chain(*start, *end, *batch_size, &app.config).await,
+        Commands::Coverage {
+            by,
+            from,
+            to,
+            format,
+            failed_blocks_dir,
+        } => {
+            commands::coverage::coverage(*by, from, to.as_deref(), *format, failed_blocks_dir, &app.config)
+                .await
+        }
     };
 
     if let Some(g) = guard {
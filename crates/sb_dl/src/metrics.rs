@@ -0,0 +1,169 @@
+//! Prometheus metrics shared by all services, exposed when `--metrics-listen` is set
+
+use {
+    anyhow::Context,
+    axum::{http::StatusCode, response::IntoResponse, routing::get, Router},
+    lazy_static::lazy_static,
+    prometheus::{
+        register_histogram, register_histogram_vec, register_int_counter,
+        register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec,
+        IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    },
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::commitment_config::CommitmentConfig,
+    std::time::Duration,
+};
+
+lazy_static! {
+    pub static ref BLOCKS_FETCHED: IntCounterVec = register_int_counter_vec!(
+        "sbdl_blocks_fetched_total",
+        "blocks fetched from upstream",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref BLOCKS_PERSISTED: IntCounterVec = register_int_counter_vec!(
+        "sbdl_blocks_persisted_total",
+        "blocks persisted to the configured sinks",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref BLOCKS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "sbdl_blocks_failed_total",
+        "blocks which failed to be encoded or persisted",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref BLOCKS_QUEUED: IntCounter = register_int_counter!(
+        "sbdl_blocks_queued_total",
+        "blocks added to the retry queue after failing to be inserted into postgres"
+    )
+    .unwrap();
+    pub static ref PERSISTENCE_CHANNEL_DEPTH: IntGauge = register_int_gauge!(
+        "sbdl_persistence_channel_depth",
+        "blocks waiting in the block persistence channel"
+    )
+    .unwrap();
+    pub static ref SEMAPHORE_WAIT_SECONDS: Histogram = register_histogram!(
+        "sbdl_persistence_semaphore_wait_seconds",
+        "time spent waiting for a persistence permit"
+    )
+    .unwrap();
+    pub static ref DB_INSERT_SECONDS: Histogram = register_histogram!(
+        "sbdl_db_insert_seconds",
+        "latency of block inserts into postgres"
+    )
+    .unwrap();
+    pub static ref LAST_PERSISTED_SLOT: IntGauge = register_int_gauge!(
+        "sbdl_last_persisted_slot",
+        "highest slot persisted by this process"
+    )
+    .unwrap();
+    pub static ref LAST_PERSISTED_BLOCK_HEIGHT: IntGauge = register_int_gauge!(
+        "sbdl_last_persisted_block_height",
+        "highest block height persisted by this process"
+    )
+    .unwrap();
+    pub static ref CHAIN_TIP_LAG_SLOTS: IntGauge = register_int_gauge!(
+        "sbdl_chain_tip_lag_slots",
+        "number of slots between the finalized chain tip and the last persisted slot"
+    )
+    .unwrap();
+    pub static ref INDEXER_RUNS: IntCounterVec = register_int_counter_vec!(
+        "sbdl_indexer_runs_total",
+        "indexer runs by outcome",
+        &["indexer", "status"]
+    )
+    .unwrap();
+    pub static ref INDEXER_RUN_SECONDS: HistogramVec = register_histogram_vec!(
+        "sbdl_indexer_run_seconds",
+        "duration of indexer runs",
+        &["indexer"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0]
+    )
+    .unwrap();
+    pub static ref INDEXER_RECORDS: IntCounterVec = register_int_counter_vec!(
+        "sbdl_indexer_records_total",
+        "records written by indexers",
+        &["indexer"]
+    )
+    .unwrap();
+    pub static ref GAP_REPAIRS: IntCounterVec = register_int_counter_vec!(
+        "sbdl_gap_repairs_total",
+        "gap repair attempts by outcome",
+        &["status"]
+    )
+    .unwrap();
+}
+
+/// Records a successfully persisted block
+pub fn record_persisted(source: &str, slot: u64, block_height: Option<u64>) {
+    BLOCKS_PERSISTED.with_label_values(&[source]).inc();
+    if slot as i64 > LAST_PERSISTED_SLOT.get() {
+        LAST_PERSISTED_SLOT.set(slot as i64);
+    }
+    if let Some(block_height) = block_height {
+        if block_height as i64 > LAST_PERSISTED_BLOCK_HEIGHT.get() {
+            LAST_PERSISTED_BLOCK_HEIGHT.set(block_height as i64);
+        }
+    }
+}
+
+/// Records the outcome and duration of a single indexer run
+pub fn record_indexer_run(indexer: &str, duration: Duration, records: usize, ok: bool) {
+    INDEXER_RUNS
+        .with_label_values(&[indexer, if ok { "ok" } else { "error" }])
+        .inc();
+    INDEXER_RUN_SECONDS
+        .with_label_values(&[indexer])
+        .observe(duration.as_secs_f64());
+    INDEXER_RECORDS
+        .with_label_values(&[indexer])
+        .inc_by(records as u64);
+}
+
+/// Serves metrics at `/metrics`. When `rpc_url` is provided the finalized chain tip
+/// is polled to report how far behind the last persisted slot is
+pub async fn serve_metrics(listen_url: String, rpc_url: Option<String>) -> anyhow::Result<()> {
+    if let Some(rpc_url) = rpc_url {
+        tokio::task::spawn(chain_tip_lag_loop(rpc_url));
+    }
+    let router = Router::new().route("/metrics", get(metrics));
+    let listener = tokio::net::TcpListener::bind(&listen_url)
+        .await
+        .with_context(|| format!("failed to bind metrics listener {listen_url}"))?;
+    log::info!("serving metrics on {listen_url}");
+    axum::serve(listener, router)
+        .await
+        .with_context(|| "metrics server failed")
+}
+
+async fn metrics() -> impl IntoResponse {
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to encode metrics {err:#?}"),
+        )
+            .into_response();
+    }
+    (StatusCode::OK, buffer).into_response()
+}
+
+async fn chain_tip_lag_loop(rpc_url: String) {
+    let rpc = RpcClient::new(rpc_url);
+    let mut ticker = tokio::time::interval(Duration::from_secs(15));
+    loop {
+        ticker.tick().await;
+        let last_persisted = LAST_PERSISTED_SLOT.get();
+        if last_persisted == 0 {
+            continue;
+        }
+        match rpc
+            .get_slot_with_commitment(CommitmentConfig::finalized())
+            .await
+        {
+            Ok(tip) => CHAIN_TIP_LAG_SLOTS.set(tip as i64 - last_persisted),
+            Err(err) => log::debug!("failed to fetch chain tip {err:#?}"),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Tiered storage for cold block ranges.
+//!
+//! Blocks are grouped by epoch into `epoch_{N}.jsonl.zst` files, where every line is compressed
+//! as an independent zstd frame. The concatenated frames form a regular `.jsonl.zst` file that can
+//! be decompressed with standard tooling, while the accompanying `epoch_{N}.index.json` maps each
+//! slot to the offset of its frame so that single blocks can be read without decompressing the epoch.
+
+use {
+    anyhow::{anyhow, Context, Result},
+    db::{
+        client::{BlockFilter, Client},
+        models::Blocks,
+    },
+    diesel::PgConnection,
+    serde::{Deserialize, Serialize},
+    solana_sdk::clock::DEFAULT_SLOTS_PER_EPOCH,
+    std::{
+        collections::BTreeMap,
+        fs::{File, OpenOptions},
+        io::{Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+/// compression level used for archived blocks
+const COMPRESSION_LEVEL: i32 = 9;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// block height of the archived block, None for blocks without a height
+    pub number: Option<i64>,
+    /// byte offset of the compressed frame within the archive file
+    pub offset: u64,
+    /// length of the compressed frame
+    pub len: u64,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    pub epoch: u64,
+    /// maps slot -> location of the block within the archive file
+    pub entries: BTreeMap<i64, IndexEntry>,
+}
+
+#[derive(Clone)]
+pub struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create archive dir {dir:?}"))?;
+        Ok(Self { dir })
+    }
+    pub fn epoch_for_slot(slot: i64) -> u64 {
+        slot as u64 / DEFAULT_SLOTS_PER_EPOCH
+    }
+    fn data_path(&self, epoch: u64) -> PathBuf {
+        self.dir.join(format!("epoch_{epoch}.jsonl.zst"))
+    }
+    fn index_path(&self, epoch: u64) -> PathBuf {
+        self.dir.join(format!("epoch_{epoch}.index.json"))
+    }
+    /// Loads the index for the given epoch, returning an empty index if the epoch is not archived
+    pub fn load_index(&self, epoch: u64) -> Result<ArchiveIndex> {
+        let path = self.index_path(epoch);
+        if !path.exists() {
+            return Ok(ArchiveIndex {
+                epoch,
+                ..Default::default()
+            });
+        }
+        let index = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+        serde_json::from_slice(&index).with_context(|| format!("failed to deserialize {path:?}"))
+    }
+    fn save_index(&self, index: &ArchiveIndex) -> Result<()> {
+        let path = self.index_path(index.epoch);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file =
+            File::create(&tmp_path).with_context(|| format!("failed to create {tmp_path:?}"))?;
+        file.write_all(&serde_json::to_vec(index)?)?;
+        file.sync_all()?;
+        // rename is atomic, so readers never observe a partially written index
+        std::fs::rename(&tmp_path, &path).with_context(|| format!("failed to rename {tmp_path:?}"))
+    }
+    /// Returns the epochs for which an archive index exists, in ascending order
+    pub fn archived_epochs(&self) -> Result<Vec<u64>> {
+        let mut epochs = std::fs::read_dir(&self.dir)
+            .with_context(|| "failed to read archive dir")?
+            .filter_map(|entry| {
+                let file_name = entry.ok()?.file_name();
+                let file_name = file_name.to_str()?;
+                file_name
+                    .strip_prefix("epoch_")?
+                    .strip_suffix(".index.json")?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        epochs.sort_unstable();
+        Ok(epochs)
+    }
+    /// Appends blocks to their epoch archive files, returning the slots which were archived.
+    ///
+    /// Blocks which are already present in the archive are skipped, and returned as archived.
+    /// The data file is synced to disk before the index is updated.
+    pub fn append(&self, blocks: &[Blocks]) -> Result<Vec<i64>> {
+        let mut by_epoch: BTreeMap<u64, Vec<&Blocks>> = BTreeMap::new();
+        for block in blocks {
+            by_epoch
+                .entry(Self::epoch_for_slot(block.slot))
+                .or_default()
+                .push(block);
+        }
+        let mut archived = Vec::with_capacity(blocks.len());
+        for (epoch, blocks) in by_epoch {
+            let mut index = self.load_index(epoch)?;
+            let path = self.data_path(epoch);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {path:?}"))?;
+            let mut offset = file.metadata()?.len();
+            for block in blocks {
+                if index.entries.contains_key(&block.slot) {
+                    archived.push(block.slot);
+                    continue;
+                }
+                let mut line = serde_json::to_vec(block)
+                    .with_context(|| format!("failed to serialize block({})", block.slot))?;
+                line.push(b'\n');
+                let frame = zstd::encode_all(&line[..], COMPRESSION_LEVEL)
+                    .with_context(|| format!("failed to compress block({})", block.slot))?;
+                file.write_all(&frame)?;
+                index.entries.insert(
+                    block.slot,
+                    IndexEntry {
+                        number: block.number,
+                        offset,
+                        len: frame.len() as u64,
+                    },
+                );
+                offset += frame.len() as u64;
+                archived.push(block.slot);
+            }
+            file.sync_all()?;
+            self.save_index(&index)?;
+        }
+        Ok(archived)
+    }
+    /// Reads a single block from the archive by slot
+    pub fn get_by_slot(&self, slot: i64) -> Result<Option<Blocks>> {
+        let epoch = Self::epoch_for_slot(slot);
+        let index = self.load_index(epoch)?;
+        match index.entries.get(&slot) {
+            Some(entry) => Ok(Some(self.read_entry(epoch, entry)?)),
+            None => Ok(None),
+        }
+    }
+    /// Reads a single block from the archive by block height.
+    ///
+    /// As the archive is keyed by slot this requires scanning the epoch indexes
+    pub fn get_by_number(&self, number: i64) -> Result<Option<Blocks>> {
+        for epoch in self.archived_epochs()? {
+            let index = self.load_index(epoch)?;
+            if let Some(entry) = index.entries.values().find(|entry| entry.number == Some(number)) {
+                return Ok(Some(self.read_entry(epoch, entry)?));
+            }
+        }
+        Ok(None)
+    }
+    /// Returns the archived block with the lowest block height
+    pub fn first_block(&self) -> Result<Option<Blocks>> {
+        for epoch in self.archived_epochs()? {
+            let index = self.load_index(epoch)?;
+            if let Some(entry) = index
+                .entries
+                .values()
+                .filter(|entry| entry.number.is_some())
+                .min_by_key(|entry| entry.number)
+            {
+                return Ok(Some(self.read_entry(epoch, entry)?));
+            }
+        }
+        Ok(None)
+    }
+    fn read_entry(&self, epoch: u64, entry: &IndexEntry) -> Result<Blocks> {
+        let path = self.data_path(epoch);
+        let mut file = File::open(&path).with_context(|| format!("failed to open {path:?}"))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut frame = vec![0_u8; entry.len as usize];
+        file.read_exact(&mut frame)
+            .with_context(|| format!("failed to read frame at offset {}", entry.offset))?;
+        let line = zstd::decode_all(&frame[..]).with_context(|| "failed to decompress frame")?;
+        serde_json::from_slice(&line).with_context(|| "failed to deserialize archived block")
+    }
+}
+
+/// Wraps [`Client`] so that block lookups fall back to the local archive
+/// when a block is no longer present in the blocks table
+#[derive(Clone)]
+pub struct ArchivedClient {
+    client: Client,
+    archive: Archive,
+}
+
+impl ArchivedClient {
+    pub fn new(client: Client, archive: Archive) -> Self {
+        Self { client, archive }
+    }
+    /// Same as [`Client::select_block`], except that `Slot`, `Number` and `FirstBlock` filters
+    /// are also resolved against the archive. `LastBlock`, `All` and range filters only return
+    /// blocks stored in postgres.
+    pub fn select_block(
+        &self,
+        conn: &mut PgConnection,
+        filter: BlockFilter,
+    ) -> Result<Vec<Blocks>> {
+        let blocks = self.client.select_block(conn, filter)?;
+        let archived = match filter {
+            BlockFilter::Slot(slot) if blocks.is_empty() => self.archive.get_by_slot(slot)?,
+            BlockFilter::Number(number) if blocks.is_empty() => {
+                self.archive.get_by_number(number)?
+            }
+            // archived blocks are always older than the blocks remaining in postgres
+            BlockFilter::FirstBlock => self.archive.first_block()?,
+            _ => None,
+        };
+        match archived {
+            Some(block) => Ok(vec![block]),
+            None => Ok(blocks),
+        }
+    }
+}
+
+/// Moves all blocks with slots in `[start_slot, end_slot]` from postgres into the archive.
+///
+/// Blocks are only deleted from postgres once they have been durably written to the archive,
+/// returning the number of blocks archived
+pub fn archive_slot_range(
+    conn: &mut PgConnection,
+    archive: &Archive,
+    start_slot: i64,
+    end_slot: i64,
+    batch_size: i64,
+) -> Result<usize> {
+    if end_slot < start_slot {
+        return Err(anyhow!("end_slot({end_slot}) < start_slot({start_slot})"));
+    }
+    if batch_size <= 0 {
+        return Err(anyhow!("batch_size must be positive"));
+    }
+    let client = Client {};
+    let mut archived_count = 0;
+    let mut batch_start = start_slot;
+    while batch_start <= end_slot {
+        let batch_end = end_slot.min(batch_start + batch_size - 1);
+        let blocks = client.select_blocks_by_slot_range(conn, batch_start, batch_end)?;
+        if !blocks.is_empty() {
+            let archived = archive.append(&blocks)?;
+            let deleted = client.delete_blocks_by_slot(conn, &archived)?;
+            log::info!("archived slots({batch_start}..={batch_end}) blocks={deleted}");
+            archived_count += deleted;
+        }
+        batch_start = batch_end + 1;
+    }
+    Ok(archived_count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_archive_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("sb_dl_archive_{}", std::process::id()));
+        let archive = Archive::new(&dir).unwrap();
+        let blocks = (0..10)
+            .map(|i| Blocks {
+                number: Some(100 + i),
+                // spread the blocks across two epochs
+                slot: i * (DEFAULT_SLOTS_PER_EPOCH as i64 / 5),
+                time: None,
+                processed: false,
+                data: serde_json::json!({ "blockhash": format!("hash_{i}") }),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(archive.append(&blocks).unwrap().len(), 10);
+        // re-archiving the same blocks must not duplicate them
+        assert_eq!(archive.append(&blocks[0..2]).unwrap().len(), 2);
+        assert_eq!(archive.archived_epochs().unwrap(), vec![0, 1]);
+        assert_eq!(archive.load_index(0).unwrap().entries.len(), 5);
+
+        for block in blocks.iter() {
+            assert_eq!(archive.get_by_slot(block.slot).unwrap().as_ref(), Some(block));
+            assert_eq!(archive.get_by_number(block.number.unwrap()).unwrap().as_ref(), Some(block));
+        }
+        assert_eq!(archive.first_block().unwrap().as_ref(), Some(&blocks[0]));
+        assert!(archive.get_by_slot(1).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,5 +1,10 @@
 use {
-    crate::{types::BlockInfo, utils::filter_vote_transactions},
+    crate::{
+        health::{self, UpstreamState},
+        metrics::BLOCKS_FETCHED,
+        types::{BlockInfo, BlockSource},
+        utils::filter_vote_transactions,
+    },
     anyhow::Context,
     solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcBlockConfig},
     solana_sdk::commitment_config::CommitmentConfig,
@@ -33,6 +38,8 @@ impl Backfiller {
                 max_supported_transaction_version: Some(1),                
             }
         ).await.with_context(|| "failed to get block")?;
+        BLOCKS_FETCHED.with_label_values(&["rpc"]).inc();
+        health::record_received(slot);
         if no_minimization == false {
             block = filter_vote_transactions(block);
         }
@@ -44,21 +51,28 @@ impl Backfiller {
         no_minimization: bool,
     ) -> anyhow::Result<()> {
         loop {
-            let current_slot = self
+            let current_slot = match self
                 .rpc
                 .get_slot_with_commitment(CommitmentConfig::finalized())
                 .await
-                .with_context(|| "failed to get slot height")?;
+            {
+                Ok(current_slot) => {
+                    health::set_upstream(UpstreamState::Connected);
+                    current_slot
+                }
+                Err(err) => {
+                    health::set_upstream(UpstreamState::Disconnected);
+                    return Err(err).with_context(|| "failed to get slot height");
+                }
+            };
             // backfill 300 most recent blocks, over estimating blocks per second by 2x
             for slot_height in current_slot - 300..current_slot {
                 match self.get_block(slot_height, no_minimization).await {
                     Ok(block) => {
-                        let block_height = if let Some(block_height) = block.block_height {
-                            block_height
-                        } else {
-                            log::warn!("block height is None for block({slot_height}");
-                            continue;
-                        };
+                        let block_height = block.block_height;
+                        if block_height.is_none() {
+                            log::warn!("block height is None for block({slot_height}), storing by slot");
+                        }
                         let time = if let Some(block_time) = block.block_time {
                             DateTime::from_timestamp(block_time, 0)
                         } else {
@@ -70,6 +84,8 @@ impl Backfiller {
                                 block,
                                 block_height,
                                 time,
+                                source: BlockSource::Rpc,
+                                minimized: !no_minimization,
                             })
                             .await
                         {
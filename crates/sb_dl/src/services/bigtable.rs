@@ -1,5 +1,5 @@
 use {
-    crate::{config::BigTableConfig, types::BlockInfo, utils::process_block}, anyhow::{anyhow, Context}, bigtable_rs::{
+    crate::{config::BigTableConfig, health::{self, UpstreamState}, metrics::BLOCKS_FETCHED, types::{BlockInfo, BlockSource}, utils::process_block}, anyhow::{anyhow, Context}, bigtable_rs::{
         bigtable::{read_rows::decode_read_rows_response, BigTable, BigTableConnection},
         google::bigtable::v2::{row_filter::Filter, ReadRowsRequest, RowFilter, RowSet},
     }, futures::stream::{self, StreamExt}, solana_sdk::clock::Slot, solana_storage_bigtable::{
@@ -27,6 +27,7 @@ impl Downloader {
         )
         .await
         .with_context(|| "failed to initialize bigtable connection")?;
+        health::set_upstream(UpstreamState::Connected);
         Ok(Self {
             conn: bigtable_conn,
             max_decoding_size: cfg.max_decoding_size,
@@ -95,16 +96,17 @@ impl Downloader {
                 if !exit.load(Ordering::SeqCst) {
                     match Self::get_confirmed_block(client, max_decoding_size, slot).await {
                         Ok(block) => {
+                            health::set_upstream(UpstreamState::Connected);
                             if let Some(block) = block {
+                                BLOCKS_FETCHED.with_label_values(&["bigtable"]).inc();
+                                health::record_received(slot);
                                 // post process the block to handle encoding and space minimization
                                 match process_block(block, no_minimization) {
                                     Ok(block) => {
-                                        let block_height = if let Some(block_height) = block.block_height {
-                                            block_height
-                                        } else {
-                                            log::warn!("block({slot}) height is none");
-                                            return;
-                                        };
+                                        let block_height = block.block_height;
+                                        if block_height.is_none() {
+                                            log::warn!("block({slot}) height is none, storing by slot");
+                                        }
                                         let time = if let Some(block_time) = block.block_time {
                                             DateTime::from_timestamp(block_time, 0)
                                         } else {
@@ -116,6 +118,8 @@ impl Downloader {
                                                 slot,
                                                 block,
                                                 time,
+                                                source: BlockSource::Bigtable,
+                                                minimized: !no_minimization,
                                             })
                                             .await
                                         {
@@ -131,6 +135,7 @@ impl Downloader {
                             }
                         }
                         Err(err) => {
+                            health::set_upstream(UpstreamState::Disconnected);
                             log::error!("failed to fetch block({slot}) {err:#?}");
                         }
                     }
@@ -143,6 +148,21 @@ impl Downloader {
         .await;
         Ok(())
     }
+    /// Downloads and encodes a single block, returning None if bigtable has no block for the slot
+    pub async fn get_block(
+        &self,
+        slot: Slot,
+        no_minimization: bool,
+    ) -> anyhow::Result<Option<UiConfirmedBlock>> {
+        let block = Self::get_confirmed_block(self.conn.client(), self.max_decoding_size, slot).await?;
+        match block {
+            Some(block) => {
+                BLOCKS_FETCHED.with_label_values(&["bigtable"]).inc();
+                Ok(Some(process_block(block, no_minimization)?))
+            }
+            None => Ok(None),
+        }
+    }
     /// Downloads multiple blocks at once, returning a vector of vec![(block_slot, block_data)]
     pub async fn get_confirmed_blocks(
         &self,
@@ -0,0 +1,166 @@
+//! Derives the heights of blocks which were stored without one.
+//!
+//! A block's height is one greater than its parent's, so within a contiguous run of stored blocks,
+//! where each block's `parentSlot` is the slot of the preceding stored block, heights can be counted
+//! forwards or backwards from any block with a known height.
+
+use {
+    anyhow::{anyhow, Result},
+    db::{client::Client, models::Blocks},
+    diesel::PgConnection,
+    serde::Serialize,
+};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DerivedHeights {
+    /// heights derived by counting forwards from a preceding block
+    pub forward: usize,
+    /// heights derived by counting backwards from a following block
+    pub backward: usize,
+    /// blocks in the range which still have no height
+    pub remaining: i64,
+}
+
+/// A stored block reduced to the fields needed to derive heights
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeightLink {
+    pub slot: i64,
+    /// None if the block data has no parent slot, which breaks the chain
+    pub parent_slot: Option<i64>,
+    pub number: Option<i64>,
+}
+
+impl From<&Blocks> for HeightLink {
+    fn from(block: &Blocks) -> Self {
+        Self {
+            slot: block.slot,
+            parent_slot: block.data["parentSlot"].as_i64(),
+            number: block.number,
+        }
+    }
+}
+
+/// Derives heights walking `links` in ascending slot order, starting from `prev`, the last link of
+/// the previous batch. Derived heights are written back to `links`, and `prev` is advanced
+pub fn derive_forward(links: &mut [HeightLink], prev: &mut Option<HeightLink>) -> Vec<(i64, i64)> {
+    let mut derived = vec![];
+    for link in links.iter_mut() {
+        let parent_number = prev
+            .filter(|prev| link.parent_slot == Some(prev.slot))
+            .and_then(|parent| parent.number);
+        if let (None, Some(parent_number)) = (link.number, parent_number) {
+            link.number = Some(parent_number + 1);
+            derived.push((link.slot, parent_number + 1));
+        }
+        *prev = Some(*link);
+    }
+    derived
+}
+
+/// Derives heights walking `links`, ordered by ascending slot, in reverse, starting from `next`, the
+/// first link of the following batch. Derived heights are written back to `links`, and `next` is advanced
+pub fn derive_backward(links: &mut [HeightLink], next: &mut Option<HeightLink>) -> Vec<(i64, i64)> {
+    let mut derived = vec![];
+    for link in links.iter_mut().rev() {
+        let child_number = next
+            .filter(|next| next.parent_slot == Some(link.slot))
+            .and_then(|child| child.number);
+        if let (None, Some(child_number)) = (link.number, child_number) {
+            link.number = Some(child_number - 1);
+            derived.push((link.slot, child_number - 1));
+        }
+        *next = Some(*link);
+    }
+    derived
+}
+
+/// Derives the heights of blocks without one in `[start_slot, end_slot]`, reading `batch_size` slots at a time.
+///
+/// Heights are first counted forwards through the range, then backwards, so that blocks preceding
+/// the first block with a known height are also derived
+pub fn derive_block_heights(
+    conn: &mut PgConnection,
+    start_slot: i64,
+    end_slot: i64,
+    batch_size: i64,
+) -> Result<DerivedHeights> {
+    if end_slot < start_slot {
+        return Err(anyhow!("end_slot({end_slot}) < start_slot({start_slot})"));
+    }
+    if batch_size <= 0 {
+        return Err(anyhow!("batch_size must be positive"));
+    }
+    let client = Client {};
+    let mut stats = DerivedHeights::default();
+    let batches = {
+        let mut batches = vec![];
+        let mut batch_start = start_slot;
+        while batch_start <= end_slot {
+            let batch_end = end_slot.min(batch_start + batch_size - 1);
+            batches.push((batch_start, batch_end));
+            batch_start = batch_end + 1;
+        }
+        batches
+    };
+    let load = |conn: &mut PgConnection,
+                (batch_start, batch_end): (i64, i64)|
+     -> Result<Vec<HeightLink>> {
+        Ok(client
+            .select_blocks_by_slot_range(conn, batch_start, batch_end)?
+            .iter()
+            .map(HeightLink::from)
+            .collect())
+    };
+
+    let mut prev = None;
+    for batch in batches.iter() {
+        let mut links = load(conn, *batch)?;
+        let derived = derive_forward(&mut links, &mut prev);
+        stats.forward += client.set_derived_block_heights(conn, &derived)?;
+    }
+    let mut next = None;
+    for batch in batches.iter().rev() {
+        let mut links = load(conn, *batch)?;
+        let derived = derive_backward(&mut links, &mut next);
+        stats.backward += client.set_derived_block_heights(conn, &derived)?;
+    }
+    stats.remaining = client.count_blocks_missing_height(conn, start_slot, end_slot)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn link(slot: i64, parent_slot: i64, number: Option<i64>) -> HeightLink {
+        HeightLink {
+            slot,
+            parent_slot: Some(parent_slot),
+            number,
+        }
+    }
+    #[test]
+    fn test_derive_heights() {
+        let mut links = vec![
+            link(1, 0, None),
+            link(2, 1, None),
+            // slot 3 was skipped
+            link(4, 2, Some(10)),
+            link(5, 4, None),
+            // the parent at slot 6 is not stored, so heights can't be counted past it
+            link(7, 6, None),
+            link(8, 7, None),
+        ];
+        let mut prev = None;
+        assert_eq!(derive_forward(&mut links[..3], &mut prev), vec![]);
+        // batches continue from the last link of the previous batch
+        assert_eq!(derive_forward(&mut links[3..], &mut prev), vec![(5, 11)]);
+        assert_eq!(prev.map(|prev| prev.slot), Some(8));
+
+        let mut next = None;
+        assert_eq!(derive_backward(&mut links, &mut next), vec![(2, 9), (1, 8)]);
+        assert_eq!(
+            links.iter().map(|link| link.number).collect::<Vec<_>>(),
+            vec![Some(8), Some(9), Some(10), Some(11), None, None]
+        );
+    }
+}
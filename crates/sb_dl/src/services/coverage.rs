@@ -0,0 +1,297 @@
+//! Summarizes how complete the stored blocks are, per epoch or per day.
+//!
+//! Every slot in a bucket is either a stored block, a known skipped slot, or unknown. Skipped slots
+//! are only known between stored blocks with consecutive heights, so slots around gaps are unknown
+//! until the gap is repaired. The percentage complete is the share of slots which are known.
+
+use {
+    anyhow::{anyhow, Context, Result},
+    chrono::prelude::*,
+    db::client::{Client, GapRange, SlotCoverage},
+    diesel::PgConnection,
+    serde::Serialize,
+    solana_sdk::clock::{Epoch, Slot, DEFAULT_SLOTS_PER_EPOCH},
+    std::collections::HashSet,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum CoverageBy {
+    Epoch,
+    /// utc days, formatted as YYYY-MM-DD
+    Day,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum CoverageFormat {
+    Table,
+    Json,
+}
+
+/// A range of slots reported on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoverageBucket {
+    /// epoch number or date
+    pub label: String,
+    /// None for days without a stored block
+    pub slots: Option<(i64, i64)>,
+}
+
+impl CoverageBucket {
+    /// Returns the slots of epoch `epoch`. Mainnet epochs have a fixed length, so warmup epochs are ignored
+    pub fn epoch(epoch: Epoch) -> Self {
+        let first_slot = epoch * DEFAULT_SLOTS_PER_EPOCH;
+        Self {
+            label: epoch.to_string(),
+            slots: Some((
+                first_slot as i64,
+                (first_slot + DEFAULT_SLOTS_PER_EPOCH - 1) as i64,
+            )),
+        }
+    }
+    /// Returns the slots from the first to the last block stored with a time on `day`.
+    ///
+    /// Blocks are the only record of when a slot happened, so missing blocks before the first and
+    /// after the last stored block of the day are not counted
+    pub fn day(conn: &mut PgConnection, day: NaiveDate) -> Result<Self> {
+        let start = day.and_time(NaiveTime::MIN).and_utc();
+        let slots =
+            Client {}.slot_range_for_times(conn, start, start + chrono::Duration::days(1))?;
+        Ok(Self {
+            label: day.format("%Y-%m-%d").to_string(),
+            slots,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CoverageRow {
+    /// epoch number or date
+    pub bucket: String,
+    pub start_slot: Option<i64>,
+    pub end_slot: Option<i64>,
+    pub expected_slots: i64,
+    pub stored_blocks: i64,
+    /// stored blocks without a block height
+    pub missing_height: i64,
+    pub known_skipped: i64,
+    /// slots which are neither stored nor known to be skipped
+    pub unknown_slots: i64,
+    /// gap ranges starting in the bucket, and their missing block heights
+    pub gap_ranges: usize,
+    pub missing_blocks: i64,
+    /// blocks waiting in the failed blocks retry queue
+    pub failed_blocks: usize,
+    pub percent_complete: f64,
+}
+
+impl CoverageRow {
+    /// Combines the counts for `bucket`. Gaps are assigned to the bucket containing their first
+    /// possible slot, so gaps spanning buckets are only counted once
+    pub fn new(
+        bucket: &CoverageBucket,
+        coverage: &SlotCoverage,
+        gaps: &[GapRange],
+        failed_slots: &HashSet<Slot>,
+    ) -> Self {
+        let Some((start_slot, end_slot)) = bucket.slots else {
+            return Self {
+                bucket: bucket.label.clone(),
+                ..Default::default()
+            };
+        };
+        let contains = |slot: i64| slot >= start_slot && slot <= end_slot;
+        let bucket_gaps = gaps
+            .iter()
+            .filter(|gap| match (gap.prev_slot, gap.next_slot) {
+                (Some(prev_slot), _) => contains(prev_slot + 1),
+                (None, Some(next_slot)) => contains(next_slot - 1),
+                (None, None) => false,
+            })
+            .collect::<Vec<_>>();
+        let expected_slots = end_slot - start_slot + 1;
+        let known_slots = coverage.stored_blocks + coverage.known_skipped;
+        Self {
+            bucket: bucket.label.clone(),
+            start_slot: Some(start_slot),
+            end_slot: Some(end_slot),
+            expected_slots,
+            stored_blocks: coverage.stored_blocks,
+            missing_height: coverage.missing_height,
+            known_skipped: coverage.known_skipped,
+            unknown_slots: expected_slots - known_slots,
+            gap_ranges: bucket_gaps.len(),
+            missing_blocks: bucket_gaps.iter().map(|gap| gap.missing()).sum(),
+            failed_blocks: failed_slots
+                .iter()
+                .filter(|slot| contains(**slot as i64))
+                .count(),
+            percent_complete: known_slots as f64 * 100.0 / expected_slots as f64,
+        }
+    }
+}
+
+/// Parses the `--from` and `--to` arguments into buckets. `to` defaults to `from`
+pub fn parse_buckets(
+    conn: &mut PgConnection,
+    by: CoverageBy,
+    from: &str,
+    to: Option<&str>,
+) -> Result<Vec<CoverageBucket>> {
+    let to = to.unwrap_or(from);
+    match by {
+        CoverageBy::Epoch => {
+            let parse = |value: &str| {
+                value
+                    .parse::<Epoch>()
+                    .with_context(|| format!("invalid epoch {value}"))
+            };
+            let (from, to) = (parse(from)?, parse(to)?);
+            if to < from {
+                return Err(anyhow!("--to({to}) < --from({from})"));
+            }
+            Ok((from..=to).map(CoverageBucket::epoch).collect())
+        }
+        CoverageBy::Day => {
+            let parse = |value: &str| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .with_context(|| format!("invalid day {value}, expected YYYY-MM-DD"))
+            };
+            let (from, to) = (parse(from)?, parse(to)?);
+            if to < from {
+                return Err(anyhow!("--to({to}) < --from({from})"));
+            }
+            from.iter_days()
+                .take_while(|day| *day <= to)
+                .map(|day| CoverageBucket::day(conn, day))
+                .collect()
+        }
+    }
+}
+
+/// Reports the coverage of each bucket. `failed_slots` are the slots in the failed blocks retry queue
+pub fn coverage_report(
+    conn: &mut PgConnection,
+    buckets: &[CoverageBucket],
+    failed_slots: &HashSet<Slot>,
+) -> Result<Vec<CoverageRow>> {
+    let client = Client {};
+    let (Some(start_slot), Some(end_slot)) = (
+        buckets
+            .iter()
+            .filter_map(|bucket| bucket.slots)
+            .map(|(start, _)| start)
+            .min(),
+        buckets
+            .iter()
+            .filter_map(|bucket| bucket.slots)
+            .map(|(_, end)| end)
+            .max(),
+    ) else {
+        return Ok(buckets
+            .iter()
+            .map(|bucket| CoverageRow::new(bucket, &SlotCoverage::default(), &[], failed_slots))
+            .collect());
+    };
+    // gaps are found once for the whole report, and then assigned to buckets
+    let gaps = match client.height_range_for_slots(conn, start_slot, end_slot)? {
+        Some((start_height, end_height)) => {
+            client.find_gap_ranges(conn, start_height, end_height)?
+        }
+        None => vec![],
+    };
+    buckets
+        .iter()
+        .map(|bucket| {
+            let coverage = match bucket.slots {
+                Some((start, end)) => client.slot_coverage(conn, start, end)?,
+                None => SlotCoverage::default(),
+            };
+            Ok(CoverageRow::new(bucket, &coverage, &gaps, failed_slots))
+        })
+        .collect()
+}
+
+/// Formats `rows` as a fixed width table
+pub fn render_table(rows: &[CoverageRow]) -> String {
+    let mut table = format!(
+        "{:<12} {:>12} {:>12} {:>10} {:>10} {:>10} {:>10} {:>6} {:>10} {:>8} {:>9}\n",
+        "bucket",
+        "start_slot",
+        "end_slot",
+        "expected",
+        "stored",
+        "skipped",
+        "unknown",
+        "gaps",
+        "missing",
+        "failed",
+        "complete"
+    );
+    let slot = |slot: Option<i64>| slot.map_or_else(|| "-".to_string(), |slot| slot.to_string());
+    for row in rows {
+        table.push_str(&format!(
+            "{:<12} {:>12} {:>12} {:>10} {:>10} {:>10} {:>10} {:>6} {:>10} {:>8} {:>8.2}%\n",
+            row.bucket,
+            slot(row.start_slot),
+            slot(row.end_slot),
+            row.expected_slots,
+            row.stored_blocks,
+            row.known_skipped,
+            row.unknown_slots,
+            row.gap_ranges,
+            row.missing_blocks,
+            row.failed_blocks,
+            row.percent_complete
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_coverage_row() {
+        let bucket = CoverageBucket::epoch(1);
+        assert_eq!(bucket.slots, Some((432_000, 863_999)));
+        let gap = |prev_slot, next_slot| GapRange {
+            gap_start: 10,
+            gap_end: 11,
+            prev_slot,
+            next_slot,
+        };
+        let row = CoverageRow::new(
+            &bucket,
+            &SlotCoverage {
+                stored_blocks: 400_000,
+                missing_height: 0,
+                known_skipped: 10_000,
+            },
+            &[
+                gap(Some(432_000), Some(432_010)),
+                // gaps spanning buckets belong to the bucket containing their first slot
+                gap(Some(431_990), Some(432_010)),
+                gap(None, Some(432_010)),
+            ],
+            &HashSet::from([432_000, 1]),
+        );
+        assert_eq!(row.expected_slots, 432_000);
+        assert_eq!(row.unknown_slots, 22_000);
+        assert_eq!(row.gap_ranges, 2);
+        assert_eq!(row.missing_blocks, 4);
+        assert_eq!(row.failed_blocks, 1);
+        assert_eq!(format!("{:.2}", row.percent_complete), "94.91");
+
+        let empty = CoverageRow::new(
+            &CoverageBucket {
+                label: "2024-06-01".to_string(),
+                slots: None,
+            },
+            &SlotCoverage::default(),
+            &[],
+            &HashSet::new(),
+        );
+        assert_eq!(empty.expected_slots, 0);
+        assert_eq!(empty.percent_complete, 0.0);
+    }
+}
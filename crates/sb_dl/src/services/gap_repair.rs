@@ -0,0 +1,465 @@
+//! Continuously detects and repairs gaps in the stored blocks.
+//!
+//! Every run scans for gap ranges, and repairs them concurrently by fetching the slots between the
+//! stored blocks on either side of each gap from an upstream. Blocks which are found are sent to the
+//! persistence loop, and the outcome of each fetch is recorded in the `gap_repairs` table. Gaps
+//! which remain are retried with an exponential backoff, and gaps whose blocks were fetched but
+//! failed to persist are found again by the next scan.
+
+use {
+    super::{
+        gaps::{find_gap_ranges, ScanRange},
+        reingest::{BlockUpstream, Upstream},
+    },
+    crate::{metrics::GAP_REPAIRS, types::BlockInfo},
+    anyhow::{anyhow, Result},
+    chrono::prelude::*,
+    db::{async_client::AsyncClient, client::GapRange, models::NewGapRepair},
+    futures::stream::{self, StreamExt},
+    serde::Serialize,
+    solana_client::nonblocking::rpc_client::RpcClient,
+    std::{collections::HashMap, time::Duration},
+    tokio::sync::mpsc::Sender,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct GapRepairConfig {
+    /// how often gaps are scanned for
+    pub interval: Duration,
+    /// maximum number of gaps repaired per run
+    pub max_gaps: usize,
+    /// number of gaps repaired at once
+    pub concurrency: usize,
+    /// maximum number of slots fetched per gap and attempt, longer gaps are repaired over several runs
+    pub max_slots_per_attempt: i64,
+    /// backoff applied to a gap after its first failed attempt, doubling on each subsequent failure
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for GapRepairConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            max_gaps: 100,
+            concurrency: 4,
+            max_slots_per_attempt: 10_000,
+            base_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+/// Outcome of fetching the missing blocks of a gap.
+///
+/// Blocks are only sent to the persistence loop, so a fetched gap is not necessarily repaired.
+/// Gaps whose blocks failed to persist are found again by the next scan
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairStatus {
+    /// every missing block was fetched
+    Fetched,
+    /// some of the missing blocks were fetched
+    PartiallyFetched,
+    Failed,
+}
+
+impl RepairStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fetched => "fetched",
+            Self::PartiallyFetched => "partially_fetched",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepairOutcome {
+    pub status: RepairStatus,
+    pub blocks_found: i64,
+    pub error: Option<String>,
+    /// first slot which was not scanned, if the attempt stopped before the end of the gap
+    pub resume_slot: Option<i64>,
+}
+
+/// State of a gap which is not yet repaired
+#[derive(Clone, Copy, Debug)]
+struct GapAttempts {
+    failures: u32,
+    last_attempt: DateTime<Utc>,
+    /// the next attempt continues scanning from this slot
+    resume_slot: Option<i64>,
+}
+
+/// Failed attempts of gaps which are not yet repaired, keyed by the first missing height
+#[derive(Default)]
+pub struct GapBackoff {
+    gaps: HashMap<i64, GapAttempts>,
+}
+
+impl GapBackoff {
+    /// returns true if enough time has passed since the last failed attempt to retry the gap
+    pub fn is_due(&self, gap_start: i64, now: DateTime<Utc>, cfg: &GapRepairConfig) -> bool {
+        let Some(attempts) = self.gaps.get(&gap_start) else {
+            return true;
+        };
+        let backoff = cfg
+            .base_backoff
+            .saturating_mul(2_u32.saturating_pow(attempts.failures.saturating_sub(1)))
+            .min(cfg.max_backoff);
+        match chrono::Duration::from_std(backoff) {
+            Ok(backoff) => now >= attempts.last_attempt + backoff,
+            Err(_) => true,
+        }
+    }
+    /// returns the slot the next attempt at the gap should start scanning from
+    pub fn resume_slot(&self, gap_start: i64) -> Option<i64> {
+        self.gaps
+            .get(&gap_start)
+            .and_then(|attempts| attempts.resume_slot)
+    }
+    /// Records the outcome of an attempt. Fetching blocks shrinks the gap, so only failures back
+    /// off, and continue scanning where the failed attempt stopped
+    pub fn record(&mut self, gap_start: i64, outcome: &RepairOutcome, now: DateTime<Utc>) {
+        match outcome.status {
+            RepairStatus::Failed => {
+                let attempts = self.gaps.entry(gap_start).or_insert(GapAttempts {
+                    failures: 0,
+                    last_attempt: now,
+                    resume_slot: None,
+                });
+                attempts.failures += 1;
+                attempts.last_attempt = now;
+                attempts.resume_slot = outcome.resume_slot;
+            }
+            RepairStatus::Fetched | RepairStatus::PartiallyFetched => {
+                self.gaps.remove(&gap_start);
+            }
+        }
+    }
+    /// Forgets gaps which no longer exist
+    pub fn retain(&mut self, gaps: &[GapRange]) {
+        self.gaps
+            .retain(|gap_start, _| gaps.iter().any(|gap| gap.gap_start == *gap_start));
+    }
+}
+
+/// Fetches the slots between the stored blocks on either side of `gap`, sending found blocks to `blocks_tx`.
+///
+/// At most `max_slots` slots are scanned, starting from `resume_slot` if a previous attempt stopped
+/// part way through the gap. Gaps at the end of the stored range are bounded by `current_slot`.
+/// Upstreams return errors for skipped slots, so errors only fail the attempt when no block is found
+pub async fn repair_gap(
+    upstream: &dyn BlockUpstream,
+    gap: &GapRange,
+    resume_slot: Option<i64>,
+    current_slot: i64,
+    max_slots: i64,
+    blocks_tx: &Sender<BlockInfo>,
+) -> RepairOutcome {
+    let Some(prev_slot) = gap.prev_slot else {
+        return RepairOutcome {
+            status: RepairStatus::Failed,
+            blocks_found: 0,
+            error: Some("no stored block precedes the gap".to_string()),
+            resume_slot: None,
+        };
+    };
+    let last_slot = gap
+        .next_slot
+        .map_or(current_slot, |next_slot| next_slot - 1);
+    let first_slot = resume_slot.map_or(prev_slot + 1, |slot| slot.max(prev_slot + 1));
+    let end_slot = last_slot.min(first_slot + max_slots - 1);
+    let mut blocks_found = 0;
+    let mut last_error = None;
+    let mut scanned_to = first_slot - 1;
+    for slot in first_slot..=end_slot {
+        if blocks_found == gap.missing() {
+            break;
+        }
+        scanned_to = slot;
+        let block = match upstream.get_block(slot as u64, false).await {
+            Ok(Some(block)) => block,
+            Ok(None) => continue,
+            Err(err) => {
+                log::debug!("failed to fetch slot({slot}) {err:#?}");
+                last_error = Some(format!("{err:#}"));
+                continue;
+            }
+        };
+        let time = block
+            .block_time
+            .and_then(|block_time| DateTime::from_timestamp(block_time, 0));
+        if let Err(err) = blocks_tx
+            .send(BlockInfo {
+                block_height: block.block_height,
+                slot: slot as u64,
+                time,
+                block,
+                source: upstream.source(),
+                // blocks are always fetched with minimization enabled
+                minimized: true,
+            })
+            .await
+        {
+            return RepairOutcome {
+                error: Some(format!("failed to send block({slot}) {err:#?}")),
+                ..outcome(gap, blocks_found, None, None)
+            };
+        }
+        blocks_found += 1;
+    }
+    // once the end of the gap is reached, the next attempt starts over from the beginning
+    let resume_slot = (scanned_to < last_slot).then_some(scanned_to + 1);
+    outcome(gap, blocks_found, last_error, resume_slot)
+}
+
+fn outcome(
+    gap: &GapRange,
+    blocks_found: i64,
+    last_error: Option<String>,
+    resume_slot: Option<i64>,
+) -> RepairOutcome {
+    let status = if blocks_found >= gap.missing() {
+        RepairStatus::Fetched
+    } else if blocks_found > 0 {
+        RepairStatus::PartiallyFetched
+    } else {
+        RepairStatus::Failed
+    };
+    RepairOutcome {
+        status,
+        blocks_found,
+        error: if status == RepairStatus::Fetched {
+            None
+        } else {
+            last_error
+        },
+        resume_slot,
+    }
+}
+
+/// Scans for and repairs gaps every `cfg.interval`, until `blocks_tx` is closed.
+///
+/// `scan_range` returns the range to scan given the current slot
+pub async fn gap_repair_loop(
+    db: AsyncClient,
+    rpc: RpcClient,
+    upstream: Upstream,
+    blocks_tx: Sender<BlockInfo>,
+    scan_range: impl Fn(i64) -> Result<ScanRange>,
+    cfg: GapRepairConfig,
+) -> Result<()> {
+    if cfg.concurrency == 0 {
+        return Err(anyhow!("concurrency must be positive"));
+    }
+    let upstream_name = upstream.source().as_str();
+    let mut backoff = GapBackoff::default();
+    let mut ticker = tokio::time::interval(cfg.interval);
+    loop {
+        ticker.tick().await;
+        if blocks_tx.is_closed() {
+            return Ok(());
+        }
+        let current_slot = match rpc.get_slot().await {
+            Ok(slot) => slot as i64,
+            Err(err) => {
+                log::error!("failed to get current slot {err:#?}");
+                continue;
+            }
+        };
+        let gaps = match find_gap_ranges(&db, scan_range(current_slot)?).await {
+            Ok((_, gaps)) => gaps,
+            Err(err) => {
+                log::error!("failed to find gaps {err:#?}");
+                continue;
+            }
+        };
+        backoff.retain(&gaps);
+        let now = Utc::now();
+        let due = gaps
+            .into_iter()
+            .filter(|gap| backoff.is_due(gap.gap_start, now, &cfg))
+            .take(cfg.max_gaps)
+            .map(|gap| {
+                let resume_slot = backoff.resume_slot(gap.gap_start);
+                (gap, resume_slot)
+            })
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            continue;
+        }
+        log::info!("repairing {} gaps", due.len());
+        let (upstream, blocks_tx) = (&upstream, &blocks_tx);
+        let mut repairs = stream::iter(due)
+            .map(|(gap, resume_slot)| async move {
+                let outcome = repair_gap(
+                    upstream,
+                    &gap,
+                    resume_slot,
+                    current_slot,
+                    cfg.max_slots_per_attempt,
+                    blocks_tx,
+                )
+                .await;
+                (gap, outcome)
+            })
+            .buffer_unordered(cfg.concurrency);
+        while let Some((gap, outcome)) = repairs.next().await {
+            log::info!(
+                "gap({}..={}) {}, fetched {} of {} blocks",
+                gap.gap_start,
+                gap.gap_end,
+                outcome.status.as_str(),
+                outcome.blocks_found,
+                gap.missing()
+            );
+            backoff.record(gap.gap_start, &outcome, Utc::now());
+            GAP_REPAIRS
+                .with_label_values(&[outcome.status.as_str()])
+                .inc();
+            let (gap_start, gap_end) = (gap.gap_start, gap.gap_end);
+            if let Err(err) = db
+                .interact(move |client, conn| {
+                    client.insert_gap_repair(
+                        conn,
+                        &NewGapRepair {
+                            gap_start,
+                            gap_end,
+                            upstream: upstream_name,
+                            status: outcome.status.as_str(),
+                            blocks_found: outcome.blocks_found,
+                            error: outcome.error.as_deref(),
+                        },
+                    )
+                })
+                .await
+            {
+                log::error!("failed to record repair of gap({gap_start}..={gap_end}) {err:#?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::services::reingest::test_utils::{block, StubUpstream},
+    };
+
+    fn gap(gap_start: i64, gap_end: i64, prev_slot: i64, next_slot: i64) -> GapRange {
+        GapRange {
+            gap_start,
+            gap_end,
+            prev_slot: Some(prev_slot),
+            next_slot: Some(next_slot),
+        }
+    }
+
+    #[test]
+    fn test_gap_backoff() {
+        let cfg = GapRepairConfig {
+            base_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(150),
+            ..Default::default()
+        };
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let secs = |secs| now + chrono::Duration::seconds(secs);
+        let gap = gap(10, 12, 100, 200);
+        let failed = outcome(&gap, 0, None, Some(150));
+        let mut backoff = GapBackoff::default();
+        assert!(backoff.is_due(10, now, &cfg));
+        assert_eq!(backoff.resume_slot(10), None);
+
+        backoff.record(10, &failed, now);
+        assert!(!backoff.is_due(10, secs(59), &cfg));
+        assert!(backoff.is_due(10, secs(60), &cfg));
+        assert_eq!(backoff.resume_slot(10), Some(150));
+        // other gaps are unaffected
+        assert!(backoff.is_due(20, now, &cfg));
+
+        // the backoff doubles, up to the maximum
+        backoff.record(10, &failed, now);
+        assert!(!backoff.is_due(10, secs(119), &cfg));
+        assert!(backoff.is_due(10, secs(120), &cfg));
+        backoff.record(10, &failed, now);
+        assert!(backoff.is_due(10, secs(150), &cfg));
+
+        // progress resets the backoff
+        backoff.record(10, &outcome(&gap, 1, None, None), now);
+        assert!(backoff.is_due(10, now, &cfg));
+        assert_eq!(backoff.resume_slot(10), None);
+
+        backoff.record(20, &failed, now);
+        backoff.retain(&[]);
+        assert!(backoff.is_due(20, now, &cfg));
+    }
+    #[test]
+    fn test_outcome() {
+        let gap = gap(10, 12, 100, 110);
+        let error = Some("slot skipped".to_string());
+        assert_eq!(
+            outcome(&gap, 3, error.clone(), None).status,
+            RepairStatus::Fetched
+        );
+        assert_eq!(outcome(&gap, 3, error.clone(), None).error, None);
+        assert_eq!(
+            outcome(&gap, 1, error.clone(), None).status,
+            RepairStatus::PartiallyFetched
+        );
+        assert_eq!(outcome(&gap, 0, error.clone(), None).error, error);
+    }
+    #[tokio::test]
+    async fn test_repair_gap_resumes_after_skipped_slots() {
+        // two heights are missing between slots 100 and 200, and slots 101..=149 are skipped
+        let gap = gap(10, 11, 100, 200);
+        let upstream = StubUpstream {
+            blocks: [150, 160]
+                .into_iter()
+                .map(|slot| (slot, block(slot)))
+                .collect(),
+            failing_slot: 105,
+        };
+        let (blocks_tx, mut blocks_rx) = tokio::sync::mpsc::channel(10);
+        let mut backoff = GapBackoff::default();
+        let now = Utc::now();
+
+        // each attempt scans 20 slots, continuing from where the previous attempt stopped
+        for resume_slot in [121, 141] {
+            let outcome = repair_gap(
+                &upstream,
+                &gap,
+                backoff.resume_slot(gap.gap_start),
+                1_000,
+                20,
+                &blocks_tx,
+            )
+            .await;
+            assert_eq!(outcome.status, RepairStatus::Failed);
+            assert_eq!(outcome.resume_slot, Some(resume_slot));
+            backoff.record(gap.gap_start, &outcome, now);
+        }
+        assert!(blocks_rx.try_recv().is_err());
+
+        let outcome = repair_gap(
+            &upstream,
+            &gap,
+            backoff.resume_slot(gap.gap_start),
+            1_000,
+            20,
+            &blocks_tx,
+        )
+        .await;
+        assert_eq!(outcome.status, RepairStatus::Fetched);
+        assert_eq!(outcome.blocks_found, 2);
+        assert_eq!(blocks_rx.try_recv().unwrap().slot, 150);
+        assert_eq!(blocks_rx.try_recv().unwrap().slot, 160);
+
+        // a scan which reaches the end of the gap starts over on the next attempt
+        let outcome = repair_gap(&upstream, &gap, Some(161), 1_000, 100, &blocks_tx).await;
+        assert_eq!(outcome.status, RepairStatus::Failed);
+        assert_eq!(outcome.resume_slot, None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Resolves the range of block heights scanned for gaps.
+//!
+//! Gaps are ranges of missing block heights, so ranges given as slots or times are mapped to heights
+//! using the stored blocks nearest to them, see [`Client::height_range_for_slots`].
+
+use {
+    anyhow::{anyhow, Context, Result},
+    chrono::prelude::*,
+    db::{
+        async_client::AsyncClient,
+        client::{Client, GapRange},
+    },
+    diesel::PgConnection,
+    solana_sdk::clock::DEFAULT_SLOTS_PER_EPOCH,
+};
+
+/// default number of slots scanned when no start is given, roughly six hours of blocks
+pub const SLOTS_PER_SIX_HOURS: i64 = (((DEFAULT_SLOTS_PER_EPOCH / 2) / 24) * 6) as i64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum GapScanBy {
+    Slot,
+    Height,
+    /// rfc3339 timestamps
+    Time,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScanRange {
+    Slots {
+        start: i64,
+        end: i64,
+    },
+    /// when `end` is None, up to the highest stored block height
+    Heights {
+        start: i64,
+        end: Option<i64>,
+    },
+    Times {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    /// every stored block height
+    All,
+}
+
+impl ScanRange {
+    /// Whether [`ScanRange::parse`] needs the current slot for the given arguments
+    pub fn needs_current_slot(by: GapScanBy, to: Option<&str>, all: bool) -> bool {
+        !all && by == GapScanBy::Slot && to.is_none()
+    }
+    /// Parses the `--from`, `--to`, `--by` and `--all` arguments.
+    ///
+    /// When `--to` is missing the range ends at `current_slot`, the current time, or the highest
+    /// stored height. When `--from` is missing slot and time ranges cover the six hours before the end
+    pub fn parse(
+        by: GapScanBy,
+        from: Option<&str>,
+        to: Option<&str>,
+        all: bool,
+        current_slot: Option<i64>,
+        now: DateTime<Utc>,
+    ) -> Result<Self> {
+        if all {
+            return Ok(Self::All);
+        }
+        let parse_number = |value: &str| {
+            value
+                .parse::<i64>()
+                .with_context(|| format!("invalid {by:?} {value}"))
+        };
+        let parse_time = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .with_context(|| format!("invalid time {value}, expected rfc3339"))
+        };
+        let range = match by {
+            GapScanBy::Slot => {
+                let end = match to {
+                    Some(to) => parse_number(to)?,
+                    None => current_slot.ok_or_else(|| anyhow!("current slot is required"))?,
+                };
+                let start = match from {
+                    Some(from) => parse_number(from)?,
+                    None => end - SLOTS_PER_SIX_HOURS,
+                };
+                Self::Slots { start, end }
+            }
+            GapScanBy::Height => Self::Heights {
+                start: parse_number(
+                    from.ok_or_else(|| anyhow!("--from is required when scanning by height"))?,
+                )?,
+                end: to.map(parse_number).transpose()?,
+            },
+            GapScanBy::Time => {
+                let end = to.map(parse_time).transpose()?.unwrap_or(now);
+                let start = match from {
+                    Some(from) => parse_time(from)?,
+                    None => end - chrono::Duration::hours(6),
+                };
+                Self::Times { start, end }
+            }
+        };
+        match range {
+            Self::Slots { start, end } if end < start => Err(anyhow!("--to({end}) < --from({start})")),
+            Self::Heights {
+                start,
+                end: Some(end),
+            } if end < start => Err(anyhow!("--to({end}) < --from({start})")),
+            Self::Times { start, end } if end < start => Err(anyhow!("--to({end}) < --from({start})")),
+            range => Ok(range),
+        }
+    }
+    /// Returns the slots covered by the range, None for height and time ranges
+    pub fn slot_range(&self) -> Option<(i64, i64)> {
+        match *self {
+            Self::Slots { start, end } => Some((start, end)),
+            Self::All => Some((0, i64::MAX)),
+            Self::Heights { .. } | Self::Times { .. } => None,
+        }
+    }
+    /// Returns the block heights to scan, None if there are no stored blocks to bound the range
+    pub fn height_range(&self, conn: &mut PgConnection) -> Result<Option<(i64, i64)>> {
+        let client = Client {};
+        match *self {
+            Self::Slots { start, end } => client.height_range_for_slots(conn, start, end),
+            Self::Heights { start, end: Some(end) } => Ok(Some((start, end))),
+            Self::Heights { start, end: None } => Ok(client
+                .stored_height_range(conn)?
+                .map(|(_, highest)| (start, highest))
+                .filter(|(start, end)| start <= end)),
+            Self::Times { start, end } => client.height_range_for_times(conn, start, end),
+            Self::All => client.stored_height_range(conn),
+        }
+    }
+}
+
+/// Resolves `range` to block heights, returning the heights and the gap ranges within them
+pub async fn find_gap_ranges(
+    db: &AsyncClient,
+    range: ScanRange,
+) -> Result<(Option<(i64, i64)>, Vec<GapRange>)> {
+    let Some((start_height, end_height)) = db.interact(move |_, conn| range.height_range(conn)).await? else {
+        log::warn!("no stored blocks bound {range:?}");
+        return Ok((None, vec![]));
+    };
+    log::debug!("scanning heights({start_height}..={end_height}) for {range:?}");
+    let gaps = db
+        .interact(move |client, conn| client.find_gap_ranges(conn, start_height, end_height))
+        .await?;
+    Ok((Some((start_height, end_height)), gaps))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_scan_range() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(
+            ScanRange::parse(GapScanBy::Slot, None, None, false, Some(100_000), now).unwrap(),
+            ScanRange::Slots {
+                start: 100_000 - SLOTS_PER_SIX_HOURS,
+                end: 100_000
+            }
+        );
+        assert_eq!(
+            ScanRange::parse(GapScanBy::Height, Some("10"), None, false, None, now).unwrap(),
+            ScanRange::Heights {
+                start: 10,
+                end: None
+            }
+        );
+        assert_eq!(
+            ScanRange::parse(GapScanBy::Time, None, None, false, None, now).unwrap(),
+            ScanRange::Times {
+                start: now - chrono::Duration::hours(6),
+                end: now
+            }
+        );
+        assert_eq!(
+            ScanRange::parse(
+                GapScanBy::Time,
+                Some("2023-11-14T00:00:00Z"),
+                Some("2023-11-14T16:13:20+00:00"),
+                false,
+                None,
+                now
+            )
+            .unwrap(),
+            ScanRange::Times {
+                start: DateTime::from_timestamp(1_699_920_000, 0).unwrap(),
+                end: now
+            }
+        );
+        assert_eq!(
+            ScanRange::parse(GapScanBy::Height, None, None, true, None, now).unwrap(),
+            ScanRange::All
+        );
+        assert!(ScanRange::parse(GapScanBy::Height, None, Some("10"), false, None, now).is_err());
+        assert!(ScanRange::parse(GapScanBy::Slot, Some("10"), Some("5"), false, None, now).is_err());
+        assert!(ScanRange::parse(GapScanBy::Time, Some("yesterday"), None, false, None, now).is_err());
+    }
+}
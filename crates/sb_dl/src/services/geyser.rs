@@ -1,5 +1,10 @@
 use {
-    crate::{types::BlockInfo, utils::process_block},
+    crate::{
+        health::{self, UpstreamState},
+        metrics::BLOCKS_FETCHED,
+        types::{BlockInfo, BlockSource},
+        utils::process_block,
+    },
     anyhow::{anyhow, Context, Result},
     futures::{sink::SinkExt, stream::StreamExt},
     solana_transaction_status::UiConfirmedBlock,
@@ -68,6 +73,7 @@ pub async fn subscribe_blocks(
         }))
         .await
         .with_context(|| "failedt to subscribe")?;
+    health::set_upstream(UpstreamState::Connected);
     while let Some(message) = stream.next().await {
         match message {
             Ok(msg) => match msg.update_oneof {
@@ -85,29 +91,34 @@ pub async fn subscribe_blocks(
                 }
                 Some(UpdateOneof::Block(block)) => {
                     let slot = block.slot;
+                    BLOCKS_FETCHED.with_label_values(&["geyser"]).inc();
+                    health::record_received(slot);
                     match create_block(block) {
                         Ok(block) => match process_block(block, no_minimization) {
                             Ok(block) => {
-                                if let Some(block_height) = block.block_height {
+                                let block_height = block.block_height;
+                                if let Some(block_height) = block_height {
                                     log::info!("got_block(slot={}, height={})", slot, block_height);
-                                    let time = if let Some(block_time) = block.block_time {
-                                        DateTime::from_timestamp(block_time, 0)
-                                    } else {
-                                        None
-                                    };
-                                    if let Err(err) = blocks_tx
-                                        .send(BlockInfo {
-                                            slot,
-                                            block,
-                                            time,
-                                            block_height,
-                                        })
-                                        .await
-                                    {
-                                        log::error!("failed to notify new block {err:#?}");
-                                    }
                                 } else {
-                                    log::warn!("missing block height");
+                                    log::warn!("missing block height for block({slot}), storing by slot");
+                                }
+                                let time = if let Some(block_time) = block.block_time {
+                                    DateTime::from_timestamp(block_time, 0)
+                                } else {
+                                    None
+                                };
+                                if let Err(err) = blocks_tx
+                                    .send(BlockInfo {
+                                        slot,
+                                        block,
+                                        time,
+                                        block_height,
+                                        source: BlockSource::Geyser,
+                                        minimized: !no_minimization,
+                                    })
+                                    .await
+                                {
+                                    log::error!("failed to notify new block {err:#?}");
                                 }
                             }
                             Err(err) => {
@@ -125,8 +136,12 @@ pub async fn subscribe_blocks(
                 }
                 None => {}
             },
-            Err(err) => return Err(anyhow!("failed to receive next message {err:#?}")),
+            Err(err) => {
+                health::set_upstream(UpstreamState::Disconnected);
+                return Err(anyhow!("failed to receive next message {err:#?}"));
+            }
         }
     }
+    health::set_upstream(UpstreamState::Disconnected);
     Ok(())
 }
@@ -0,0 +1,412 @@
+//! Reconstructs the history of anchor idls from the idl instructions in stored blocks.
+//!
+//! Anchor programs accept idl management instructions, prefixed with [`IDL_IX_TAG`], which create
+//! the program's [`IdlAccount`], append compressed idl bytes to it or to a buffer account, and copy
+//! a buffer into the idl account. Replaying these instructions in slot order recovers the contents
+//! of each idl account over time, and every time an idl account holds a complete idl a version is
+//! recorded at that slot.
+
+use {
+    super::{
+        idl_indexer::{decode_idl, IdlAccount},
+        idl_sources::DecodedIdl,
+    },
+    anyhow::{anyhow, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    db::client::{BlockFilter, BlockOrder, Client, IdlChange},
+    diesel::PgConnection,
+    serde::Serialize,
+    solana_sdk::pubkey::Pubkey,
+    solana_transaction_status::{
+        option_serializer::OptionSerializer, EncodedTransaction, EncodedTransactionWithStatusMeta,
+        UiCompiledInstruction, UiConfirmedBlock, UiInstruction, UiMessage, UiParsedInstruction,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        str::FromStr,
+    },
+};
+
+/// prefix of the instruction data of anchor idl instructions
+pub const IDL_IX_TAG: [u8; 8] = 0x0a69e9a778bcf440_u64.to_le_bytes();
+
+/// Anchor's idl instructions, following [`IDL_IX_TAG`]
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub enum IdlInstruction {
+    /// accounts: from, idl, base, system program, program
+    Create { data_len: u64 },
+    /// accounts: buffer, authority
+    CreateBuffer,
+    /// appends `data` to the idl or buffer account. accounts: idl or buffer, authority
+    Write { data: Vec<u8> },
+    /// copies the buffer into the idl account. accounts: buffer, idl, authority
+    SetBuffer,
+    SetAuthority { new_authority: Pubkey },
+    /// accounts: idl or buffer, authority, sol destination
+    Close,
+    Resize { data_len: u64 },
+}
+
+/// A top level instruction of a successful transaction, with its program and accounts resolved to addresses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxInstruction {
+    pub program_id: String,
+    pub accounts: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdlVersion {
+    pub program_id: Pubkey,
+    pub slot: u64,
+    pub idl: DecodedIdl,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IdlHistoryStats {
+    pub blocks: usize,
+    /// blocks which could not be decoded
+    pub skipped_blocks: usize,
+    pub idl_instructions: usize,
+    /// complete idls found, including unchanged idls which were written again
+    pub versions_found: usize,
+    /// versions which changed the idls table
+    pub versions_stored: usize,
+}
+
+/// Contents of the idl and buffer accounts written by replayed instructions
+#[derive(Default)]
+pub struct IdlReplay {
+    /// compressed idl bytes of each account created during the replay, keyed by address
+    accounts: HashMap<String, Vec<u8>>,
+    /// last complete idl of each program
+    current: HashMap<Pubkey, DecodedIdl>,
+}
+
+impl IdlReplay {
+    /// Returns the compressed idl bytes written to `address`, None if the account was not created during the replay
+    pub fn account_data(&self, address: &str) -> Option<&[u8]> {
+        self.accounts.get(address).map(|data| data.as_slice())
+    }
+    /// Applies an idl instruction sent to `program_id`, returning true if the program's idl account changed.
+    ///
+    /// Accounts created before the replay started have unknown contents, so writes to them are ignored
+    pub fn apply(&mut self, program_id: &Pubkey, ix: IdlInstruction, accounts: &[String]) -> Result<bool> {
+        let idl_address = IdlAccount::address(program_id)?.to_string();
+        let account = |idx: usize| {
+            accounts
+                .get(idx)
+                .ok_or_else(|| anyhow!("missing account {idx} for {ix:?}"))
+        };
+        match &ix {
+            IdlInstruction::Create { .. } => {
+                let idl = account(1)?;
+                if *idl != idl_address {
+                    return Err(anyhow!("created idl({idl}) is not the idl account({idl_address})"));
+                }
+                self.accounts.insert(idl.clone(), vec![]);
+                Ok(false)
+            }
+            IdlInstruction::CreateBuffer => {
+                self.accounts.insert(account(0)?.clone(), vec![]);
+                Ok(false)
+            }
+            IdlInstruction::Write { data } => {
+                let target = account(0)?;
+                match self.accounts.get_mut(target) {
+                    Some(written) => {
+                        written.extend_from_slice(data);
+                        Ok(*target == idl_address)
+                    }
+                    None => Ok(false),
+                }
+            }
+            IdlInstruction::SetBuffer => {
+                let (buffer, idl) = (account(0)?, account(1)?);
+                if *idl != idl_address {
+                    return Ok(false);
+                }
+                match self.accounts.get(buffer).cloned() {
+                    Some(data) => {
+                        self.accounts.insert(idl.clone(), data);
+                        Ok(true)
+                    }
+                    // the idl account now holds unknown contents
+                    None => {
+                        self.accounts.remove(idl);
+                        Ok(false)
+                    }
+                }
+            }
+            IdlInstruction::Close => {
+                self.accounts.remove(account(0)?);
+                Ok(false)
+            }
+            IdlInstruction::SetAuthority { .. } | IdlInstruction::Resize { .. } => Ok(false),
+        }
+    }
+    /// Applies the idl instructions of a transaction, returning the new idls which are complete afterwards.
+    ///
+    /// When `programs` is not empty, only instructions sent to these programs are applied
+    pub fn apply_transaction(
+        &mut self,
+        slot: u64,
+        instructions: &[TxInstruction],
+        programs: &HashSet<Pubkey>,
+        stats: &mut IdlHistoryStats,
+    ) -> Vec<IdlVersion> {
+        let mut changed = vec![];
+        for ix in instructions {
+            let Some(data) = ix.data.strip_prefix(&IDL_IX_TAG) else {
+                continue;
+            };
+            let Ok(program_id) = Pubkey::from_str(&ix.program_id) else {
+                continue;
+            };
+            if !programs.is_empty() && !programs.contains(&program_id) {
+                continue;
+            }
+            let idl_ix = match IdlInstruction::try_from_slice(data) {
+                Ok(idl_ix) => idl_ix,
+                Err(err) => {
+                    log::debug!("failed to decode idl instruction(pid={program_id}, slot={slot}) {err:#?}");
+                    continue;
+                }
+            };
+            stats.idl_instructions += 1;
+            match self.apply(&program_id, idl_ix, &ix.accounts) {
+                Ok(true) if !changed.contains(&program_id) => changed.push(program_id),
+                Ok(_) => (),
+                Err(err) => log::warn!("failed to apply idl instruction(pid={program_id}, slot={slot}) {err:#?}"),
+            }
+        }
+        let mut versions = vec![];
+        for program_id in changed {
+            let Some(data) = IdlAccount::address(&program_id)
+                .ok()
+                .and_then(|address| self.accounts.get(&address.to_string()))
+            else {
+                continue;
+            };
+            // idls written directly to the idl account are incomplete until the last write
+            let idl = match decode_idl(&program_id, data) {
+                Ok(idl) => idl,
+                Err(err) => {
+                    log::debug!("incomplete idl(pid={program_id}, slot={slot}) {err:#?}");
+                    continue;
+                }
+            };
+            stats.versions_found += 1;
+            if self.current.get(&program_id) != Some(&idl) {
+                self.current.insert(program_id, idl.clone());
+                versions.push(IdlVersion {
+                    program_id,
+                    slot,
+                    idl,
+                });
+            }
+        }
+        versions
+    }
+}
+
+/// Returns the top level instructions of a successful transaction, empty for failed transactions
+pub fn tx_instructions(tx: &EncodedTransactionWithStatusMeta) -> Vec<TxInstruction> {
+    let Some(meta) = &tx.meta else {
+        return vec![];
+    };
+    if meta.err.is_some() {
+        return vec![];
+    }
+    let EncodedTransaction::Json(ui_tx) = &tx.transaction else {
+        return vec![];
+    };
+    let compiled = |keys: &[String], ix: &UiCompiledInstruction| {
+        Some(TxInstruction {
+            program_id: keys.get(ix.program_id_index as usize)?.clone(),
+            accounts: ix
+                .accounts
+                .iter()
+                .map(|idx| keys.get(*idx as usize).cloned())
+                .collect::<Option<Vec<_>>>()?,
+            data: bs58::decode(&ix.data).into_vec().ok()?,
+        })
+    };
+    match &ui_tx.message {
+        // parsed account keys include the addresses loaded from lookup tables
+        UiMessage::Parsed(msg) => {
+            let keys = msg
+                .account_keys
+                .iter()
+                .map(|key| key.pubkey.clone())
+                .collect::<Vec<_>>();
+            msg.instructions
+                .iter()
+                .filter_map(|ix| match ix {
+                    UiInstruction::Compiled(ix) => compiled(&keys, ix),
+                    UiInstruction::Parsed(UiParsedInstruction::PartiallyDecoded(ix)) => {
+                        Some(TxInstruction {
+                            program_id: ix.program_id.clone(),
+                            accounts: ix.accounts.clone(),
+                            data: bs58::decode(&ix.data).into_vec().ok()?,
+                        })
+                    }
+                    // only instructions of known programs are fully parsed
+                    UiInstruction::Parsed(UiParsedInstruction::Parsed(_)) => None,
+                })
+                .collect()
+        }
+        UiMessage::Raw(msg) => {
+            let mut keys = msg.account_keys.clone();
+            if let OptionSerializer::Some(loaded) = &meta.loaded_addresses {
+                keys.extend(loaded.writable.iter().cloned());
+                keys.extend(loaded.readonly.iter().cloned());
+            }
+            msg.instructions
+                .iter()
+                .filter_map(|ix| compiled(&keys, ix))
+                .collect()
+        }
+    }
+}
+
+/// Replays the idl instructions in stored blocks within `[start, end]`, and stores the idl versions found.
+///
+/// When `programs` is not empty, only the idls of these programs are reconstructed
+pub fn reconstruct_idl_history(
+    conn: &mut PgConnection,
+    start: i64,
+    end: i64,
+    batch_size: i64,
+    programs: &HashSet<Pubkey>,
+) -> Result<IdlHistoryStats> {
+    if end < start {
+        return Err(anyhow!("end({end}) < start({start})"));
+    }
+    let client = Client {};
+    let mut stats = IdlHistoryStats::default();
+    let mut replay = IdlReplay::default();
+    let mut versions = vec![];
+    for blocks in client.select_block_pages(
+        conn,
+        BlockFilter::SlotRange {
+            start,
+            end,
+            order: BlockOrder::Ascending,
+            limit: None,
+        },
+        batch_size,
+    )? {
+        for block in blocks? {
+            let slot = block.slot;
+            let ui_block: UiConfirmedBlock = match serde_json::from_value(block.data) {
+                Ok(ui_block) => ui_block,
+                Err(err) => {
+                    log::warn!("skipping block({slot}) {err:#?}");
+                    stats.skipped_blocks += 1;
+                    continue;
+                }
+            };
+            stats.blocks += 1;
+            for tx in ui_block.transactions.iter().flatten() {
+                versions.extend(replay.apply_transaction(
+                    slot as u64,
+                    &tx_instructions(tx),
+                    programs,
+                    &mut stats,
+                ));
+            }
+        }
+    }
+    for version in versions {
+        let program_id = version.program_id.to_string();
+        match client.insert_or_update_idl(
+            conn,
+            program_id.clone(),
+            version.slot as i64,
+            version.idl.kind.as_str(),
+            version.idl.idl,
+        )? {
+            IdlChange::Unchanged => (),
+            change => {
+                log::info!("stored idl(pid={program_id}, slot={}) {change:?}", version.slot);
+                stats.versions_stored += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_idl_replay() {
+        let program_id = Pubkey::new_unique();
+        let idl_address = IdlAccount::address(&program_id).unwrap().to_string();
+        let buffer = Pubkey::new_unique().to_string();
+        let authority = Pubkey::new_unique().to_string();
+        let ix = |idl_ix: IdlInstruction, accounts: &[&String]| {
+            let mut data = IDL_IX_TAG.to_vec();
+            data.extend(idl_ix.try_to_vec().unwrap());
+            TxInstruction {
+                program_id: program_id.to_string(),
+                accounts: accounts.iter().map(|account| account.to_string()).collect(),
+                data,
+            }
+        };
+        let mut replay = IdlReplay::default();
+        let mut stats = IdlHistoryStats::default();
+        let programs = HashSet::new();
+
+        // writes to accounts created before the replay are ignored
+        replay.apply_transaction(
+            1,
+            &[ix(IdlInstruction::Write { data: vec![9] }, &[&idl_address, &authority])],
+            &programs,
+            &mut stats,
+        );
+        assert_eq!(replay.account_data(&idl_address), None);
+
+        // idl init writes directly to the idl account
+        let versions = replay.apply_transaction(
+            2,
+            &[
+                ix(IdlInstruction::Create { data_len: 3 }, &[&authority, &idl_address]),
+                ix(IdlInstruction::Write { data: vec![1, 2] }, &[&idl_address, &authority]),
+                ix(IdlInstruction::Write { data: vec![3] }, &[&idl_address, &authority]),
+            ],
+            &programs,
+            &mut stats,
+        );
+        // the bytes are not a compressed idl
+        assert!(versions.is_empty());
+        assert_eq!(replay.account_data(&idl_address), Some([1, 2, 3].as_slice()));
+
+        // idl upgrades write to a buffer, which is then copied into the idl account
+        replay.apply_transaction(
+            3,
+            &[
+                ix(IdlInstruction::CreateBuffer, &[&buffer, &authority]),
+                ix(IdlInstruction::Write { data: vec![4, 5] }, &[&buffer, &authority]),
+                ix(IdlInstruction::SetBuffer, &[&buffer, &idl_address, &authority]),
+                ix(IdlInstruction::Close, &[&buffer, &authority, &authority]),
+            ],
+            &programs,
+            &mut stats,
+        );
+        assert_eq!(replay.account_data(&idl_address), Some([4, 5].as_slice()));
+        assert_eq!(replay.account_data(&buffer), None);
+        assert_eq!(stats.idl_instructions, 8);
+
+        // instructions for other programs are skipped
+        replay.apply_transaction(
+            4,
+            &[ix(IdlInstruction::Write { data: vec![6] }, &[&idl_address, &authority])],
+            &HashSet::from([Pubkey::new_unique()]),
+            &mut stats,
+        );
+        assert_eq!(replay.account_data(&idl_address), Some([4, 5].as_slice()));
+        assert_eq!(stats.idl_instructions, 8);
+    }
+}
@@ -0,0 +1,216 @@
+//! Bulk import of offchain idls from a directory tree.
+//!
+//! Idl files are named `<program_id>_<anything>.json`. Every file is validated and normalized
+//! before anything is written, and all idls are then inserted in a single transaction, so a failed
+//! import leaves the idls table untouched.
+
+use {
+    super::idl_sources::{normalize_idl, DecodedIdl},
+    anyhow::{anyhow, Context, Result},
+    db::client::{Client, IdlChange},
+    diesel::{Connection, PgConnection},
+    serde::Serialize,
+    solana_sdk::pubkey::Pubkey,
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdlFileStatus {
+    /// the program had no idl version at the import slot
+    Inserted,
+    /// the idl replaced the version active at the import slot
+    Updated,
+    Unchanged,
+    /// the file could not be imported, see the error
+    Invalid,
+}
+
+impl IdlFileStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inserted => "inserted",
+            Self::Updated => "updated",
+            Self::Unchanged => "unchanged",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+impl From<&IdlChange> for IdlFileStatus {
+    fn from(change: &IdlChange) -> Self {
+        match change {
+            IdlChange::Inserted => Self::Inserted,
+            IdlChange::Updated { .. } => Self::Updated,
+            IdlChange::Unchanged => Self::Unchanged,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IdlFileResult {
+    pub path: String,
+    pub program_id: Option<String>,
+    /// dialect of the idl, see [`super::idl_sources::IdlKind`]
+    pub kind: Option<&'static str>,
+    pub status: IdlFileStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IdlImportReport {
+    /// when true nothing was written, and statuses are the changes the import would have made
+    pub dry_run: bool,
+    pub slot: u64,
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub invalid: usize,
+    pub files: Vec<IdlFileResult>,
+}
+
+/// Returns every `.json` file beneath `dir`, sorted by path
+pub fn find_idl_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("failed to read dir {dir:?}"))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "json") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Parses the program id from a file named `<program_id>_<anything>.json`
+pub fn parse_program_id(path: &Path) -> Result<Pubkey> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid file name"))?;
+    let (program_id, _) = file_name
+        .split_once('_')
+        .ok_or_else(|| anyhow!("file name must be <program_id>_<anything>.json"))?;
+    Pubkey::from_str(program_id).with_context(|| format!("invalid program id {program_id}"))
+}
+
+/// Reads, validates and normalizes the idl in `path`
+pub fn load_idl_file(path: &Path) -> Result<(Pubkey, DecodedIdl)> {
+    let program_id = parse_program_id(path)?;
+    let idl: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)
+        .with_context(|| "failed to deserialize json idl")?;
+    Ok((program_id, normalize_idl(&program_id, None, idl)?))
+}
+
+/// Imports the idl files beneath `dir` as versions beginning at `slot`, in a single transaction.
+///
+/// Files which fail validation, or name a program which an earlier file already named, are reported
+/// as invalid and skipped. With `dry_run` the transaction is rolled back once every idl is inserted
+pub fn import_idl_dir(
+    conn: &mut PgConnection,
+    dir: &Path,
+    slot: u64,
+    dry_run: bool,
+) -> Result<IdlImportReport> {
+    let mut files = vec![];
+    let mut idls = vec![];
+    let mut seen = HashMap::new();
+    for path in find_idl_files(dir)? {
+        let mut result = IdlFileResult {
+            path: path.display().to_string(),
+            program_id: None,
+            kind: None,
+            status: IdlFileStatus::Invalid,
+            error: None,
+        };
+        match load_idl_file(&path) {
+            Ok((program_id, idl)) => {
+                result.program_id = Some(program_id.to_string());
+                result.kind = Some(idl.kind.as_str());
+                if let Some(other) = seen.get(&program_id) {
+                    result.error = Some(format!(
+                        "duplicate program id, already imported from {other}"
+                    ));
+                } else {
+                    seen.insert(program_id, result.path.clone());
+                    idls.push((files.len(), program_id, idl));
+                }
+            }
+            Err(err) => result.error = Some(format!("{err:#}")),
+        }
+        files.push(result);
+    }
+
+    let client = Client {};
+    let mut rolled_back = false;
+    let res = conn.transaction::<_, anyhow::Error, _>(|conn| {
+        for (idx, program_id, idl) in idls {
+            let change = client
+                .insert_or_update_idl(
+                    conn,
+                    program_id.to_string(),
+                    slot as i64,
+                    idl.kind.as_str(),
+                    idl.idl,
+                )
+                .with_context(|| format!("failed to import {}", files[idx].path))?;
+            files[idx].status = IdlFileStatus::from(&change);
+        }
+        if dry_run {
+            rolled_back = true;
+            return Err(anyhow!("dry run"));
+        }
+        Ok(())
+    });
+    match res {
+        Err(_) if rolled_back => (),
+        res => res?,
+    }
+
+    let count = |status| files.iter().filter(|file| file.status == status).count();
+    Ok(IdlImportReport {
+        dry_run,
+        slot,
+        inserted: count(IdlFileStatus::Inserted),
+        updated: count(IdlFileStatus::Updated),
+        unchanged: count(IdlFileStatus::Unchanged),
+        invalid: count(IdlFileStatus::Invalid),
+        files,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_idl_files() {
+        let idls = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../idls");
+        let files = find_idl_files(&idls).unwrap();
+        assert_eq!(files.len(), 3);
+        for file in files {
+            let (program_id, idl) = load_idl_file(&file).unwrap();
+            assert!(file.to_str().unwrap().contains(&program_id.to_string()));
+            assert_eq!(idl.kind.as_str(), "anchor");
+            assert_eq!(
+                idl.idl["address"],
+                serde_json::json!(program_id.to_string())
+            );
+        }
+        assert!(parse_program_id(Path::new("idls/not_a_program.json")).is_err());
+        assert!(parse_program_id(Path::new(
+            "idls/worm2ZoG2kUd4vFXhvjh93UUH596ayRfgQ2MgjNMTth.json"
+        ))
+        .is_err());
+    }
+}
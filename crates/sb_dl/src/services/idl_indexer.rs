@@ -1,11 +1,11 @@
 use {
+    super::idl_sources::{default_idl_sources, normalize_idl, DecodedIdl, IdlKind, IdlSource},
     anyhow::Context,
     flate2::read::ZlibDecoder,
-    serde_json::json,
     solana_account_decoder::UiAccountEncoding,
     solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcAccountInfoConfig},
     solana_sdk::pubkey::Pubkey,
-    std::{io::Read, time::Duration},
+    std::{collections::HashMap, io::Read, time::Duration},
 };
 
 #[derive(borsh::BorshDeserialize, borsh::BorshSerialize)]
@@ -19,33 +19,53 @@ pub struct IdlAccount {
 
 pub struct ProgramIdl {
     pub program_id: Pubkey,
+    pub kind: IdlKind,
     pub idl: serde_json::Value,
+    /// slot the idl account was read at
+    pub slot: u64,
 }
 
 pub struct IdlIndexer {
     rpc: RpcClient,
+    /// checked in order, the idl of the first source holding one is used
+    sources: Vec<Box<dyn IdlSource>>,
 }
 
 impl IdlIndexer {
     pub async fn new(endpoint: &str) -> anyhow::Result<Self> {
+        Self::with_sources(endpoint, default_idl_sources()).await
+    }
+    pub async fn with_sources(
+        endpoint: &str,
+        sources: Vec<Box<dyn IdlSource>>,
+    ) -> anyhow::Result<Self> {
         let rpc = RpcClient::new_with_timeout(endpoint.to_string(), Duration::from_secs(600));
-        Ok(Self { rpc })
+        Ok(Self { rpc, sources })
     }
-    /// returns all possible accounts used to store idl's for deployed programs
+    /// returns the idls of `programs` found in any of the idl sources
     pub async fn get_idl_accounts(&self, programs: &[Pubkey]) -> anyhow::Result<Vec<ProgramIdl>> {
-        let program_idls = programs
-            .into_iter()
-            .filter_map(|program| Some((program, IdlAccount::address(&program).ok()?)))
+        // (program, source index, idl account)
+        let idl_accounts = programs
+            .iter()
+            .flat_map(|program| {
+                self.sources
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(idx, source)| {
+                        Some((*program, idx, source.address(program).ok()?))
+                    })
+            })
             .collect::<Vec<_>>();
 
-        let mut idls = Vec::with_capacity(program_idls.len());
-        for program_idl_chunk in program_idls.chunks(100) {
-            let idl_accounts = self
+        let mut found: HashMap<Pubkey, (usize, ProgramIdl)> =
+            HashMap::with_capacity(programs.len());
+        for idl_account_chunk in idl_accounts.chunks(100) {
+            let response = self
                 .rpc
                 .get_multiple_accounts_with_config(
-                    &program_idl_chunk
+                    &idl_account_chunk
                         .iter()
-                        .map(|(_, idl)| *idl)
+                        .map(|(_, _, idl)| *idl)
                         .collect::<Vec<_>>(),
                     RpcAccountInfoConfig {
                         encoding: Some(UiAccountEncoding::Base64Zstd),
@@ -53,92 +73,64 @@ impl IdlIndexer {
                     },
                 )
                 .await
-                .with_context(|| "failed to fetch multiple accounts")?
-                .value
-                .into_iter()
-                .enumerate()
-                .filter_map(|(idx, acct)| Some((idx, acct?)))
-                .collect::<Vec<_>>();
-
-            for (idx, account) in idl_accounts {
-                if account.data.is_empty() {
+                .with_context(|| "failed to fetch multiple accounts")?;
+            let slot = response.context.slot;
+            for ((program_id, source_idx, idl), account) in
+                idl_account_chunk.iter().zip(response.value)
+            {
+                let Some(account) = account else {
+                    continue;
+                };
+                // an idl was already found in a preferred source
+                if found
+                    .get(program_id)
+                    .is_some_and(|(idx, _)| idx < source_idx)
+                {
                     continue;
                 }
-                match borsh::BorshDeserialize::deserialize(&mut &account.data[8..]) {
-                    Ok(idl_account) => {
-                        let idl_account: IdlAccount = idl_account;
-                        if idl_account.data_len == 0 {
-                            continue;
-                        }
-                        let compressed_len: usize = idl_account.data_len.try_into().unwrap();
-                        let compressed_bytes = &account.data[44..44 + compressed_len];
-                        let mut z = ZlibDecoder::new(compressed_bytes);
-                        let mut s = Vec::new();
-                        if let Err(err) = z.read_to_end(&mut s) {
-                            log::error!(
-                                "deflate stream read failed for pid({}) idl({}) {err:#?}",
-                                program_idl_chunk[idx].0,
-                                program_idl_chunk[idx].1
-                            );
-                            continue;
-                        }
-                        match serde_json::from_slice::<serde_json::Value>(&s[..]) {
-                            Ok(mut idl_json) => {
-                                // handle idl conversion
-                                if let Some(obj) = idl_json.as_object_mut() {
-                                    // Insert or get the `metadata` object
-                                    let metadata =
-                                        obj.entry("metadata").or_insert_with(|| json!({}));
-
-                                    // Ensure `metadata` is an object
-                                    if let Some(metadata_obj) = metadata.as_object_mut() {
-                                        if !metadata_obj.contains_key("address") {
-                                            // Insert the `address` field into `metadata`
-                                            metadata_obj.insert(
-                                                "address".to_string(),
-                                                json!(program_idl_chunk[idx].0.to_string()),
-                                            );
-                                        }
-                                    }
-                                }
-                                if let Ok(idl_json) = serde_json::to_vec(&idl_json) {
-                                    match anchor_lang_idl::convert::convert_idl(&idl_json) {
-                                        Ok(idl) => match serde_json::to_value(&idl) {
-                                            Ok(idl_json) => {
-                                                idls.push(ProgramIdl {
-                                                    program_id: *program_idl_chunk[idx].0,
-                                                    idl: idl_json,
-                                                });
-                                            }
-                                            Err(err) => {
-                                                log::error!("failed to convert idl {err:#?}");
-                                            }
-                                        },
-                                        Err(err) => {
-                                            log::error!("failed to convert idl {err:#?}");
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                log::error!(
-                                    "failed to deserialize json idl(pid={},idl={}) {err:#?}",
-                                    program_idl_chunk[idx].0,
-                                    program_idl_chunk[idx].1
-                                );
-                            }
-                        }
+                let source = &self.sources[*source_idx];
+                match source.decode(program_id, &account.data) {
+                    Ok(Some(decoded)) => {
+                        found.insert(
+                            *program_id,
+                            (
+                                *source_idx,
+                                ProgramIdl {
+                                    program_id: *program_id,
+                                    kind: decoded.kind,
+                                    idl: decoded.idl,
+                                    slot,
+                                },
+                            ),
+                        );
                     }
-                    Err(err) => {
-                        log::error!("failed to deserialize idl account {err:#?}")
-                    }
-                };
+                    Ok(None) => (),
+                    Err(err) => log::error!(
+                        "failed to decode idl(pid={program_id},idl={idl},source={}) {err:#?}",
+                        source.name()
+                    ),
+                }
             }
         }
-        Ok(idls)
+        Ok(programs
+            .iter()
+            .filter_map(|program| found.remove(program))
+            .map(|(_, idl)| idl)
+            .collect())
     }
 }
 
+/// Decompresses the idl bytes stored in an idl account, and normalizes the idl with [`normalize_idl`]
+pub fn decode_idl(program_id: &Pubkey, compressed_bytes: &[u8]) -> anyhow::Result<DecodedIdl> {
+    let mut z = ZlibDecoder::new(compressed_bytes);
+    let mut s = Vec::new();
+    z.read_to_end(&mut s)
+        .with_context(|| "deflate stream read failed")?;
+    let idl_json = serde_json::from_slice::<serde_json::Value>(&s[..])
+        .with_context(|| "failed to deserialize json idl")?;
+    normalize_idl(program_id, None, idl_json)
+}
+
 impl IdlAccount {
     pub fn address(program_id: &Pubkey) -> anyhow::Result<Pubkey> {
         let program_signer = Pubkey::find_program_address(&[], program_id).0;
@@ -0,0 +1,333 @@
+//! Accounts which programs publish idls in, and the idl dialects they contain.
+//!
+//! Each [`IdlSource`] derives the account holding a program's idl and decodes the account data.
+//! Sources only describe where the idl bytes live, so any source may hold an idl of any dialect.
+//! Decoded idls are normalized with [`normalize_idl`], which detects the [`IdlKind`] from the idl
+//! contents and stores it alongside the idl, allowing downstream decoding to pick the right parser.
+
+use {
+    super::idl_indexer::{decode_idl, IdlAccount},
+    anyhow::{anyhow, Context, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    flate2::read::{GzDecoder, ZlibDecoder},
+    serde_json::{json, Value},
+    solana_sdk::{pubkey, pubkey::Pubkey},
+    std::io::Read,
+};
+
+/// the program-metadata program, which stores metadata such as idls in accounts derived from the program id
+pub const PROGRAM_METADATA_ID: Pubkey = pubkey!("ProgM6JCCvbYkfKqJYHePx4xxSUSqJp7rh8Lyv7nk7S");
+
+/// program-metadata seed of idl accounts
+pub const PROGRAM_METADATA_IDL_SEED: &str = "idl";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum IdlKind {
+    /// anchor idls, converted to the latest anchor idl format
+    Anchor,
+    /// codama root nodes
+    Codama,
+    /// idls generated by shank, which resemble legacy anchor idls
+    Shank,
+}
+
+impl IdlKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Anchor => "anchor",
+            Self::Codama => "codama",
+            Self::Shank => "shank",
+        }
+    }
+    /// Detects the dialect of `idl`. Idls which are neither codama nor shank are assumed to be anchor idls
+    pub fn detect(idl: &Value) -> Self {
+        if idl.get("standard").and_then(Value::as_str) == Some("codama")
+            || idl.get("kind").and_then(Value::as_str) == Some("rootNode")
+        {
+            Self::Codama
+        } else if idl.pointer("/metadata/origin").and_then(Value::as_str) == Some("shank") {
+            Self::Shank
+        } else {
+            Self::Anchor
+        }
+    }
+}
+
+/// An idl in its stored format
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedIdl {
+    pub kind: IdlKind,
+    pub idl: Value,
+}
+
+/// Normalizes `idl` into its stored format, detecting its dialect when `kind` is None.
+///
+/// * anchor idls are converted to the latest anchor idl format, with `metadata.address` defaulting to `program_id`
+/// * shank idls are stored as is, with `metadata.address` defaulting to `program_id`
+/// * codama idls are stored as is, with `program.publicKey` defaulting to `program_id`
+pub fn normalize_idl(
+    program_id: &Pubkey,
+    kind: Option<IdlKind>,
+    mut idl: Value,
+) -> Result<DecodedIdl> {
+    let kind = kind.unwrap_or_else(|| IdlKind::detect(&idl));
+    let obj = idl
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("idl is not a json object"))?;
+    let (parent, field) = match kind {
+        IdlKind::Anchor | IdlKind::Shank => ("metadata", "address"),
+        IdlKind::Codama => ("program", "publicKey"),
+    };
+    if let Some(parent) = obj
+        .entry(parent)
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+    {
+        parent
+            .entry(field)
+            .or_insert_with(|| json!(program_id.to_string()));
+    }
+    let idl = match kind {
+        IdlKind::Anchor => {
+            let idl = anchor_lang_idl::convert::convert_idl(&serde_json::to_vec(&idl)?)
+                .map_err(|err| anyhow!("failed to convert idl {err:#?}"))?;
+            serde_json::to_value(&idl).with_context(|| "failed to serialize idl")?
+        }
+        IdlKind::Codama | IdlKind::Shank => idl,
+    };
+    Ok(DecodedIdl { kind, idl })
+}
+
+/// An account format which programs publish idls in
+pub trait IdlSource: Send + Sync {
+    /// name of the source used for logging
+    fn name(&self) -> &'static str;
+    /// Returns the account which holds the idl of `program_id`
+    fn address(&self, program_id: &Pubkey) -> Result<Pubkey>;
+    /// Decodes the idl stored in `data`, the data of [`IdlSource::address`]. Returns None when the account holds no idl
+    fn decode(&self, program_id: &Pubkey, data: &[u8]) -> Result<Option<DecodedIdl>>;
+}
+
+/// Idls written by `anchor idl init` and `anchor idl upgrade` to the program's [`IdlAccount`]
+pub struct AnchorIdlSource;
+
+impl IdlSource for AnchorIdlSource {
+    fn name(&self) -> &'static str {
+        "anchor"
+    }
+    fn address(&self, program_id: &Pubkey) -> Result<Pubkey> {
+        IdlAccount::address(program_id)
+    }
+    fn decode(&self, program_id: &Pubkey, data: &[u8]) -> Result<Option<DecodedIdl>> {
+        // 8 byte discriminator, followed by the idl account and the compressed idl
+        let Some(mut account) = data.get(8..) else {
+            return Ok(None);
+        };
+        let idl_account: IdlAccount = BorshDeserialize::deserialize(&mut account)
+            .with_context(|| "failed to deserialize idl account")?;
+        if idl_account.data_len == 0 {
+            return Ok(None);
+        }
+        let compressed_bytes = account
+            .get(..idl_account.data_len as usize)
+            .ok_or_else(|| anyhow!("idl data_len({}) exceeds the account", idl_account.data_len))?;
+        decode_idl(program_id, compressed_bytes).map(Some)
+    }
+}
+
+/// Header of program-metadata accounts, followed by `data_length` bytes of data
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub struct MetadataHeader {
+    /// 2 for metadata accounts
+    pub discriminator: u8,
+    pub program: Pubkey,
+    /// zeroed for canonical accounts, which are owned by the program's upgrade authority
+    pub authority: Pubkey,
+    pub mutable: u8,
+    pub canonical: u8,
+    /// zero padded seed, ie `idl`
+    pub seed: [u8; 16],
+    /// 0 none, 1 utf8, 2 base58, 3 base64
+    pub encoding: u8,
+    /// 0 none, 1 gzip, 2 zlib
+    pub compression: u8,
+    /// 0 none, 1 json, 2 yaml, 3 toml
+    pub format: u8,
+    /// 0 direct, 1 url, 2 external account
+    pub data_source: u8,
+    pub data_length: u32,
+    pub padding: [u8; 5],
+}
+
+impl MetadataHeader {
+    pub const LEN: usize = 96;
+    pub const DISCRIMINATOR: u8 = 2;
+}
+
+/// Idls uploaded with the program-metadata program to the canonical `idl` metadata account of the program
+pub struct ProgramMetadataIdlSource;
+
+impl ProgramMetadataIdlSource {
+    pub fn seed() -> [u8; 16] {
+        let mut seed = [0_u8; 16];
+        seed[..PROGRAM_METADATA_IDL_SEED.len()]
+            .copy_from_slice(PROGRAM_METADATA_IDL_SEED.as_bytes());
+        seed
+    }
+    /// Reverses the encoding and compression of the account data, returning the formatted idl bytes
+    fn unpack(header: &MetadataHeader, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match header.encoding {
+            0 | 1 => data.to_vec(),
+            2 => bs58::decode(std::str::from_utf8(data)?.trim_end_matches('\0'))
+                .into_vec()
+                .with_context(|| "invalid base58 data")?,
+            3 => {
+                use base64::Engine;
+                base64::engine::general_purpose::STANDARD
+                    .decode(std::str::from_utf8(data)?.trim_end_matches('\0'))
+                    .with_context(|| "invalid base64 data")?
+            }
+            encoding => return Err(anyhow!("unsupported encoding({encoding})")),
+        };
+        let mut formatted = Vec::new();
+        match header.compression {
+            0 => formatted = compressed,
+            1 => {
+                GzDecoder::new(&compressed[..])
+                    .read_to_end(&mut formatted)
+                    .with_context(|| "gzip stream read failed")?;
+            }
+            2 => {
+                ZlibDecoder::new(&compressed[..])
+                    .read_to_end(&mut formatted)
+                    .with_context(|| "deflate stream read failed")?;
+            }
+            compression => return Err(anyhow!("unsupported compression({compression})")),
+        }
+        Ok(formatted)
+    }
+}
+
+impl IdlSource for ProgramMetadataIdlSource {
+    fn name(&self) -> &'static str {
+        "program-metadata"
+    }
+    fn address(&self, program_id: &Pubkey) -> Result<Pubkey> {
+        Ok(Pubkey::find_program_address(
+            &[program_id.as_ref(), &Self::seed()],
+            &PROGRAM_METADATA_ID,
+        )
+        .0)
+    }
+    fn decode(&self, program_id: &Pubkey, data: &[u8]) -> Result<Option<DecodedIdl>> {
+        if data.len() < MetadataHeader::LEN {
+            return Ok(None);
+        }
+        let header = MetadataHeader::deserialize(&mut &data[..MetadataHeader::LEN])
+            .with_context(|| "failed to deserialize metadata header")?;
+        if header.discriminator != MetadataHeader::DISCRIMINATOR {
+            return Err(anyhow!(
+                "not a metadata account, discriminator({})",
+                header.discriminator
+            ));
+        }
+        if header.program != *program_id {
+            return Err(anyhow!("metadata belongs to program({})", header.program));
+        }
+        if header.data_source != 0 {
+            return Err(anyhow!(
+                "unsupported data source({}), only data stored in the metadata account is supported",
+                header.data_source
+            ));
+        }
+        if header.data_length == 0 {
+            return Ok(None);
+        }
+        let data = data
+            .get(MetadataHeader::LEN..MetadataHeader::LEN + header.data_length as usize)
+            .ok_or_else(|| anyhow!("data_length({}) exceeds the account", header.data_length))?;
+        let formatted = Self::unpack(&header, data)?;
+        let idl: Value = match header.format {
+            0 | 1 => serde_json::from_slice(&formatted)
+                .with_context(|| "failed to deserialize json idl")?,
+            2 => serde_yaml::from_slice(&formatted)
+                .with_context(|| "failed to deserialize yaml idl")?,
+            format => return Err(anyhow!("unsupported format({format})")),
+        };
+        normalize_idl(program_id, None, idl).map(Some)
+    }
+}
+
+/// Returns the idl sources checked by default, in order of preference
+pub fn default_idl_sources() -> Vec<Box<dyn IdlSource>> {
+    vec![
+        Box::new(ProgramMetadataIdlSource),
+        Box::new(AnchorIdlSource),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, flate2::write::ZlibEncoder, std::io::Write};
+    #[test]
+    fn test_idl_kind() {
+        let program_id = Pubkey::new_unique();
+        let codama = json!({"kind": "rootNode", "standard": "codama", "program": {"kind": "programNode", "name": "test"}});
+        let decoded = normalize_idl(&program_id, None, codama).unwrap();
+        assert_eq!(decoded.kind, IdlKind::Codama);
+        assert_eq!(
+            decoded.idl["program"]["publicKey"],
+            json!(program_id.to_string())
+        );
+
+        let shank = json!({"version": "0.1.0", "name": "test", "instructions": [], "metadata": {"origin": "shank", "address": "11111111111111111111111111111111"}});
+        let decoded = normalize_idl(&program_id, None, shank.clone()).unwrap();
+        assert_eq!(decoded.kind, IdlKind::Shank);
+        // existing addresses are kept, and shank idls are not converted
+        assert_eq!(decoded.idl, shank);
+
+        assert_eq!(
+            IdlKind::detect(&json!({"version": "0.1.0", "name": "test"})),
+            IdlKind::Anchor
+        );
+        assert!(normalize_idl(&program_id, None, json!([])).is_err());
+    }
+    #[test]
+    fn test_program_metadata_source() {
+        let program_id = Pubkey::new_unique();
+        let idl = json!({"kind": "rootNode", "standard": "codama", "program": {"kind": "programNode", "name": "test"}});
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(&serde_json::to_vec(&idl).unwrap())
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        let header = MetadataHeader {
+            discriminator: MetadataHeader::DISCRIMINATOR,
+            program: program_id,
+            authority: Pubkey::default(),
+            mutable: 1,
+            canonical: 1,
+            seed: ProgramMetadataIdlSource::seed(),
+            encoding: 0,
+            compression: 2,
+            format: 1,
+            data_source: 0,
+            data_length: compressed.len() as u32,
+            padding: [0; 5],
+        };
+        let mut data = header.try_to_vec().unwrap();
+        assert_eq!(data.len(), MetadataHeader::LEN);
+        data.extend(&compressed);
+        // accounts are allocated larger than their data
+        data.extend([0; 10]);
+
+        let source = ProgramMetadataIdlSource;
+        let decoded = source.decode(&program_id, &data).unwrap().unwrap();
+        assert_eq!(decoded.kind, IdlKind::Codama);
+        assert_eq!(decoded.idl["program"]["name"], json!("test"));
+        // metadata of other programs is rejected
+        assert!(source.decode(&Pubkey::new_unique(), &data).is_err());
+        // empty accounts hold no idl
+        assert_eq!(source.decode(&program_id, &[]).unwrap(), None);
+    }
+}
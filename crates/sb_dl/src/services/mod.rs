@@ -1,8 +1,22 @@
+pub mod archive;
 pub mod backfill;
 pub mod bigtable;
+pub mod block_heights;
+pub mod coverage;
+pub mod gap_repair;
+pub mod gaps;
 pub mod geyser;
+pub mod idl_history;
+pub mod idl_import;
 pub mod idl_indexer;
+pub mod idl_sources;
+pub mod parquet_export;
 pub mod program_indexer;
+pub mod reingest;
+pub mod replication;
+pub mod retry_queue;
 pub mod transfer_flow_api;
 pub mod transfer_parser;
+pub mod verify_blocks;
+pub mod verify_chain;
 pub mod squads_indexer;
\ No newline at end of file
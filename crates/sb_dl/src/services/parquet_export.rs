@@ -0,0 +1,446 @@
+//! Columnar export of stored blocks for analytics.
+//!
+//! Three tables are written, each using hive style day partitioning so that DuckDB and Spark
+//! can query the output directory directly:
+//!
+//! `{output}/blocks/date=YYYY-MM-DD/part-{start}-{end}.parquet`
+//! `{output}/transactions/date=YYYY-MM-DD/part-{start}-{end}.parquet`
+//! `{output}/transfers/date=YYYY-MM-DD/part-{start}-{end}.parquet`
+//!
+//! Blocks without a block time are written to the `date=__HIVE_DEFAULT_PARTITION__` partition.
+//! The schemas below are part of the export contract, columns must only ever be appended.
+
+use {
+    crate::transfer_flow::create_ordered_transfer_for_block,
+    anyhow::{anyhow, Context, Result},
+    arrow::{
+        array::{
+            ArrayRef, BooleanBuilder, Int32Builder, Int64Builder, StringBuilder,
+            TimestampMicrosecondBuilder,
+        },
+        datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+        record_batch::RecordBatch,
+    },
+    db::{
+        client::{BlockFilter, BlockOrder, Client},
+        models::Blocks,
+    },
+    diesel::PgConnection,
+    parquet::{
+        arrow::ArrowWriter,
+        basic::{Compression, ZstdLevel},
+        file::properties::WriterProperties,
+    },
+    solana_transaction_status::{
+        option_serializer::OptionSerializer, EncodedTransaction, UiConfirmedBlock,
+    },
+    std::{
+        collections::BTreeMap,
+        fs::File,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+};
+
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum RangeKind {
+    /// the range refers to slot numbers
+    Slot,
+    /// the range refers to block heights
+    Height,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExportStats {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub transfers: usize,
+    /// blocks which could not be decoded, and were skipped
+    pub skipped: usize,
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+}
+
+pub fn blocks_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("slot", DataType::Int64, false),
+        Field::new("block_height", DataType::Int64, true),
+        Field::new("block_time", timestamp_type(), true),
+        Field::new("blockhash", DataType::Utf8, false),
+        Field::new("previous_blockhash", DataType::Utf8, false),
+        Field::new("parent_slot", DataType::Int64, false),
+        Field::new("transaction_count", DataType::Int64, false),
+    ]))
+}
+
+pub fn transactions_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("slot", DataType::Int64, false),
+        Field::new("block_height", DataType::Int64, true),
+        Field::new("block_time", timestamp_type(), true),
+        Field::new("tx_index", DataType::Int32, false),
+        Field::new("signature", DataType::Utf8, false),
+        Field::new("success", DataType::Boolean, false),
+        Field::new("err", DataType::Utf8, true),
+        Field::new("fee", DataType::Int64, true),
+        Field::new("compute_units_consumed", DataType::Int64, true),
+    ]))
+}
+
+pub fn transfers_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("slot", DataType::Int64, false),
+        Field::new("block_height", DataType::Int64, true),
+        Field::new("block_time", timestamp_type(), true),
+        Field::new("tx_hash", DataType::Utf8, false),
+        Field::new("transfer_index", DataType::Int32, false),
+        Field::new("sender", DataType::Utf8, false),
+        Field::new("recipient", DataType::Utf8, false),
+        Field::new("authority", DataType::Utf8, true),
+        Field::new("mint", DataType::Utf8, false),
+        // amounts are stored as strings to avoid losing precision on u64 values
+        Field::new("amount", DataType::Utf8, false),
+    ]))
+}
+
+#[derive(Default)]
+struct BlockColumns {
+    slot: Int64Builder,
+    block_height: Int64Builder,
+    block_time: TimestampMicrosecondBuilder,
+    blockhash: StringBuilder,
+    previous_blockhash: StringBuilder,
+    parent_slot: Int64Builder,
+    transaction_count: Int64Builder,
+}
+
+#[derive(Default)]
+struct TransactionColumns {
+    slot: Int64Builder,
+    block_height: Int64Builder,
+    block_time: TimestampMicrosecondBuilder,
+    tx_index: Int32Builder,
+    signature: StringBuilder,
+    success: BooleanBuilder,
+    err: StringBuilder,
+    fee: Int64Builder,
+    compute_units_consumed: Int64Builder,
+}
+
+#[derive(Default)]
+struct TransferColumns {
+    slot: Int64Builder,
+    block_height: Int64Builder,
+    block_time: TimestampMicrosecondBuilder,
+    tx_hash: StringBuilder,
+    transfer_index: Int32Builder,
+    sender: StringBuilder,
+    recipient: StringBuilder,
+    authority: StringBuilder,
+    mint: StringBuilder,
+    amount: StringBuilder,
+}
+
+/// rows for a single day partition
+#[derive(Default)]
+struct Partition {
+    blocks: BlockColumns,
+    transactions: TransactionColumns,
+    transfers: TransferColumns,
+    block_rows: usize,
+    transaction_rows: usize,
+    transfer_rows: usize,
+}
+
+impl Partition {
+    fn append_block(&mut self, block: Blocks, stats: &mut ExportStats) -> Result<()> {
+        let slot = block.slot;
+        let number = block.number;
+        let time = block.time.map(|time| time.timestamp_micros());
+        let ui_block: UiConfirmedBlock = serde_json::from_value(block.data)
+            .with_context(|| format!("failed to deserialize block({slot})"))?;
+
+        let b = &mut self.blocks;
+        b.slot.append_value(slot);
+        b.block_height.append_option(number);
+        b.block_time.append_option(time);
+        b.blockhash.append_value(&ui_block.blockhash);
+        b.previous_blockhash.append_value(&ui_block.previous_blockhash);
+        b.parent_slot.append_value(ui_block.parent_slot as i64);
+        b.transaction_count.append_value(
+            ui_block
+                .transactions
+                .as_ref()
+                .map(|txs| txs.len())
+                .unwrap_or_default() as i64,
+        );
+        self.block_rows += 1;
+        stats.blocks += 1;
+
+        let t = &mut self.transactions;
+        for (idx, tx) in ui_block.transactions.iter().flatten().enumerate() {
+            let EncodedTransaction::Json(ui_tx) = &tx.transaction else {
+                continue;
+            };
+            let Some(signature) = ui_tx.signatures.first() else {
+                continue;
+            };
+            t.slot.append_value(slot);
+            t.block_height.append_option(number);
+            t.block_time.append_option(time);
+            t.tx_index.append_value(idx as i32);
+            t.signature.append_value(signature);
+            match &tx.meta {
+                Some(meta) => {
+                    t.success.append_value(meta.err.is_none());
+                    t.err
+                        .append_option(meta.err.as_ref().map(|err| err.to_string()));
+                    t.fee.append_value(meta.fee as i64);
+                    t.compute_units_consumed
+                        .append_option(match &meta.compute_units_consumed {
+                            OptionSerializer::Some(units) => Some(*units as i64),
+                            _ => None,
+                        });
+                }
+                None => {
+                    t.success.append_value(true);
+                    t.err.append_null();
+                    t.fee.append_null();
+                    t.compute_units_consumed.append_null();
+                }
+            }
+            self.transaction_rows += 1;
+            stats.transactions += 1;
+        }
+
+        let ordered_transfers = match create_ordered_transfer_for_block(ui_block) {
+            Ok(ordered_transfers) => ordered_transfers,
+            Err(err) => {
+                log::debug!("no transfers for block({slot}) {err:#?}");
+                return Ok(());
+            }
+        };
+        let tr = &mut self.transfers;
+        for ordered in ordered_transfers {
+            for (idx, transfer) in ordered.transfers.into_iter().enumerate() {
+                tr.slot.append_value(slot);
+                tr.block_height.append_option(number);
+                tr.block_time.append_option(time);
+                tr.tx_hash.append_value(&ordered.tx_hash);
+                tr.transfer_index.append_value(idx as i32);
+                tr.sender.append_value(transfer.sender);
+                tr.recipient.append_value(transfer.recipient);
+                tr.authority.append_option(transfer.authority);
+                tr.mint.append_value(transfer.mint);
+                tr.amount.append_value(transfer.amount);
+                self.transfer_rows += 1;
+                stats.transfers += 1;
+            }
+        }
+        Ok(())
+    }
+    fn finish(mut self) -> Result<[(&'static str, RecordBatch, usize); 3]> {
+        let b = &mut self.blocks;
+        let blocks = RecordBatch::try_new(
+            blocks_schema(),
+            vec![
+                Arc::new(b.slot.finish()) as ArrayRef,
+                Arc::new(b.block_height.finish()),
+                Arc::new(b.block_time.finish().with_timezone("UTC")),
+                Arc::new(b.blockhash.finish()),
+                Arc::new(b.previous_blockhash.finish()),
+                Arc::new(b.parent_slot.finish()),
+                Arc::new(b.transaction_count.finish()),
+            ],
+        )?;
+        let t = &mut self.transactions;
+        let transactions = RecordBatch::try_new(
+            transactions_schema(),
+            vec![
+                Arc::new(t.slot.finish()) as ArrayRef,
+                Arc::new(t.block_height.finish()),
+                Arc::new(t.block_time.finish().with_timezone("UTC")),
+                Arc::new(t.tx_index.finish()),
+                Arc::new(t.signature.finish()),
+                Arc::new(t.success.finish()),
+                Arc::new(t.err.finish()),
+                Arc::new(t.fee.finish()),
+                Arc::new(t.compute_units_consumed.finish()),
+            ],
+        )?;
+        let tr = &mut self.transfers;
+        let transfers = RecordBatch::try_new(
+            transfers_schema(),
+            vec![
+                Arc::new(tr.slot.finish()) as ArrayRef,
+                Arc::new(tr.block_height.finish()),
+                Arc::new(tr.block_time.finish().with_timezone("UTC")),
+                Arc::new(tr.tx_hash.finish()),
+                Arc::new(tr.transfer_index.finish()),
+                Arc::new(tr.sender.finish()),
+                Arc::new(tr.recipient.finish()),
+                Arc::new(tr.authority.finish()),
+                Arc::new(tr.mint.finish()),
+                Arc::new(tr.amount.finish()),
+            ],
+        )?;
+        Ok([
+            ("blocks", blocks, self.block_rows),
+            ("transactions", transactions, self.transaction_rows),
+            ("transfers", transfers, self.transfer_rows),
+        ])
+    }
+}
+
+/// Exports blocks within `[start, end]` to day partitioned parquet files in `output_dir`
+pub struct ParquetExporter {
+    output_dir: PathBuf,
+    /// open writers keyed by (table, partition)
+    writers: BTreeMap<(&'static str, String), ArrowWriter<File>>,
+    file_name: String,
+}
+
+impl ParquetExporter {
+    pub fn new(output_dir: impl AsRef<Path>, start: i64, end: i64) -> Self {
+        Self {
+            output_dir: output_dir.as_ref().to_path_buf(),
+            writers: Default::default(),
+            file_name: format!("part-{start}-{end}.parquet"),
+        }
+    }
+    pub fn export(
+        mut self,
+        conn: &mut PgConnection,
+        kind: RangeKind,
+        start: i64,
+        end: i64,
+        batch_size: i64,
+    ) -> Result<ExportStats> {
+        if end < start {
+            return Err(anyhow!("end({end}) < start({start})"));
+        }
+        if batch_size <= 0 {
+            return Err(anyhow!("batch_size must be positive"));
+        }
+        let client = Client {};
+        let mut stats = ExportStats::default();
+        let filter = match kind {
+            RangeKind::Slot => BlockFilter::SlotRange {
+                start,
+                end,
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+            RangeKind::Height => BlockFilter::NumberRange {
+                start,
+                end,
+                order: BlockOrder::Ascending,
+                limit: None,
+            },
+        };
+        for blocks in client.select_block_pages(conn, filter, batch_size)? {
+            let blocks = blocks?;
+            let (first_slot, last_slot) = match (blocks.first(), blocks.last()) {
+                (Some(first), Some(last)) => (first.slot, last.slot),
+                _ => continue,
+            };
+            let mut partitions: BTreeMap<String, Partition> = BTreeMap::new();
+            for block in blocks {
+                let slot = block.slot;
+                let partition = match block.time {
+                    Some(time) => time.format("%Y-%m-%d").to_string(),
+                    None => DEFAULT_PARTITION.to_string(),
+                };
+                if let Err(err) = partitions
+                    .entry(partition)
+                    .or_default()
+                    .append_block(block, &mut stats)
+                {
+                    log::warn!("skipping block({slot}) {err:#?}");
+                    stats.skipped += 1;
+                }
+            }
+            for (partition, rows) in partitions {
+                for (table, batch, num_rows) in rows.finish()? {
+                    if num_rows == 0 {
+                        continue;
+                    }
+                    self.writer(table, &partition, batch.schema())?
+                        .write(&batch)
+                        .with_context(|| format!("failed to write {table}(date={partition})"))?;
+                }
+            }
+            log::info!(
+                "exported slots({first_slot}..={last_slot}) blocks={} transactions={} transfers={}",
+                stats.blocks,
+                stats.transactions,
+                stats.transfers
+            );
+        }
+        for ((table, partition), writer) in std::mem::take(&mut self.writers) {
+            writer
+                .close()
+                .with_context(|| format!("failed to close {table}(date={partition})"))?;
+        }
+        Ok(stats)
+    }
+    fn writer(
+        &mut self,
+        table: &'static str,
+        partition: &str,
+        schema: SchemaRef,
+    ) -> Result<&mut ArrowWriter<File>> {
+        let key = (table, partition.to_string());
+        if !self.writers.contains_key(&key) {
+            let dir = self
+                .output_dir
+                .join(table)
+                .join(format!("date={partition}"));
+            std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir:?}"))?;
+            let path = dir.join(&self.file_name);
+            let file = File::create(&path).with_context(|| format!("failed to create {path:?}"))?;
+            let props = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .build();
+            self.writers
+                .insert(key.clone(), ArrowWriter::try_new(file, schema, Some(props))?);
+        }
+        Ok(self.writers.get_mut(&key).expect("writer inserted above"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_schemas_are_stable() {
+        let names = |schema: SchemaRef| {
+            schema
+                .fields()
+                .iter()
+                .map(|field| field.name().clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(blocks_schema()),
+            vec![
+                "slot",
+                "block_height",
+                "block_time",
+                "blockhash",
+                "previous_blockhash",
+                "parent_slot",
+                "transaction_count"
+            ]
+        );
+        assert_eq!(names(transactions_schema()).len(), 9);
+        assert_eq!(names(transfers_schema()).len(), 10);
+        // an empty partition must still produce batches matching the schemas
+        let batches = Partition::default().finish().unwrap();
+        assert!(batches.iter().all(|(_, batch, rows)| batch.num_rows() == *rows));
+    }
+}
@@ -0,0 +1,304 @@
+//! Re-fetches stored blocks from an upstream source and overwrites them in place.
+//!
+//! Used to replace minimized blocks with full copies, or blocks stored with an outdated encoding.
+
+use {
+    super::{backfill::Backfiller, bigtable::Downloader},
+    crate::{
+        sinks::EncodedBlock,
+        types::{BlockInfo, BlockSource},
+    },
+    anyhow::{anyhow, Result},
+    chrono::prelude::*,
+    db::{async_client::AsyncClient, client::ReplaceOutcome},
+    futures::{
+        future::BoxFuture,
+        stream::{self, StreamExt},
+    },
+    serde::Serialize,
+    solana_transaction_status::UiConfirmedBlock,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum ReingestFrom {
+    Bigtable,
+    Rpc,
+}
+
+pub enum Upstream {
+    Bigtable(Downloader),
+    Rpc(Backfiller),
+}
+
+impl Upstream {
+    pub fn source(&self) -> BlockSource {
+        match self {
+            Self::Bigtable(_) => BlockSource::Bigtable,
+            Self::Rpc(_) => BlockSource::Rpc,
+        }
+    }
+    pub async fn get_block(&self, slot: u64, no_minimization: bool) -> Result<Option<UiConfirmedBlock>> {
+        match self {
+            Self::Bigtable(downloader) => downloader.get_block(slot, no_minimization).await,
+            Self::Rpc(backfiller) => Ok(Some(backfiller.get_block(slot, no_minimization).await?)),
+        }
+    }
+}
+
+/// A source blocks can be re-fetched from
+pub trait BlockUpstream: Send + Sync {
+    /// source recorded in the provenance of fetched blocks
+    fn source(&self) -> BlockSource;
+    /// Fetches the block at `slot`, returning None if the upstream has no block for it
+    fn get_block<'a>(
+        &'a self,
+        slot: u64,
+        no_minimization: bool,
+    ) -> BoxFuture<'a, Result<Option<UiConfirmedBlock>>>;
+}
+
+impl BlockUpstream for Upstream {
+    fn source(&self) -> BlockSource {
+        Upstream::source(self)
+    }
+    fn get_block<'a>(
+        &'a self,
+        slot: u64,
+        no_minimization: bool,
+    ) -> BoxFuture<'a, Result<Option<UiConfirmedBlock>>> {
+        Box::pin(Upstream::get_block(self, slot, no_minimization))
+    }
+}
+
+/// Upstream fixtures shared by the tests of services which fetch blocks
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use {super::*, std::collections::HashMap};
+
+    /// an upstream serving fixed blocks, which fails to fetch `failing_slot`
+    pub(crate) struct StubUpstream {
+        pub blocks: HashMap<u64, UiConfirmedBlock>,
+        pub failing_slot: u64,
+    }
+
+    impl BlockUpstream for StubUpstream {
+        fn source(&self) -> BlockSource {
+            BlockSource::Rpc
+        }
+        fn get_block<'a>(
+            &'a self,
+            slot: u64,
+            _no_minimization: bool,
+        ) -> BoxFuture<'a, Result<Option<UiConfirmedBlock>>> {
+            Box::pin(async move {
+                if slot == self.failing_slot {
+                    return Err(anyhow!("upstream unavailable"));
+                }
+                Ok(self.blocks.get(&slot).cloned())
+            })
+        }
+    }
+
+    /// an empty block at `slot`, whose parent is the previous slot
+    pub(crate) fn block(slot: u64) -> UiConfirmedBlock {
+        serde_json::from_value(serde_json::json!({
+            "previousBlockhash": "11111111111111111111111111111111",
+            "blockhash": "11111111111111111111111111111111",
+            "parentSlot": slot - 1,
+            "blockHeight": slot,
+            "transactions": [],
+        }))
+        .unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReingestStats {
+    /// stored blocks matching the selection
+    pub selected: usize,
+    /// blocks which the upstream did not return
+    pub missing: usize,
+    /// blocks which could not be fetched, encoded or written
+    pub failed: usize,
+    pub replaced: usize,
+    /// blocks whose data was identical to the stored copy
+    pub unchanged: usize,
+    /// blocks which were deleted from postgres after being selected, and were inserted again
+    pub inserted: usize,
+    /// replaced blocks whose minimization changed
+    pub minimization_changed: usize,
+    /// replaced blocks whose encoding version changed
+    pub encoding_changed: usize,
+    /// change in the total number of stored transactions
+    pub transactions_delta: i64,
+}
+
+/// Re-fetches each slot from `upstream` with the given options, and overwrites the stored block
+pub async fn reingest_slots(
+    db: &AsyncClient,
+    upstream: &dyn BlockUpstream,
+    slots: Vec<i64>,
+    no_minimization: bool,
+    threads: usize,
+) -> Result<ReingestStats> {
+    if threads == 0 {
+        return Err(anyhow!("threads must be positive"));
+    }
+    let mut stats = ReingestStats {
+        selected: slots.len(),
+        ..Default::default()
+    };
+    let mut fetched = stream::iter(slots)
+        .map(|slot| async move { (slot, upstream.get_block(slot as u64, no_minimization).await) })
+        .buffer_unordered(threads);
+    while let Some((slot, res)) = fetched.next().await {
+        let block = match res {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                log::warn!("block({slot}) not found upstream");
+                stats.missing += 1;
+                continue;
+            }
+            Err(err) => {
+                log::error!("failed to fetch block({slot}) {err:#?}");
+                stats.failed += 1;
+                continue;
+            }
+        };
+        let block_height = block.block_height;
+        let time = block
+            .block_time
+            .and_then(|block_time| DateTime::from_timestamp(block_time, 0));
+        let new_txs = transaction_count(&block) as i64;
+        let block = match EncodedBlock::try_from(BlockInfo {
+            block_height,
+            slot: slot as u64,
+            time,
+            block,
+            source: upstream.source(),
+            minimized: !no_minimization,
+        }) {
+            Ok(block) => block,
+            Err(err) => {
+                log::error!("failed to encode block({slot}) {err:#?}");
+                stats.failed += 1;
+                continue;
+            }
+        };
+        let (minimized, encoding_version) = (
+            block.provenance.minimized,
+            block.provenance.encoding_version,
+        );
+        let res = db
+            .interact(move |client, conn| {
+                client.replace_block(
+                    conn,
+                    block.block_height.map(|block_height| block_height as i64),
+                    slot,
+                    block.time,
+                    &block.data,
+                    &block.provenance,
+                )
+            })
+            .await;
+        match res {
+            Ok(ReplaceOutcome::Replaced(previous)) => {
+                stats.replaced += 1;
+                if previous.minimized != minimized {
+                    stats.minimization_changed += 1;
+                }
+                if previous.encoding_version != encoding_version {
+                    stats.encoding_changed += 1;
+                }
+                let previous_txs = previous.data["transactions"]
+                    .as_array()
+                    .map(|txs| txs.len())
+                    .unwrap_or_default() as i64;
+                stats.transactions_delta += new_txs - previous_txs;
+                log::info!("replaced block({slot})");
+            }
+            Ok(ReplaceOutcome::Unchanged) => stats.unchanged += 1,
+            Ok(ReplaceOutcome::Inserted) => stats.inserted += 1,
+            Err(err) => {
+                log::error!("failed to replace block({slot}) {err:#?}");
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn transaction_count(block: &UiConfirmedBlock) -> usize {
+    block
+        .transactions
+        .as_ref()
+        .map(|txs| txs.len())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{
+            test_utils::{block, StubUpstream},
+            *,
+        },
+        db::{models::Provenance, test_utils::TestDb},
+    };
+
+    #[tokio::test]
+    async fn test_reingest_slots() {
+        let test_db = TestDb::new();
+        let db = AsyncClient::new(&test_db.url(), 2).unwrap();
+        db.run_migrations().await.unwrap();
+        for (slot, txs) in [(10, 2), (20, 0)] {
+            db.interact(move |client, conn| {
+                client.insert_block(
+                    conn,
+                    Some(slot),
+                    slot,
+                    None,
+                    &serde_json::json!({ "transactions": vec![serde_json::json!({}); txs] }),
+                    &Provenance {
+                        source: "geyser".to_string(),
+                        minimized: Some(true),
+                        encoding_version: None,
+                    },
+                )
+            })
+            .await
+            .unwrap();
+        }
+        let upstream = StubUpstream {
+            blocks: [10, 20, 30]
+                .into_iter()
+                .map(|slot| (slot, block(slot)))
+                .collect(),
+            failing_slot: 50,
+        };
+
+        // slot 30 was selected but deleted before re-ingesting, 40 is missing upstream and 50 fails
+        let stats = reingest_slots(&db, &upstream, vec![10, 20, 30, 40, 50], true, 2)
+            .await
+            .unwrap();
+        assert_eq!(stats.selected, 5);
+        assert_eq!(stats.replaced, 2);
+        assert_eq!(stats.inserted, 1);
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.unchanged, 0);
+        assert_eq!(stats.minimization_changed, 2);
+        assert_eq!(stats.encoding_changed, 2);
+        assert_eq!(stats.transactions_delta, -2);
+
+        // blocks are now identical to the upstream copy
+        let stats = reingest_slots(&db, &upstream, vec![10, 20, 30], true, 2)
+            .await
+            .unwrap();
+        assert_eq!(stats.unchanged, 3);
+        assert_eq!(stats.replaced, 0);
+        assert!(reingest_slots(&db, &upstream, vec![], true, 0)
+            .await
+            .is_err());
+    }
+}
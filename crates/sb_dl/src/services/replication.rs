@@ -0,0 +1,428 @@
+//! Incremental replication of the blocks, idls, programs and squads tables between two databases.
+//!
+//! Each table is copied in batches from a high-water mark, which is stored in the `replication_state`
+//! table of the destination database together with the batch it describes, so an interrupted run
+//! resumes without skipping or duplicating rows.
+//!
+//! Rows are tracked by `change_id`, which is assigned from a sequence whenever a row is inserted or
+//! its contents are modified, and are upserted into the destination. As a change id is taken before
+//! its transaction commits, each run only copies change ids below a fence at which every transaction
+//! which could still commit a lower change id has finished, see [`Client::replication_fence`].
+//!
+//! * `blocks` stored before change ids were assigned to blocks are copied by slot first.
+//! * `squads` which conflict with a destination multisig are recorded in `replication_failures`,
+//!   and retried by every run.
+
+use {
+    anyhow::{anyhow, Context, Result},
+    db::{
+        client::{Client, ReplicationFence},
+        models::Squads,
+    },
+    diesel::{Connection, PgConnection},
+    std::time::{Duration, Instant},
+};
+
+/// high-water mark of blocks which were assigned a change id, blocks without one use `blocks`
+const BLOCK_CHANGES_MARK: &str = "blocks_changes";
+
+/// how long to wait for transactions holding change ids below a fence to finish
+const FENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum ReplicationDirection {
+    /// copy from `db_url` to `remotedb_url`
+    LocalToRemote,
+    /// copy from `remotedb_url` to `db_url`
+    RemoteToLocal,
+}
+
+impl ReplicationDirection {
+    /// name of the source database, used to key the high-water marks stored in the destination
+    pub fn source_name(&self) -> &'static str {
+        match self {
+            Self::LocalToRemote => "local",
+            Self::RemoteToLocal => "remote",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum ReplicatedTable {
+    Blocks,
+    Idls,
+    Programs,
+    Squads,
+}
+
+impl ReplicatedTable {
+    pub fn table_name(&self) -> &'static str {
+        match self {
+            Self::Blocks => "blocks",
+            Self::Idls => "idls",
+            Self::Programs => "programs",
+            Self::Squads => "squads",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Replicator {
+    source_name: &'static str,
+    batch_size: i64,
+    client: Client,
+}
+
+impl Replicator {
+    pub fn new(direction: ReplicationDirection, batch_size: i64) -> Result<Self> {
+        if batch_size <= 0 {
+            return Err(anyhow!("batch_size must be positive"));
+        }
+        Ok(Self {
+            source_name: direction.source_name(),
+            batch_size,
+            client: Client {},
+        })
+    }
+    /// Copies all rows of `table` changed since the last run, returning the number of rows copied
+    pub fn replicate_table(
+        &self,
+        src: &mut PgConnection,
+        dst: &mut PgConnection,
+        table: ReplicatedTable,
+    ) -> Result<usize> {
+        let mut copied = 0;
+        match table {
+            ReplicatedTable::Blocks => loop {
+                let batch = self.replicate_legacy_blocks(src, dst)?;
+                copied += batch;
+                if batch < self.batch_size as usize {
+                    break;
+                }
+            },
+            ReplicatedTable::Squads => copied += self.retry_failed_squads(src, dst)?,
+            ReplicatedTable::Idls | ReplicatedTable::Programs => {}
+        }
+        let Some(fence) = self.wait_for_fence(src)? else {
+            log::warn!(
+                "timed out waiting for {} changes to commit, retrying next run",
+                table.table_name()
+            );
+            return Ok(copied);
+        };
+        loop {
+            let batch = self.replicate_batch(src, dst, table, fence.change_id)?;
+            copied += batch;
+            if batch < self.batch_size as usize {
+                return Ok(copied);
+            }
+        }
+    }
+    /// Returns a fence of the source database once it is complete, or None if that takes longer
+    /// than [`FENCE_TIMEOUT`]
+    fn wait_for_fence(&self, src: &mut PgConnection) -> Result<Option<ReplicationFence>> {
+        let fence = self.client.replication_fence(src)?;
+        let started = Instant::now();
+        while !self.client.replication_fence_complete(src, fence)? {
+            if started.elapsed() >= FENCE_TIMEOUT {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(Some(fence))
+    }
+    /// Copies a single batch of blocks without a change id, which are never modified, by slot
+    fn replicate_legacy_blocks(
+        &self,
+        src: &mut PgConnection,
+        dst: &mut PgConnection,
+    ) -> Result<usize> {
+        let table_name = ReplicatedTable::Blocks.table_name();
+        let hwm = self
+            .client
+            .select_replication_hwm(dst, self.source_name, table_name)?
+            .unwrap_or(-1);
+        let rows = self.client.select_blocks_after(src, hwm, self.batch_size)?;
+        let Some(last) = rows.last().map(|block| block.slot) else {
+            return Ok(0);
+        };
+        dst.transaction::<_, anyhow::Error, _>(|dst| {
+            self.client.upsert_replicated_blocks(dst, &rows)?;
+            self.client
+                .update_replication_hwm(dst, self.source_name, table_name, last)
+        })?;
+        Ok(rows.len())
+    }
+    /// Copies a single batch of rows with a change id up to `up_to`, returning the number of rows
+    /// read from the source
+    fn replicate_batch(
+        &self,
+        src: &mut PgConnection,
+        dst: &mut PgConnection,
+        table: ReplicatedTable,
+        up_to: i64,
+    ) -> Result<usize> {
+        let table_name = match table {
+            ReplicatedTable::Blocks => BLOCK_CHANGES_MARK,
+            _ => table.table_name(),
+        };
+        let hwm = self
+            .client
+            .select_replication_hwm(dst, self.source_name, table_name)?
+            // change ids are never negative
+            .unwrap_or(-1);
+        let client = self.client;
+        let source_name = self.source_name;
+        match table {
+            ReplicatedTable::Blocks => {
+                let rows = client.select_blocks_changed_after(src, hwm, up_to, self.batch_size)?;
+                let Some(last) = rows.last().map(|(_, change_id)| *change_id) else {
+                    return Ok(0);
+                };
+                let rows = rows.into_iter().map(|(row, _)| row).collect::<Vec<_>>();
+                dst.transaction::<_, anyhow::Error, _>(|dst| {
+                    client.upsert_replicated_blocks(dst, &rows)?;
+                    client.update_replication_hwm(dst, source_name, table_name, last)
+                })?;
+                Ok(rows.len())
+            }
+            ReplicatedTable::Idls => {
+                let rows = client.select_idls_changed_after(src, hwm, up_to, self.batch_size)?;
+                let Some(last) = rows.last().map(|(_, change_id)| *change_id) else {
+                    return Ok(0);
+                };
+                let rows = rows.into_iter().map(|(row, _)| row).collect::<Vec<_>>();
+                dst.transaction::<_, anyhow::Error, _>(|dst| {
+                    client.upsert_idls(dst, &rows)?;
+                    client.update_replication_hwm(dst, source_name, table_name, last)
+                })?;
+                Ok(rows.len())
+            }
+            ReplicatedTable::Programs => {
+                let rows =
+                    client.select_programs_changed_after(src, hwm, up_to, self.batch_size)?;
+                let Some(last) = rows.last().map(|(_, change_id)| *change_id) else {
+                    return Ok(0);
+                };
+                let rows = rows.into_iter().map(|(row, _)| row).collect::<Vec<_>>();
+                dst.transaction::<_, anyhow::Error, _>(|dst| {
+                    client.upsert_programs(dst, &rows)?;
+                    client.update_replication_hwm(dst, source_name, table_name, last)
+                })?;
+                Ok(rows.len())
+            }
+            ReplicatedTable::Squads => {
+                let rows = client.select_squads_changed_after(src, hwm, up_to, self.batch_size)?;
+                let Some(last) = rows.last().map(|(_, change_id)| *change_id) else {
+                    return Ok(0);
+                };
+                let rows = rows.into_iter().map(|(row, _)| row).collect::<Vec<_>>();
+                if let Err(err) = dst.transaction::<_, anyhow::Error, _>(|dst| {
+                    client.upsert_squads(dst, &rows)?;
+                    client.update_replication_hwm(dst, source_name, table_name, last)
+                }) {
+                    // vaults are unique across multisigs, so a single conflicting row fails the
+                    // whole batch. retry row by row, recording the conflicting rows to retry later
+                    log::warn!("failed to replicate squads batch, retrying individually {err:#?}");
+                    dst.transaction::<_, anyhow::Error, _>(|dst| {
+                        self.upsert_squads_individually(dst, &rows)?;
+                        client.update_replication_hwm(dst, source_name, table_name, last)
+                    })
+                    .with_context(|| "failed to replicate squads individually")?;
+                }
+                Ok(rows.len())
+            }
+        }
+    }
+    /// Retries multisigs which previously failed to replicate, returning the number now copied
+    fn retry_failed_squads(&self, src: &mut PgConnection, dst: &mut PgConnection) -> Result<usize> {
+        let table_name = ReplicatedTable::Squads.table_name();
+        let accounts =
+            self.client
+                .select_replication_failures(dst, self.source_name, table_name)?;
+        if accounts.is_empty() {
+            return Ok(0);
+        }
+        let rows = self.client.select_squads_by_accounts(src, &accounts)?;
+        dst.transaction::<_, anyhow::Error, _>(|dst| {
+            for account in accounts.iter() {
+                if !rows.iter().any(|row| &row.account == account) {
+                    // removed from the source, so there is nothing left to copy
+                    self.client.delete_replication_failure(
+                        dst,
+                        self.source_name,
+                        table_name,
+                        account,
+                    )?;
+                }
+            }
+            self.upsert_squads_individually(dst, &rows)
+        })
+    }
+    /// Upserts each multisig in its own savepoint, recording the rows which fail in
+    /// `replication_failures` and clearing the records of rows which succeed. Returns the number of
+    /// rows written
+    fn upsert_squads_individually(&self, dst: &mut PgConnection, rows: &[Squads]) -> Result<usize> {
+        let table_name = ReplicatedTable::Squads.table_name();
+        let mut copied = 0;
+        for row in rows.iter() {
+            match dst.transaction::<_, anyhow::Error, _>(|dst| {
+                self.client.upsert_squads(dst, std::slice::from_ref(row))
+            }) {
+                Ok(_) => {
+                    self.client.delete_replication_failure(
+                        dst,
+                        self.source_name,
+                        table_name,
+                        &row.account,
+                    )?;
+                    copied += 1;
+                }
+                Err(err) => {
+                    log::error!("failed to replicate multisig({}) {err:#?}", row.account);
+                    self.client.insert_replication_failure(
+                        dst,
+                        self.source_name,
+                        table_name,
+                        &row.account,
+                        &format!("{err:#}"),
+                    )?;
+                }
+            }
+        }
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        db::{migrations::run_migrations, models::Provenance, test_utils::TestDb},
+        diesel::{sql_query, RunQueryDsl},
+    };
+
+    #[test]
+    fn test_replicate_tables() {
+        let src_db = TestDb::new();
+        let dst_db = TestDb::new();
+        let mut src = src_db.conn();
+        let mut dst = dst_db.conn();
+        run_migrations(&mut src);
+        run_migrations(&mut dst);
+        let client = Client {};
+        let replicator = Replicator::new(ReplicationDirection::LocalToRemote, 2).unwrap();
+        let provenance = Provenance {
+            source: "geyser".to_string(),
+            minimized: Some(true),
+            encoding_version: Some(1),
+        };
+        let insert_block = |conn: &mut PgConnection, slot: i64| {
+            client
+                .insert_block(
+                    conn,
+                    Some(slot),
+                    slot,
+                    None,
+                    &serde_json::json!({ "slot": slot }),
+                    &provenance,
+                )
+                .unwrap();
+        };
+
+        // a block stored before blocks were assigned change ids
+        insert_block(&mut src, 5);
+        sql_query("UPDATE blocks SET change_id = NULL WHERE slot = 5")
+            .execute(&mut src)
+            .unwrap();
+        for slot in [10, 20, 30] {
+            insert_block(&mut src, slot);
+        }
+        assert_eq!(
+            replicator
+                .replicate_table(&mut src, &mut dst, ReplicatedTable::Blocks)
+                .unwrap(),
+            4
+        );
+
+        // a block inserted below the high-water mark, and a replaced block
+        insert_block(&mut src, 15);
+        let reingested = serde_json::json!({ "slot": 20, "reingested": true });
+        client
+            .replace_block(&mut src, Some(20), 20, None, &reingested, &provenance)
+            .unwrap();
+        assert_eq!(
+            replicator
+                .replicate_table(&mut src, &mut dst, ReplicatedTable::Blocks)
+                .unwrap(),
+            2
+        );
+        let blocks = client
+            .select_blocks_by_slot_range(&mut dst, 0, 100)
+            .unwrap();
+        assert_eq!(
+            blocks.iter().map(|block| block.slot).collect::<Vec<_>>(),
+            vec![5, 10, 15, 20, 30]
+        );
+        assert_eq!(blocks[3].data, reingested);
+        assert_eq!(
+            replicator
+                .replicate_table(&mut src, &mut dst, ReplicatedTable::Blocks)
+                .unwrap(),
+            0
+        );
+
+        // a multisig conflicting with the vaults of a destination multisig is recorded and retried
+        let vaults = |vault: &str| vec![vault.to_string()];
+        client
+            .insert_or_update_squads(&mut src, "multisig_a", &vaults("vault_a"), &[], 1, 1, 4)
+            .unwrap();
+        client
+            .insert_or_update_squads(&mut src, "multisig_b", &vaults("vault_b"), &[], 1, 1, 4)
+            .unwrap();
+        client
+            .insert_or_update_squads(&mut dst, "multisig_c", &vaults("vault_b"), &[], 1, 1, 4)
+            .unwrap();
+        assert_eq!(
+            replicator
+                .replicate_table(&mut src, &mut dst, ReplicatedTable::Squads)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            client
+                .select_replication_failures(&mut dst, "local", "squads")
+                .unwrap(),
+            vec!["multisig_b".to_string()]
+        );
+        let accounts = vec!["multisig_a".to_string(), "multisig_b".to_string()];
+        assert_eq!(
+            client
+                .select_squads_by_accounts(&mut dst, &accounts)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        sql_query("DELETE FROM squads WHERE account = 'multisig_c'")
+            .execute(&mut dst)
+            .unwrap();
+        assert_eq!(
+            replicator
+                .replicate_table(&mut src, &mut dst, ReplicatedTable::Squads)
+                .unwrap(),
+            1
+        );
+        assert!(client
+            .select_replication_failures(&mut dst, "local", "squads")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            client
+                .select_squads_by_accounts(&mut dst, &accounts)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,652 @@
+//! Durable on-disk queue for blocks which failed to be persisted to postgres.
+//!
+//! Blocks are appended to segment files named `segment_{id}.seg`, where each record is laid out as
+//!
+//! `[slot: u64 LE][payload_len: u32 LE][crc32(payload): u32 LE][payload]`
+//!
+//! and the payload is a zstd compressed json encoding of [`QueuedBlock`]. New records are always
+//! written to the active (highest id) segment, while older segments are drained by [`retry_loop`].
+//! A segment is only removed once every record in it has either been persisted, or re-queued
+//! into the active segment, so no block is lost if the process exits part way through a drain.
+//! Segments where no record is due for a retry are left untouched until their earliest record is
+//! due.
+
+use {
+    anyhow::{anyhow, Context, Result},
+    chrono::prelude::*,
+    db::{async_client::AsyncClient, models::Provenance},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet},
+        fs::{File, OpenOptions},
+        io::{BufReader, Read, Write},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+};
+
+/// size of the fixed record header, slot + payload_len + checksum
+const HEADER_LEN: usize = 16;
+/// compression level used for queued blocks, favours speed as blocks are short lived
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedBlock {
+    pub slot: u64,
+    /// None for blocks without a height, which are stored by slot
+    pub block_height: Option<u64>,
+    pub time: Option<DateTime<Utc>>,
+    /// error encountered during the most recent persistence attempt
+    pub reason: String,
+    /// number of retry attempts made by the retry task
+    pub attempts: u32,
+    pub queued_at: DateTime<Utc>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// the sanitized block, as it would be stored in postgres
+    pub block: serde_json::Value,
+    /// None for blocks queued by releases which did not record provenance
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+}
+
+impl QueuedBlock {
+    pub fn new(
+        slot: u64,
+        block_height: Option<u64>,
+        time: Option<DateTime<Utc>>,
+        reason: String,
+        block: serde_json::Value,
+        provenance: Provenance,
+    ) -> Self {
+        Self {
+            slot,
+            block_height,
+            time,
+            reason,
+            attempts: 0,
+            queued_at: Utc::now(),
+            last_attempt: None,
+            block,
+            provenance: Some(provenance),
+        }
+    }
+    /// returns true if enough time has passed since the last attempt to retry the block
+    pub fn is_due(&self, now: DateTime<Utc>, cfg: &RetryConfig) -> bool {
+        self.due_at(cfg).map_or(true, |due_at| now >= due_at)
+    }
+    /// returns when the block can next be retried, None if it can be retried immediately
+    pub fn due_at(&self, cfg: &RetryConfig) -> Option<DateTime<Utc>> {
+        let last_attempt = self.last_attempt?;
+        let backoff = cfg
+            .base_backoff
+            .saturating_mul(2_u32.saturating_pow(self.attempts.saturating_sub(1)))
+            .min(cfg.max_backoff);
+        Some(last_attempt + chrono::Duration::from_std(backoff).ok()?)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    /// how often the queue is drained
+    pub interval: Duration,
+    /// backoff applied to a block after its first failed retry, doubling on each subsequent failure
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            base_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub segments: usize,
+    pub records: usize,
+    pub bytes: u64,
+    pub oldest_queued_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrainStats {
+    pub persisted: usize,
+    pub requeued: usize,
+    /// records in segments which were left untouched, as none of their records were due
+    pub backing_off: usize,
+}
+
+/// A sealed segment where no record was due when it was last read
+#[derive(Clone, Copy, Debug)]
+struct SegmentBackoff {
+    /// when the earliest record in the segment is due
+    due_at: DateTime<Utc>,
+    records: usize,
+}
+
+struct ActiveSegment {
+    id: u64,
+    file: Option<File>,
+    len: u64,
+}
+
+pub struct RetryQueue {
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    active: Mutex<ActiveSegment>,
+    /// sealed segments which are skipped until they are due, keyed by path
+    backoff: Mutex<HashMap<PathBuf, SegmentBackoff>>,
+}
+
+impl RetryQueue {
+    /// Opens the queue stored in `dir`, creating the directory if needed.
+    ///
+    /// Any existing segments are treated as sealed, and new records are written to a fresh segment
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create queue dir {dir:?}"))?;
+        let next_id = Self::segment_ids(&dir)?
+            .last()
+            .map(|id| id + 1)
+            .unwrap_or_default();
+        Ok(Self {
+            dir,
+            max_segment_bytes: 64 * 1024 * 1024,
+            active: Mutex::new(ActiveSegment {
+                id: next_id,
+                file: None,
+                len: 0,
+            }),
+            backoff: Mutex::new(HashMap::new()),
+        })
+    }
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("segment_{id:020}.seg"))
+    }
+    fn segment_ids(dir: &Path) -> Result<Vec<u64>> {
+        let mut ids = std::fs::read_dir(dir)
+            .with_context(|| "failed to read queue dir")?
+            .filter_map(|entry| {
+                let file_name = entry.ok()?.file_name();
+                file_name
+                    .to_str()?
+                    .strip_prefix("segment_")?
+                    .strip_suffix(".seg")?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+    /// Durably appends a block to the active segment
+    pub fn push(&self, record: &QueuedBlock) -> Result<()> {
+        self.push_batch(std::slice::from_ref(record))
+    }
+    /// Durably appends blocks to the active segment, with a single sync
+    pub fn push_batch(&self, records: &[QueuedBlock]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut buf = vec![];
+        for record in records {
+            let payload = zstd::encode_all(&serde_json::to_vec(record)?[..], COMPRESSION_LEVEL)
+                .with_context(|| format!("failed to compress block({})", record.slot))?;
+            buf.extend_from_slice(&record.slot.to_le_bytes());
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+
+        let mut active = self
+            .active
+            .lock()
+            .map_err(|err| anyhow!("queue lock poisoned {err:#?}"))?;
+        if active.len >= self.max_segment_bytes {
+            active.id += 1;
+            active.file = None;
+            active.len = 0;
+        }
+        if active.file.is_none() {
+            let path = Self::segment_path(&self.dir, active.id);
+            active.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("failed to open {path:?}"))?,
+            );
+        }
+        let file = active.file.as_mut().expect("segment opened above");
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        active.len += buf.len() as u64;
+        Ok(())
+    }
+    /// Rotates the active segment if it contains data, returning all segments which can be drained
+    fn seal(&self) -> Result<Vec<PathBuf>> {
+        let sealed_below = {
+            let mut active = self
+                .active
+                .lock()
+                .map_err(|err| anyhow!("queue lock poisoned {err:#?}"))?;
+            if active.len > 0 {
+                active.id += 1;
+                active.file = None;
+                active.len = 0;
+            }
+            active.id
+        };
+        Ok(Self::segment_ids(&self.dir)?
+            .into_iter()
+            .filter(|id| *id < sealed_below)
+            .map(|id| Self::segment_path(&self.dir, id))
+            .collect())
+    }
+    /// Reads all records from a segment. If a corrupted record is encountered, the valid
+    /// records preceding it are returned along with an error describing the corruption
+    fn read_segment(path: &Path) -> Result<(Vec<QueuedBlock>, Option<anyhow::Error>)> {
+        let mut reader =
+            BufReader::new(File::open(path).with_context(|| format!("failed to open {path:?}"))?);
+        let mut records = vec![];
+        loop {
+            let mut header = [0_u8; HEADER_LEN];
+            match read_full(&mut reader, &mut header)? {
+                0 => return Ok((records, None)),
+                n if n < HEADER_LEN => {
+                    return Ok((
+                        records,
+                        Some(anyhow!("truncated record header in {path:?}")),
+                    ))
+                }
+                _ => {}
+            }
+            let slot = u64::from_le_bytes(header[0..8].try_into()?);
+            let payload_len = u32::from_le_bytes(header[8..12].try_into()?) as usize;
+            let checksum = u32::from_le_bytes(header[12..16].try_into()?);
+            let mut payload = vec![0_u8; payload_len];
+            if read_full(&mut reader, &mut payload)? < payload_len {
+                return Ok((
+                    records,
+                    Some(anyhow!("truncated record for block({slot}) in {path:?}")),
+                ));
+            }
+            if crc32fast::hash(&payload) != checksum {
+                return Ok((
+                    records,
+                    Some(anyhow!("checksum mismatch for block({slot}) in {path:?}")),
+                ));
+            }
+            let record = zstd::decode_all(&payload[..])
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(serde_json::from_slice::<QueuedBlock>(&json)?));
+            match record {
+                Ok(record) => records.push(record),
+                Err(err) => {
+                    return Ok((
+                        records,
+                        Some(anyhow!(
+                            "failed to decode block({slot}) in {path:?} {err:#?}"
+                        )),
+                    ))
+                }
+            }
+        }
+    }
+    /// Returns the slots of all queued blocks without decoding the block payloads
+    pub fn queued_slots(&self) -> Result<HashSet<u64>> {
+        let mut slots = HashSet::new();
+        for id in Self::segment_ids(&self.dir)? {
+            let path = Self::segment_path(&self.dir, id);
+            let bytes = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+            let mut offset = 0;
+            while offset + HEADER_LEN <= bytes.len() {
+                slots.insert(u64::from_le_bytes(bytes[offset..offset + 8].try_into()?));
+                let payload_len =
+                    u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into()?) as usize;
+                offset += HEADER_LEN + payload_len;
+            }
+        }
+        Ok(slots)
+    }
+    /// Reports the depth of the queue
+    pub fn status(&self) -> Result<QueueStatus> {
+        let mut status = QueueStatus::default();
+        for id in Self::segment_ids(&self.dir)? {
+            let path = Self::segment_path(&self.dir, id);
+            let bytes = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+            if bytes.is_empty() {
+                continue;
+            }
+            status.segments += 1;
+            status.bytes += bytes.len() as u64;
+            let mut offset = 0;
+            while offset + HEADER_LEN <= bytes.len() {
+                let payload_len =
+                    u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into()?) as usize;
+                offset += HEADER_LEN + payload_len;
+                status.records += 1;
+            }
+            // records are requeued into later segments, so any segment may hold the oldest record
+            let (records, _) = Self::read_segment(&path)?;
+            status.oldest_queued_at = records
+                .iter()
+                .map(|record| record.queued_at)
+                .chain(status.oldest_queued_at)
+                .min();
+        }
+        Ok(status)
+    }
+    /// Attempts to persist every queued block which is due for a retry.
+    ///
+    /// Segments where no block is due are left untouched. In other segments, blocks which fail
+    /// again or are still backing off are moved to the active segment. File io runs on the
+    /// blocking thread pool
+    pub async fn drain_once(
+        self: &Arc<Self>,
+        db: &AsyncClient,
+        cfg: &RetryConfig,
+    ) -> Result<DrainStats> {
+        let mut stats = DrainStats::default();
+        let segments = {
+            let queue = self.clone();
+            tokio::task::spawn_blocking(move || queue.seal()).await??
+        };
+        for path in segments {
+            let now = Utc::now();
+            if let Some(backoff) = self.segment_backoff(&path)? {
+                if now < backoff.due_at {
+                    stats.backing_off += backoff.records;
+                    continue;
+                }
+            }
+            let (records, corruption) = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || Self::read_segment(&path)).await??
+            };
+            if corruption.is_none() {
+                if let Some(due_at) = backoff_until(&records, now, cfg) {
+                    stats.backing_off += records.len();
+                    self.set_segment_backoff(
+                        &path,
+                        Some(SegmentBackoff {
+                            due_at,
+                            records: records.len(),
+                        }),
+                    )?;
+                    continue;
+                }
+            }
+            self.set_segment_backoff(&path, None)?;
+
+            let mut requeue = vec![];
+            for mut record in records {
+                if !record.is_due(now, cfg) {
+                    requeue.push(record);
+                    continue;
+                }
+                record.attempts += 1;
+                record.last_attempt = Some(now);
+                let provenance = record.provenance.clone().unwrap_or_else(|| Provenance {
+                    source: "retry_queue".to_string(),
+                    ..Default::default()
+                });
+                let res = {
+                    let record = record.clone();
+                    db.interact(move |client, conn| {
+                        client.insert_block(
+                            conn,
+                            record.block_height.map(|block_height| block_height as i64),
+                            record.slot as i64,
+                            record.time,
+                            &record.block,
+                            &provenance,
+                        )
+                    })
+                    .await
+                };
+                match res {
+                    Ok(_) => {
+                        log::info!(
+                            "persisted queued block({}) after {} attempts",
+                            record.slot,
+                            record.attempts
+                        );
+                        stats.persisted += 1;
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "queued block({}) persistence failed(attempts={}) {err:#?}",
+                            record.slot,
+                            record.attempts
+                        );
+                        record.reason = format!("{err:#}");
+                        requeue.push(record);
+                    }
+                }
+            }
+            stats.requeued += requeue.len();
+            let queue = self.clone();
+            tokio::task::spawn_blocking(move || -> Result<()> {
+                queue.push_batch(&requeue)?;
+                match corruption {
+                    Some(err) => {
+                        // keep the corrupted segment around for manual inspection
+                        log::error!("corrupted queue segment {err:#?}");
+                        std::fs::rename(&path, path.with_extension("corrupt"))
+                            .with_context(|| format!("failed to quarantine {path:?}"))
+                    }
+                    None => std::fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove drained segment {path:?}")),
+                }
+            })
+            .await??;
+        }
+        Ok(stats)
+    }
+    fn segment_backoff(&self, path: &Path) -> Result<Option<SegmentBackoff>> {
+        Ok(self
+            .backoff
+            .lock()
+            .map_err(|err| anyhow!("queue lock poisoned {err:#?}"))?
+            .get(path)
+            .copied())
+    }
+    fn set_segment_backoff(&self, path: &Path, backoff: Option<SegmentBackoff>) -> Result<()> {
+        let mut segments = self
+            .backoff
+            .lock()
+            .map_err(|err| anyhow!("queue lock poisoned {err:#?}"))?;
+        match backoff {
+            Some(backoff) => segments.insert(path.to_path_buf(), backoff),
+            None => segments.remove(path),
+        };
+        Ok(())
+    }
+}
+
+/// Returns when the earliest of `records` is due, if none of them are due at `now`
+fn backoff_until(
+    records: &[QueuedBlock],
+    now: DateTime<Utc>,
+    cfg: &RetryConfig,
+) -> Option<DateTime<Utc>> {
+    records
+        .iter()
+        .map(|record| record.due_at(cfg).filter(|due_at| now < *due_at))
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .min()
+}
+
+/// Periodically drains the retry queue into postgres
+pub async fn retry_loop(queue: Arc<RetryQueue>, db: AsyncClient, cfg: RetryConfig) {
+    let mut ticker = tokio::time::interval(cfg.interval);
+    loop {
+        ticker.tick().await;
+        match queue.drain_once(&db, &cfg).await {
+            Ok(stats) => {
+                if stats.persisted > 0 || stats.requeued > 0 {
+                    log::info!(
+                        "drained retry queue(persisted={}, requeued={}, backing_off={})",
+                        stats.persisted,
+                        stats.requeued,
+                        stats.backing_off
+                    );
+                }
+            }
+            Err(err) => log::error!("failed to drain retry queue {err:#?}"),
+        }
+    }
+}
+
+/// reads into `buf` until it is full or EOF is reached, returning the number of bytes read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_retry_queue_segments() {
+        let dir = std::env::temp_dir().join(format!("sb_dl_retry_queue_{}", std::process::id()));
+        let queue = RetryQueue::open(&dir).unwrap();
+        for slot in 0..5 {
+            queue
+                .push(&QueuedBlock::new(
+                    slot,
+                    Some(slot + 100),
+                    None,
+                    "test".to_string(),
+                    serde_json::json!({ "slot": slot }),
+                    Provenance::default(),
+                ))
+                .unwrap();
+        }
+        assert_eq!(
+            queue.queued_slots().unwrap(),
+            (0..5).collect::<HashSet<_>>()
+        );
+        let status = queue.status().unwrap();
+        assert_eq!(status.segments, 1);
+        assert_eq!(status.records, 5);
+        assert!(status.oldest_queued_at.is_some());
+
+        let sealed = queue.seal().unwrap();
+        assert_eq!(sealed.len(), 1);
+        let (records, corruption) = RetryQueue::read_segment(&sealed[0]).unwrap();
+        assert!(corruption.is_none());
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[3].block_height, Some(103));
+
+        // corrupt the final record, the preceding records must still be readable
+        let mut bytes = std::fs::read(&sealed[0]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&sealed[0], bytes).unwrap();
+        let (records, corruption) = RetryQueue::read_segment(&sealed[0]).unwrap();
+        assert!(corruption.is_some());
+        assert_eq!(records.len(), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    #[test]
+    fn test_backoff() {
+        let cfg = RetryConfig::default();
+        let now = Utc::now();
+        let mut record = QueuedBlock::new(
+            1,
+            Some(1),
+            None,
+            "".to_string(),
+            serde_json::json!({}),
+            Provenance::default(),
+        );
+        assert!(record.is_due(now, &cfg));
+        record.attempts = 3;
+        record.last_attempt = Some(now);
+        // third failure backs off for base_backoff * 4
+        assert!(!record.is_due(now + chrono::Duration::seconds(119), &cfg));
+        assert!(record.is_due(now + chrono::Duration::seconds(120), &cfg));
+        record.attempts = 30;
+        assert!(record.is_due(now + chrono::Duration::hours(1), &cfg));
+    }
+    #[test]
+    fn test_segment_backoff() {
+        let cfg = RetryConfig::default();
+        let now = Utc::now();
+        let record = |attempts, last_attempt| {
+            let mut record = QueuedBlock::new(
+                1,
+                Some(1),
+                None,
+                "".to_string(),
+                serde_json::json!({}),
+                Provenance::default(),
+            );
+            record.attempts = attempts;
+            record.last_attempt = last_attempt;
+            record
+        };
+        let backing_off = vec![record(1, Some(now)), record(2, Some(now))];
+        assert_eq!(
+            backoff_until(&backing_off, now, &cfg),
+            Some(now + chrono::Duration::seconds(30))
+        );
+        // segments with any due record are drained
+        let mut due = backing_off.clone();
+        due.push(record(0, None));
+        assert_eq!(backoff_until(&due, now, &cfg), None);
+        assert_eq!(
+            backoff_until(&backing_off, now + chrono::Duration::seconds(30), &cfg),
+            None
+        );
+    }
+    #[test]
+    fn test_status_oldest_queued_at() {
+        let dir =
+            std::env::temp_dir().join(format!("sb_dl_retry_queue_status_{}", std::process::id()));
+        let queue = RetryQueue::open(&dir).unwrap();
+        let record = |slot, queued_at| {
+            let mut record = QueuedBlock::new(
+                slot,
+                Some(slot),
+                None,
+                "test".to_string(),
+                serde_json::json!({ "slot": slot }),
+                Provenance::default(),
+            );
+            record.queued_at = queued_at;
+            record
+        };
+        let now = Utc::now();
+        queue.push(&record(1, now)).unwrap();
+        queue.seal().unwrap();
+        // requeued records keep their original queued_at in later segments
+        let oldest = now - chrono::Duration::hours(1);
+        queue
+            .push_batch(&[record(2, oldest), record(3, now)])
+            .unwrap();
+        let status = queue.status().unwrap();
+        assert_eq!(status.segments, 2);
+        assert_eq!(status.records, 3);
+        assert_eq!(status.oldest_queued_at, Some(oldest));
+        assert_eq!(queue.queued_slots().unwrap(), HashSet::from([1, 2, 3]));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -20,9 +20,9 @@ impl TransferParser {
     }
     pub async fn start(&self, blocks: Vec<Blocks>) -> Result<()> {
         for block in blocks.into_iter() {
-            let block_number = block.number;
+            let block_slot = block.slot;
             if let Err(err) = self.process_block(block).await {
-                return Err(anyhow!("failed to process block({block_number}) {err:#?}"));
+                return Err(anyhow!("failed to process block(slot={block_slot}) {err:#?}"));
             }
         }
         Ok(())
@@ -31,7 +31,11 @@ impl TransferParser {
         &self,
         block: Blocks,
     ) -> Result<()> {
-        let block_number=  block.number;
+        // transfer ids are derived from the block height
+        let Some(block_number) = block.number else {
+            log::warn!("skipping block(slot={}) without a block height", block.slot);
+            return Ok(());
+        };
         let transfers = match Self::decode_transfers(block) {
             Ok(transfers) => transfers,
             Err(err) => return Err(anyhow!("failed to decode transfers {err:#?}")),
@@ -0,0 +1,506 @@
+//! Spot checks stored blocks against a fresh copy fetched from bigtable or rpc.
+//!
+//! Each block is re-fetched with the minimization it was stored with, encoded the same way blocks
+//! are persisted, and structurally compared against the stored block. The outcome of every check is
+//! recorded in the `block_verifications` table.
+
+use {
+    super::reingest::BlockUpstream,
+    crate::{sinks::EncodedBlock, types::BlockInfo},
+    anyhow::{anyhow, Result},
+    chrono::prelude::*,
+    db::{
+        async_client::AsyncClient,
+        client::BlockFilter,
+        models::{Blocks, NewBlockVerification},
+    },
+    futures::stream::{self, StreamExt},
+    serde::Serialize,
+    serde_json::Value,
+    solana_transaction_status::UiConfirmedBlock,
+    std::collections::{BTreeSet, HashMap},
+};
+
+/// meta fields which are reported as balance differences
+const BALANCE_FIELDS: [&str; 4] = [
+    "preBalances",
+    "postBalances",
+    "preTokenBalances",
+    "postTokenBalances",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BlockDifference {
+    /// a block level field differs, ie blockhash or parentSlot
+    BlockField {
+        field: String,
+        stored: Value,
+        upstream: Value,
+    },
+    /// the transaction is stored but was not returned by the upstream
+    SignatureMissingUpstream { signature: String },
+    /// the transaction was returned by the upstream but is not stored
+    SignatureMissingStored { signature: String },
+    /// transactions common to both blocks are ordered differently
+    TransactionOrder,
+    /// the transaction message differs
+    Transaction { signature: String },
+    /// a transaction meta field other than the balances differs
+    Meta { signature: String, field: String },
+    Balances {
+        signature: String,
+        field: String,
+        stored: Value,
+        upstream: Value,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerificationStatus {
+    Match,
+    Mismatch,
+    /// the upstream has no block for the slot
+    Missing,
+    /// the block could not be fetched or encoded
+    Error,
+}
+
+impl VerificationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Match => "match",
+            Self::Mismatch => "mismatch",
+            Self::Missing => "missing",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerifyStats {
+    /// stored blocks selected for verification
+    pub selected: usize,
+    pub matched: usize,
+    pub mismatched: usize,
+    /// blocks which the upstream did not return
+    pub missing: usize,
+    /// blocks which could not be fetched, encoded or recorded
+    pub failed: usize,
+    /// slots of the mismatched blocks
+    pub mismatched_slots: Vec<i64>,
+}
+
+/// Re-fetches each slot from `upstream` and compares it against the stored block, recording the results.
+///
+/// Blocks stored without provenance are fetched using `no_minimization`
+pub async fn verify_slots(
+    db: &AsyncClient,
+    upstream: &dyn BlockUpstream,
+    slots: Vec<i64>,
+    no_minimization: bool,
+    threads: usize,
+) -> Result<VerifyStats> {
+    if threads == 0 {
+        return Err(anyhow!("threads must be positive"));
+    }
+    let upstream_name = upstream.source().as_str();
+    let mut stats = VerifyStats {
+        selected: slots.len(),
+        ..Default::default()
+    };
+    // stored blocks are loaded a chunk at a time, so that only a bounded number are held in memory
+    for chunk in slots.chunks(threads * 4) {
+        let stored = {
+            let chunk = chunk.to_vec();
+            db.interact(move |client, conn| {
+                let mut stored = Vec::with_capacity(chunk.len());
+                let mut failed = 0;
+                for slot in chunk {
+                    match client.select_block(conn, BlockFilter::Slot(slot)) {
+                        Ok(mut blocks) if !blocks.is_empty() => stored.push(blocks.remove(0)),
+                        Ok(_) => log::warn!("block({slot}) was deleted while verifying"),
+                        Err(err) => {
+                            log::error!("failed to select block({slot}) {err:#?}");
+                            failed += 1;
+                        }
+                    }
+                }
+                Ok((stored, failed))
+            })
+            .await
+        };
+        let stored = match stored {
+            Ok((stored, failed)) => {
+                stats.failed += failed;
+                stored
+            }
+            Err(err) => {
+                log::error!("failed to select blocks {err:#?}");
+                stats.failed += chunk.len();
+                continue;
+            }
+        };
+        let mut fetched = stream::iter(stored)
+            .map(|block| async move {
+                let no_minimization = block
+                    .minimized
+                    .map(|minimized| !minimized)
+                    .unwrap_or(no_minimization);
+                let res = upstream
+                    .get_block(block.slot as u64, no_minimization)
+                    .await
+                    .and_then(|upstream_block| {
+                        upstream_block
+                            .map(|upstream_block| {
+                                encode(upstream, &block, upstream_block, no_minimization)
+                            })
+                            .transpose()
+                    });
+                (block, res)
+            })
+            .buffer_unordered(threads);
+        while let Some((block, res)) = fetched.next().await {
+            let slot = block.slot;
+            let (status, differences, error) = match res {
+                Ok(Some(upstream_block)) => {
+                    let differences = diff_blocks(&block.data, &upstream_block.data);
+                    if differences.is_empty() {
+                        (VerificationStatus::Match, differences, None)
+                    } else {
+                        log::warn!(
+                            "block({slot}) differs from {upstream_name}, found {} differences",
+                            differences.len()
+                        );
+                        (VerificationStatus::Mismatch, differences, None)
+                    }
+                }
+                Ok(None) => (VerificationStatus::Missing, vec![], None),
+                Err(err) => {
+                    log::error!("failed to verify block({slot}) {err:#?}");
+                    (VerificationStatus::Error, vec![], Some(format!("{err:#}")))
+                }
+            };
+            let recorded = match serde_json::to_value(&differences) {
+                Ok(differences) => {
+                    db.interact(move |client, conn| {
+                        client.insert_block_verification(
+                            conn,
+                            &NewBlockVerification {
+                                slot,
+                                upstream: upstream_name,
+                                status: status.as_str(),
+                                differences: &differences,
+                                error: error.as_deref(),
+                            },
+                        )
+                    })
+                    .await
+                }
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = recorded {
+                log::error!("failed to record verification for block({slot}) {err:#?}");
+                stats.failed += 1;
+                continue;
+            }
+            match status {
+                VerificationStatus::Match => stats.matched += 1,
+                VerificationStatus::Mismatch => {
+                    stats.mismatched += 1;
+                    stats.mismatched_slots.push(slot);
+                }
+                VerificationStatus::Missing => stats.missing += 1,
+                VerificationStatus::Error => stats.failed += 1,
+            }
+        }
+    }
+    stats.mismatched_slots.sort_unstable();
+    Ok(stats)
+}
+
+/// Encodes an upstream block the same way it would have been persisted
+fn encode(
+    upstream: &dyn BlockUpstream,
+    stored: &Blocks,
+    block: UiConfirmedBlock,
+    no_minimization: bool,
+) -> Result<EncodedBlock> {
+    let block_height = block
+        .block_height
+        .or(stored.number.map(|number| number as u64));
+    let time = block
+        .block_time
+        .and_then(|block_time| DateTime::from_timestamp(block_time, 0));
+    EncodedBlock::try_from(BlockInfo {
+        block_height,
+        slot: stored.slot as u64,
+        time,
+        block,
+        source: upstream.source(),
+        minimized: !no_minimization,
+    })
+}
+
+/// Returns the structural differences between a stored and an upstream encoded block.
+///
+/// Transactions are matched by their first signature, or by their position when the signature
+/// is not available
+pub fn diff_blocks(stored: &Value, upstream: &Value) -> Vec<BlockDifference> {
+    let mut differences = vec![];
+    let empty = serde_json::Map::new();
+    let stored_fields = stored.as_object().unwrap_or(&empty);
+    let upstream_fields = upstream.as_object().unwrap_or(&empty);
+    let fields: BTreeSet<&String> = stored_fields.keys().chain(upstream_fields.keys()).collect();
+    for field in fields {
+        if field == "transactions" {
+            continue;
+        }
+        let (stored_value, upstream_value) = (&stored[field.as_str()], &upstream[field.as_str()]);
+        if stored_value != upstream_value {
+            differences.push(BlockDifference::BlockField {
+                field: field.clone(),
+                stored: stored_value.clone(),
+                upstream: upstream_value.clone(),
+            });
+        }
+    }
+
+    let stored_txs = transactions_by_signature(stored);
+    let upstream_txs = transactions_by_signature(upstream);
+    let upstream_index: HashMap<&str, &Value> = upstream_txs
+        .iter()
+        .map(|(signature, tx)| (signature.as_str(), *tx))
+        .collect();
+    let stored_index: HashMap<&str, &Value> = stored_txs
+        .iter()
+        .map(|(signature, tx)| (signature.as_str(), *tx))
+        .collect();
+    for (signature, stored_tx) in stored_txs.iter() {
+        match upstream_index.get(signature.as_str()) {
+            Some(upstream_tx) => {
+                diff_transaction(signature, stored_tx, upstream_tx, &mut differences)
+            }
+            None => differences.push(BlockDifference::SignatureMissingUpstream {
+                signature: signature.clone(),
+            }),
+        }
+    }
+    for (signature, _) in upstream_txs.iter() {
+        if !stored_index.contains_key(signature.as_str()) {
+            differences.push(BlockDifference::SignatureMissingStored {
+                signature: signature.clone(),
+            });
+        }
+    }
+    let common = |txs: &[(String, &Value)], other: &HashMap<&str, &Value>| {
+        txs.iter()
+            .filter(|(signature, _)| other.contains_key(signature.as_str()))
+            .map(|(signature, _)| signature.clone())
+            .collect::<Vec<_>>()
+    };
+    if common(&stored_txs, &upstream_index) != common(&upstream_txs, &stored_index) {
+        differences.push(BlockDifference::TransactionOrder);
+    }
+    differences
+}
+
+fn diff_transaction(
+    signature: &str,
+    stored: &Value,
+    upstream: &Value,
+    differences: &mut Vec<BlockDifference>,
+) {
+    if stored["transaction"] != upstream["transaction"] {
+        differences.push(BlockDifference::Transaction {
+            signature: signature.to_string(),
+        });
+    }
+    let empty = serde_json::Map::new();
+    let stored_meta = stored["meta"].as_object().unwrap_or(&empty);
+    let upstream_meta = upstream["meta"].as_object().unwrap_or(&empty);
+    let fields: BTreeSet<&String> = stored_meta.keys().chain(upstream_meta.keys()).collect();
+    for field in fields {
+        let (stored_value, upstream_value) = (
+            &stored["meta"][field.as_str()],
+            &upstream["meta"][field.as_str()],
+        );
+        if stored_value == upstream_value {
+            continue;
+        }
+        if BALANCE_FIELDS.contains(&field.as_str()) {
+            differences.push(BlockDifference::Balances {
+                signature: signature.to_string(),
+                field: field.clone(),
+                stored: stored_value.clone(),
+                upstream: upstream_value.clone(),
+            });
+        } else {
+            differences.push(BlockDifference::Meta {
+                signature: signature.to_string(),
+                field: field.clone(),
+            });
+        }
+    }
+}
+
+/// Returns the transactions of an encoded block keyed by their first signature, in block order
+fn transactions_by_signature(block: &Value) -> Vec<(String, &Value)> {
+    block["transactions"]
+        .as_array()
+        .map(|txs| {
+            txs.iter()
+                .enumerate()
+                .map(|(idx, tx)| {
+                    let signature = tx["transaction"]["signatures"][0]
+                        .as_str()
+                        .map(|signature| signature.to_string())
+                        .unwrap_or_else(|| format!("#{idx}"));
+                    (signature, tx)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            services::reingest::test_utils::{block, StubUpstream},
+            types::BlockSource,
+        },
+        db::test_utils::TestDb,
+        serde_json::json,
+    };
+
+    #[tokio::test]
+    async fn test_verify_slots() {
+        let test_db = TestDb::new();
+        let db = AsyncClient::new(&test_db.url(), 2).unwrap();
+        db.run_migrations().await.unwrap();
+        // 10 matches, 20 is stored with a different parent, 30 is missing upstream and 40 fails
+        for slot in [10, 20, 30, 40] {
+            let mut encoded = EncodedBlock::try_from(BlockInfo {
+                block_height: Some(slot),
+                slot,
+                time: None,
+                block: block(slot),
+                source: BlockSource::Rpc,
+                minimized: false,
+            })
+            .unwrap();
+            if slot == 20 {
+                encoded.data["parentSlot"] = json!(0);
+            }
+            db.interact(move |client, conn| {
+                client.insert_block(
+                    conn,
+                    Some(slot as i64),
+                    slot as i64,
+                    None,
+                    &encoded.data,
+                    &encoded.provenance,
+                )
+            })
+            .await
+            .unwrap();
+        }
+        let upstream = StubUpstream {
+            blocks: [10, 20, 40]
+                .into_iter()
+                .map(|slot| (slot, block(slot)))
+                .collect(),
+            failing_slot: 40,
+        };
+
+        // slot 50 is not stored, so it is skipped
+        let stats = verify_slots(&db, &upstream, vec![10, 20, 30, 40, 50], true, 2)
+            .await
+            .unwrap();
+        assert_eq!(stats.selected, 5);
+        assert_eq!(stats.matched, 1);
+        assert_eq!(stats.mismatched, 1);
+        assert_eq!(stats.mismatched_slots, vec![20]);
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.failed, 1);
+
+        let verifications = db
+            .interact(|client, conn| client.select_block_verifications(conn, None, 10))
+            .await
+            .unwrap();
+        let mut statuses = verifications
+            .iter()
+            .map(|verification| (verification.slot, verification.status.clone()))
+            .collect::<Vec<_>>();
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            vec![
+                (10, "match".to_string()),
+                (20, "mismatch".to_string()),
+                (30, "missing".to_string()),
+                (40, "error".to_string()),
+            ]
+        );
+    }
+
+    fn tx(signature: &str, fee: u64, post_balances: Vec<u64>) -> Value {
+        json!({
+            "transaction": {"signatures": [signature], "message": {}},
+            "meta": {"fee": fee, "preBalances": [10, 10], "postBalances": post_balances},
+        })
+    }
+    #[test]
+    fn test_diff_blocks() {
+        let stored = json!({
+            "blockhash": "hash",
+            "parentSlot": 9,
+            "transactions": [tx("a", 5000, vec![5, 15]), tx("b", 5000, vec![10, 10]), tx("c", 5000, vec![10, 10])],
+        });
+        assert!(diff_blocks(&stored, &stored).is_empty());
+
+        let upstream = json!({
+            "blockhash": "hash",
+            "parentSlot": 8,
+            "transactions": [tx("a", 5000, vec![0, 20]), tx("b", 10000, vec![10, 10]), tx("d", 5000, vec![10, 10])],
+        });
+        assert_eq!(
+            diff_blocks(&stored, &upstream),
+            vec![
+                BlockDifference::BlockField {
+                    field: "parentSlot".to_string(),
+                    stored: json!(9),
+                    upstream: json!(8),
+                },
+                BlockDifference::Balances {
+                    signature: "a".to_string(),
+                    field: "postBalances".to_string(),
+                    stored: json!([5, 15]),
+                    upstream: json!([0, 20]),
+                },
+                BlockDifference::Meta {
+                    signature: "b".to_string(),
+                    field: "fee".to_string(),
+                },
+                BlockDifference::SignatureMissingUpstream {
+                    signature: "c".to_string(),
+                },
+                BlockDifference::SignatureMissingStored {
+                    signature: "d".to_string(),
+                },
+            ]
+        );
+
+        let reordered = json!({
+            "blockhash": "hash",
+            "parentSlot": 9,
+            "transactions": [tx("b", 5000, vec![10, 10]), tx("a", 5000, vec![5, 15]), tx("c", 5000, vec![10, 10])],
+        });
+        assert_eq!(
+            diff_blocks(&stored, &reordered),
+            vec![BlockDifference::TransactionOrder]
+        );
+    }
+}
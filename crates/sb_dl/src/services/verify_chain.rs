@@ -0,0 +1,261 @@
+//! Verifies that stored blocks form a continuous chain.
+//!
+//! Blocks are walked in slot order, and every block is expected to build on the preceding stored
+//! block: its `parentSlot` must equal the previous block's slot, its `previousBlockhash` the previous
+//! block's `blockhash`, and its block height, when both blocks have one, must be one greater. Skipped
+//! slots are expected, as the parent slot always refers to the last produced block.
+
+use {
+    anyhow::{anyhow, Result},
+    db::{client::Client, models::Blocks},
+    diesel::PgConnection,
+    serde::Serialize,
+    std::collections::BTreeMap,
+};
+
+/// number of recently verified blocks kept to resolve parents of forked blocks
+const RECENT_BLOCKS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChainIssue {
+    /// the parent block is not stored, `previous_slot` is the preceding stored block
+    MissingParent {
+        slot: i64,
+        parent_slot: i64,
+        previous_slot: i64,
+    },
+    /// the block does not build on the preceding stored block, but on an earlier block
+    Fork {
+        slot: i64,
+        parent_slot: i64,
+        previous_slot: i64,
+    },
+    /// the block's previous blockhash does not match the blockhash of its parent
+    HashMismatch {
+        slot: i64,
+        parent_slot: i64,
+        previous_blockhash: String,
+        parent_blockhash: String,
+    },
+    /// the block height is not one greater than the parent's block height
+    HeightMismatch {
+        slot: i64,
+        number: i64,
+        parent_number: i64,
+    },
+    /// the stored block data is missing chain fields
+    Malformed { slot: i64, error: String },
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChainReport {
+    pub start_slot: i64,
+    pub end_slot: i64,
+    pub blocks_checked: usize,
+    pub ok: bool,
+    pub issues: Vec<ChainIssue>,
+}
+
+struct ChainLink {
+    number: Option<i64>,
+    blockhash: String,
+}
+
+/// Incrementally verifies blocks pushed in ascending slot order
+#[derive(Default)]
+pub struct ChainVerifier {
+    /// recently verified blocks by slot, the last entry is the preceding stored block
+    recent: BTreeMap<i64, ChainLink>,
+    blocks_checked: usize,
+    issues: Vec<ChainIssue>,
+}
+
+impl ChainVerifier {
+    pub fn push(&mut self, block: &Blocks) {
+        self.blocks_checked += 1;
+        let (blockhash, previous_blockhash, parent_slot) = match chain_fields(&block.data) {
+            Ok(fields) => fields,
+            Err(err) => {
+                self.issues.push(ChainIssue::Malformed {
+                    slot: block.slot,
+                    error: format!("{err:#}"),
+                });
+                return;
+            }
+        };
+        // the first block in the range is the anchor which following blocks are verified against
+        if let Some((&previous_slot, _)) = self.recent.last_key_value() {
+            if parent_slot > previous_slot {
+                self.issues.push(ChainIssue::MissingParent {
+                    slot: block.slot,
+                    parent_slot,
+                    previous_slot,
+                });
+            } else {
+                if parent_slot < previous_slot {
+                    self.issues.push(ChainIssue::Fork {
+                        slot: block.slot,
+                        parent_slot,
+                        previous_slot,
+                    });
+                }
+                if let Some(parent) = self.recent.get(&parent_slot) {
+                    if parent.blockhash != previous_blockhash {
+                        self.issues.push(ChainIssue::HashMismatch {
+                            slot: block.slot,
+                            parent_slot,
+                            previous_blockhash: previous_blockhash.clone(),
+                            parent_blockhash: parent.blockhash.clone(),
+                        });
+                    }
+                    // blocks without a height can't be checked
+                    if let (Some(number), Some(parent_number)) = (block.number, parent.number) {
+                        if parent_number + 1 != number {
+                            self.issues.push(ChainIssue::HeightMismatch {
+                                slot: block.slot,
+                                number,
+                                parent_number,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        self.recent.insert(
+            block.slot,
+            ChainLink {
+                number: block.number,
+                blockhash,
+            },
+        );
+        if self.recent.len() > RECENT_BLOCKS {
+            self.recent.pop_first();
+        }
+    }
+    pub fn finish(self, start_slot: i64, end_slot: i64) -> ChainReport {
+        ChainReport {
+            start_slot,
+            end_slot,
+            blocks_checked: self.blocks_checked,
+            ok: self.issues.is_empty(),
+            issues: self.issues,
+        }
+    }
+}
+
+/// returns the blockhash, previous blockhash and parent slot of an encoded block
+fn chain_fields(data: &serde_json::Value) -> Result<(String, String, i64)> {
+    let blockhash = data["blockhash"]
+        .as_str()
+        .ok_or_else(|| anyhow!("missing blockhash"))?;
+    let previous_blockhash = data["previousBlockhash"]
+        .as_str()
+        .ok_or_else(|| anyhow!("missing previousBlockhash"))?;
+    let parent_slot = data["parentSlot"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("missing parentSlot"))?;
+    Ok((
+        blockhash.to_string(),
+        previous_blockhash.to_string(),
+        parent_slot,
+    ))
+}
+
+/// Verifies the stored blocks with slots in `[start_slot, end_slot]`, reading `batch_size` slots at a time
+pub fn verify_chain(
+    conn: &mut PgConnection,
+    start_slot: i64,
+    end_slot: i64,
+    batch_size: i64,
+) -> Result<ChainReport> {
+    if end_slot < start_slot {
+        return Err(anyhow!("end_slot({end_slot}) < start_slot({start_slot})"));
+    }
+    if batch_size <= 0 {
+        return Err(anyhow!("batch_size must be positive"));
+    }
+    let client = Client {};
+    let mut verifier = ChainVerifier::default();
+    let mut batch_start = start_slot;
+    while batch_start <= end_slot {
+        let batch_end = end_slot.min(batch_start + batch_size - 1);
+        for block in client.select_blocks_by_slot_range(conn, batch_start, batch_end)? {
+            verifier.push(&block);
+        }
+        log::debug!("verified slots({batch_start}..={batch_end})");
+        batch_start = batch_end + 1;
+    }
+    Ok(verifier.finish(start_slot, end_slot))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn block(number: i64, slot: i64, parent_slot: i64, previous_blockhash: &str) -> Blocks {
+        Blocks {
+            number: Some(number),
+            slot,
+            data: serde_json::json!({
+                "blockhash": format!("hash_{slot}"),
+                "previousBlockhash": previous_blockhash,
+                "parentSlot": parent_slot,
+            }),
+            ..Default::default()
+        }
+    }
+    #[test]
+    fn test_verify_chain() {
+        let mut verifier = ChainVerifier::default();
+        for block in [
+            block(1, 10, 9, "hash_9"),
+            // slot 11 was skipped
+            block(2, 12, 10, "hash_10"),
+            block(3, 13, 12, "wrong_hash"),
+            // the parent at slot 14 is not stored
+            block(5, 15, 14, "hash_14"),
+            // builds on slot 13 rather than 15
+            block(4, 16, 13, "hash_13"),
+            block(6, 17, 16, "hash_16"),
+        ] {
+            verifier.push(&block);
+        }
+        let mut malformed = block(7, 18, 17, "hash_17");
+        malformed.data = serde_json::json!({});
+        verifier.push(&malformed);
+
+        let report = verifier.finish(10, 18);
+        assert_eq!(report.blocks_checked, 7);
+        assert!(!report.ok);
+        assert_eq!(
+            report.issues,
+            vec![
+                ChainIssue::HashMismatch {
+                    slot: 13,
+                    parent_slot: 12,
+                    previous_blockhash: "wrong_hash".to_string(),
+                    parent_blockhash: "hash_12".to_string(),
+                },
+                ChainIssue::MissingParent {
+                    slot: 15,
+                    parent_slot: 14,
+                    previous_slot: 13,
+                },
+                ChainIssue::Fork {
+                    slot: 16,
+                    parent_slot: 13,
+                    previous_slot: 15,
+                },
+                ChainIssue::HeightMismatch {
+                    slot: 17,
+                    number: 6,
+                    parent_number: 4,
+                },
+                ChainIssue::Malformed {
+                    slot: 18,
+                    error: "missing blockhash".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Destinations which downloaded blocks are persisted to.
+//!
+//! Services are configured with one or more sinks using `--sinks`, for example
+//! `--sinks postgres,zstd:/data/raw_blocks` persists blocks to postgres while also
+//! archiving them to rotating zstd compressed files on local disk.
+
+use {
+    crate::{
+        metrics::{BLOCKS_QUEUED, DB_INSERT_SECONDS},
+        services::retry_queue::{QueuedBlock, RetryQueue},
+        types::{BlockInfo, BLOCK_ENCODING_VERSION},
+        utils::{sanitize_for_postgres, sanitize_value},
+    },
+    anyhow::{anyhow, Context, Result},
+    chrono::prelude::*,
+    db::{async_client::AsyncClient, models::Provenance},
+    futures::future::BoxFuture,
+    serde::Serialize,
+    std::{
+        fs::{File, OpenOptions},
+        io::Write,
+        path::PathBuf,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// files written by the filesystem sink are rotated once they exceed this size
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// A block which has been encoded and sanitized, ready to be persisted
+#[derive(Clone, Serialize)]
+pub struct EncodedBlock {
+    pub slot: u64,
+    pub block_height: Option<u64>,
+    pub time: Option<DateTime<Utc>>,
+    pub data: serde_json::Value,
+    pub provenance: Provenance,
+}
+
+impl TryFrom<BlockInfo> for EncodedBlock {
+    type Error = anyhow::Error;
+    fn try_from(block_info: BlockInfo) -> Result<Self> {
+        Self::try_from(&block_info)
+    }
+}
+
+impl TryFrom<&BlockInfo> for EncodedBlock {
+    type Error = anyhow::Error;
+    fn try_from(block_info: &BlockInfo) -> Result<Self> {
+        let mut data = serde_json::to_value(&block_info.block)
+            .with_context(|| format!("failed to serialize block({})", block_info.slot))?;
+        // sanitize the values first
+        // escape invalid unicode points
+        sanitize_value(&mut data);
+        // replace escaped unicode points with empty string
+        sanitize_for_postgres(&mut data);
+        Ok(Self {
+            slot: block_info.slot,
+            block_height: block_info.block_height,
+            time: block_info.time,
+            data,
+            provenance: Provenance {
+                source: block_info.source.as_str().to_string(),
+                minimized: Some(block_info.minimized),
+                encoding_version: Some(BLOCK_ENCODING_VERSION),
+            },
+        })
+    }
+}
+
+pub trait BlockSink: Send + Sync {
+    /// name of the sink used for logging
+    fn name(&self) -> String;
+    /// Persists a single block
+    fn persist<'a>(&'a self, block: &'a EncodedBlock) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Persists blocks to the blocks table, queueing blocks which fail to be inserted
+pub struct PostgresSink {
+    db: AsyncClient,
+    retry_queue: Arc<RetryQueue>,
+}
+
+impl PostgresSink {
+    pub fn new(db: AsyncClient, retry_queue: Arc<RetryQueue>) -> Self {
+        Self { db, retry_queue }
+    }
+    async fn insert(&self, block: &EncodedBlock) -> Result<()> {
+        let slot = block.slot;
+        let timer = DB_INSERT_SECONDS.start_timer();
+        let res = {
+            let block = block.clone();
+            self.db
+                .interact(move |client, conn| {
+                    client.insert_block(
+                        conn,
+                        block.block_height.map(|block_height| block_height as i64),
+                        slot as i64,
+                        block.time,
+                        &block.data,
+                        &block.provenance,
+                    )
+                })
+                .await
+        };
+        timer.observe_duration();
+        if let Err(err) = res {
+            // block persistence failed despite sanitization, queue the block to be retried
+            log::warn!("block({slot}) persistence failed {err:#?}");
+            self.retry_queue
+                .push(&QueuedBlock::new(
+                    slot,
+                    block.block_height,
+                    block.time,
+                    format!("{err:#}"),
+                    block.data.clone(),
+                    block.provenance.clone(),
+                ))
+                .with_context(|| format!("failed to queue failed block({slot})"))?;
+            BLOCKS_QUEUED.inc();
+            log::warn!("block({slot}) failed to persist, added to retry queue");
+        }
+        Ok(())
+    }
+}
+
+impl BlockSink for PostgresSink {
+    fn name(&self) -> String {
+        "postgres".to_string()
+    }
+    fn persist<'a>(&'a self, block: &'a EncodedBlock) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.insert(block))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileFormat {
+    Jsonl,
+    /// jsonl where each line is compressed as an independent zstd frame
+    Zstd,
+}
+
+struct ActiveFile {
+    file: Option<File>,
+    len: u64,
+    seq: u64,
+}
+
+impl ActiveFile {
+    /// Syncs and closes the current file, lines are written without buffering so there is
+    /// nothing left to flush
+    fn close(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_data()
+                .with_context(|| format!("failed to sync block file({})", self.seq))?;
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// Appends blocks as json lines to files on local disk, rotating files once they exceed `max_file_bytes`
+pub struct FileSink {
+    dir: PathBuf,
+    format: FileFormat,
+    max_file_bytes: u64,
+    active: Mutex<ActiveFile>,
+}
+
+impl FileSink {
+    pub fn new(dir: impl Into<PathBuf>, format: FileFormat, max_file_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir:?}"))?;
+        Ok(Self {
+            dir,
+            format,
+            max_file_bytes,
+            active: Mutex::new(ActiveFile {
+                file: None,
+                len: 0,
+                seq: 0,
+            }),
+        })
+    }
+    fn new_file_path(&self, seq: u64) -> PathBuf {
+        let extension = match self.format {
+            FileFormat::Jsonl => "jsonl",
+            FileFormat::Zstd => "jsonl.zst",
+        };
+        self.dir.join(format!(
+            "blocks_{}_{seq}.{extension}",
+            Utc::now().format("%Y%m%dT%H%M%S")
+        ))
+    }
+}
+
+impl FileSink {
+    fn write(&self, block: &EncodedBlock) -> Result<()> {
+        let mut line = serde_json::to_vec(block)
+            .with_context(|| format!("failed to serialize block({})", block.slot))?;
+        line.push(b'\n');
+        if self.format == FileFormat::Zstd {
+            line = zstd::encode_all(&line[..], 3)
+                .with_context(|| format!("failed to compress block({})", block.slot))?;
+        }
+        let mut active = self
+            .active
+            .lock()
+            .map_err(|err| anyhow!("file sink lock poisoned {err:#?}"))?;
+        if active.file.is_none() || active.len >= self.max_file_bytes {
+            active.close()?;
+            active.seq += 1;
+            let path = self.new_file_path(active.seq);
+            active.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("failed to open {path:?}"))?,
+            );
+        }
+        active
+            .file
+            .as_mut()
+            .expect("file opened above")
+            .write_all(&line)?;
+        active.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        match self.active.lock() {
+            Ok(mut active) => {
+                if let Err(err) = active.close() {
+                    log::error!("failed to close {} {err:#?}", self.name());
+                }
+            }
+            Err(err) => log::error!("file sink lock poisoned {err:#?}"),
+        }
+    }
+}
+
+impl BlockSink for FileSink {
+    fn name(&self) -> String {
+        format!("file({})", self.dir.display())
+    }
+    fn persist<'a>(&'a self, block: &'a EncodedBlock) -> BoxFuture<'a, Result<()>> {
+        // appends to local files are written inline, they are short compared to database round trips
+        Box::pin(async move { self.write(block) })
+    }
+}
+
+/// Writes blocks as json lines to stdout
+pub struct StdoutSink;
+
+impl BlockSink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+    fn persist<'a>(&'a self, block: &'a EncodedBlock) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(block)
+                .with_context(|| format!("failed to serialize block({})", block.slot))?;
+            line.push(b'\n');
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&line)?;
+            stdout.flush()?;
+            Ok(())
+        })
+    }
+}
+
+/// Persists each block to every sink, failing if any sink fails.
+///
+/// Only the postgres sink queues failed blocks for retry, so the caller must treat an error as
+/// the block being lost by the sinks named in it
+pub struct FanoutSink {
+    sinks: Vec<Arc<dyn BlockSink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Arc<dyn BlockSink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl BlockSink for FanoutSink {
+    fn name(&self) -> String {
+        format!(
+            "fanout({})",
+            self.sinks
+                .iter()
+                .map(|sink| sink.name())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+    fn persist<'a>(&'a self, block: &'a EncodedBlock) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut failed = vec![];
+            for sink in self.sinks.iter() {
+                if let Err(err) = sink.persist(block).await {
+                    log::error!(
+                        "sink({}) failed to persist block({}) {err:#?}",
+                        sink.name(),
+                        block.slot
+                    );
+                    failed.push(sink.name());
+                }
+            }
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "block({}) failed to persist to {failed:?}",
+                    block.slot
+                ))
+            }
+        })
+    }
+}
+
+/// Sink specification as accepted on the command line
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SinkConfig {
+    Postgres,
+    Stdout,
+    File { dir: String, format: FileFormat },
+}
+
+impl FromStr for SinkConfig {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "postgres" => Ok(Self::Postgres),
+            None if s == "stdout" => Ok(Self::Stdout),
+            Some(("jsonl", dir)) if !dir.is_empty() => Ok(Self::File {
+                dir: dir.to_string(),
+                format: FileFormat::Jsonl,
+            }),
+            Some(("zstd", dir)) if !dir.is_empty() => Ok(Self::File {
+                dir: dir.to_string(),
+                format: FileFormat::Zstd,
+            }),
+            _ => Err(anyhow!(
+                "invalid sink({s}), expected one of postgres, stdout, jsonl:<dir>, zstd:<dir>"
+            )),
+        }
+    }
+}
+
+impl SinkConfig {
+    /// Returns true if any of `configs` persists blocks to postgres
+    pub fn any_postgres(configs: &[SinkConfig]) -> bool {
+        configs.contains(&SinkConfig::Postgres)
+    }
+}
+
+/// Instantiates the configured sinks, returning a fan-out sink if more than one is configured.
+///
+/// `postgres` is the client and retry queue used by the postgres sink, it is only required
+/// when a postgres sink is configured
+pub fn new_block_sink(
+    configs: &[SinkConfig],
+    postgres: Option<(AsyncClient, Arc<RetryQueue>)>,
+) -> Result<Arc<dyn BlockSink>> {
+    let mut sinks: Vec<Arc<dyn BlockSink>> = Vec::with_capacity(configs.len());
+    for config in configs {
+        sinks.push(match config {
+            SinkConfig::Postgres => {
+                let Some((db, retry_queue)) = &postgres else {
+                    return Err(anyhow!("postgres sink configured without a database"));
+                };
+                Arc::new(PostgresSink::new(db.clone(), retry_queue.clone()))
+            }
+            SinkConfig::Stdout => Arc::new(StdoutSink),
+            SinkConfig::File { dir, format } => {
+                Arc::new(FileSink::new(dir, *format, DEFAULT_MAX_FILE_BYTES)?)
+            }
+        });
+    }
+    match sinks.len() {
+        0 => Err(anyhow!("no sinks configured")),
+        1 => Ok(sinks.remove(0)),
+        _ => Ok(Arc::new(FanoutSink::new(sinks))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_sink_config() {
+        assert_eq!(
+            SinkConfig::from_str("postgres").unwrap(),
+            SinkConfig::Postgres
+        );
+        assert_eq!(SinkConfig::from_str("stdout").unwrap(), SinkConfig::Stdout);
+        assert_eq!(
+            SinkConfig::from_str("zstd:/data/blocks").unwrap(),
+            SinkConfig::File {
+                dir: "/data/blocks".to_string(),
+                format: FileFormat::Zstd
+            }
+        );
+        assert!(SinkConfig::from_str("jsonl:").is_err());
+        assert!(SinkConfig::from_str("kafka").is_err());
+    }
+    #[test]
+    fn test_new_block_sink_without_postgres() {
+        let dir = std::env::temp_dir().join(format!("sb_dl_sink_config_{}", std::process::id()));
+        let configs = [
+            SinkConfig::Stdout,
+            SinkConfig::File {
+                dir: dir.display().to_string(),
+                format: FileFormat::Jsonl,
+            },
+        ];
+        assert!(!SinkConfig::any_postgres(&configs));
+        let sink = new_block_sink(&configs, None).unwrap();
+        assert_eq!(
+            sink.name(),
+            format!("fanout(stdout,file({}))", dir.display())
+        );
+        assert!(new_block_sink(&[SinkConfig::Postgres], None).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    #[test]
+    fn test_file_sink_rotation() {
+        let dir = std::env::temp_dir().join(format!("sb_dl_file_sink_{}", std::process::id()));
+        // rotate after every block
+        let sink = FileSink::new(&dir, FileFormat::Zstd, 1).unwrap();
+        for slot in 0..3 {
+            futures::executor::block_on(sink.persist(&EncodedBlock {
+                slot,
+                block_height: Some(slot),
+                time: None,
+                data: serde_json::json!({ "slot": slot }),
+                provenance: Provenance::default(),
+            }))
+            .unwrap();
+        }
+        let mut lines = vec![];
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let data =
+                zstd::decode_all(&std::fs::read(entry.unwrap().path()).unwrap()[..]).unwrap();
+            lines.push(String::from_utf8(data).unwrap());
+        }
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.ends_with('\n')));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
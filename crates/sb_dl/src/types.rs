@@ -2,10 +2,42 @@ use db::models::NewBlock;
 use solana_transaction_status::UiConfirmedBlock;
 use chrono::prelude::*;
 
+/// Version of the json encoding used for stored block data.
+///
+/// Must be incremented whenever the encoding, minimization or sanitization of blocks changes,
+/// so that blocks stored with an older encoding can be found and re-ingested
+pub const BLOCK_ENCODING_VERSION: i32 = 1;
+
+/// Service which ingested a block
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockSource {
+    Geyser,
+    Bigtable,
+    Rpc,
+    /// legacy `block_{slot}.json` files imported by `import-failed-blocks`
+    FailedBlocksImport,
+}
+
+impl BlockSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Geyser => "geyser",
+            Self::Bigtable => "bigtable",
+            Self::Rpc => "rpc",
+            Self::FailedBlocksImport => "failed_blocks_import",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct BlockInfo {
-    pub block_height: u64,
+    /// None for blocks without a height, ie pre-2021 mainnet blocks, which are stored by slot
+    pub block_height: Option<u64>,
     pub slot: u64,
     pub time: Option<DateTime<Utc>>,
     pub block: UiConfirmedBlock,
+    pub source: BlockSource,
+    /// whether vote transactions were filtered from the block
+    pub minimized: bool,
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use {
-    anyhow::Context, solana_sdk::pubkey::Pubkey, solana_transaction_status::{
+    anyhow::Context, serde_json::Value, solana_sdk::pubkey::Pubkey, solana_transaction_status::{
         BlockEncodingOptions, ConfirmedBlock, EncodedTransaction, TransactionDetails,
         UiConfirmedBlock, UiInstruction, UiMessage, UiParsedInstruction, UiTransactionEncoding,
     }, std::{io::BufWriter, str::FromStr}, tracing_appender::non_blocking::WorkerGuard, tracing_subscriber::{filter::LevelFilter, prelude::*, EnvFilter, Layer}
@@ -100,6 +100,51 @@ pub fn filter_vote_transactions(mut block: UiConfirmedBlock) -> UiConfirmedBlock
     block
 }
 
+// sanitizes utf8 encoding issues which prevent converting serde_json::Value to a string
+// this is done before failed blocks are persisted to disk
+pub fn sanitize_value(value: &mut Value) {
+    match value {
+        Value::String(s) => {
+            // Check if the string contains valid UTF-8
+            if let Err(_) = std::str::from_utf8(s.as_bytes()) {
+                // Replace invalid UTF-8 with a placeholder
+                *s = String::from_utf8_lossy(s.as_bytes()).into_owned();
+            }
+        }
+        Value::Array(arr) => {
+            for v in arr {
+                sanitize_value(v);
+            }
+        }
+        Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                sanitize_value(v);
+            }
+        }
+        _ => {}
+    }
+}
+
+// removes null characters which postgres does not support in jsonb values
+pub fn sanitize_for_postgres(value: &mut Value) {
+    match value {
+        Value::String(ref mut s) => {
+            *s = s.replace("\u{0000}", "");
+        }
+        Value::Array(ref mut arr) => {
+            for item in arr {
+                sanitize_for_postgres(item);
+            }
+        }
+        Value::Object(ref mut obj) => {
+            for (_key, val) in obj.iter_mut() {
+                sanitize_for_postgres(val);
+            }
+        }
+        _ => {}
+    }
+}
+
 /// initializes logging capabilities but adds a variety of customization, including file+line which sourced the log,
 /// a tokio-console used for monitoring async tasks, as well as log-level filtration
 pub fn init_log(level: &str, file: &str) -> Option<WorkerGuard> {
@@ -107,8 +152,10 @@ pub fn init_log(level: &str, file: &str) -> Option<WorkerGuard> {
     let level_filter = LevelFilter::from_level(tracing::Level::from_str(level).unwrap());
     let filter = EnvFilter::from_default_env().add_directive(level_filter.into());
 
+    // console logs are written to stderr so that stdout can be used to pipe blocks
     layers.push(
         tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
             .with_level(true)
             .with_line_number(true)
             .with_file(true)
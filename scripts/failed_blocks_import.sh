@@ -1,3 +1,4 @@
 #! /bin/bash
 
-./sb_dl import-failed-blocks --failed-blocks failed_blocks
\ No newline at end of file
+# moves legacy block_<slot>.json files into the retry queue and drains it once
+./sb_dl services import-failed-blocks --failed-blocks-dir failed_blocks
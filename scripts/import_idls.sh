@@ -1,9 +1,6 @@
 #! /bin/bash
 
 PATH_TO_SB_DL="target/release/sb_dl"
-PATH_TO_IDLS="$1"
+PATH_TO_IDLS="${1:-idls}"
 
-find "$PATH_TO_IDLS" -type f -name "*.json" | while read -r IDL; do
-    PROGRAM_ID=$(echo "$IDL" | awk -F '/' '{print $NF}' | awk -F '_' '{print $1}')
-    "$PATH_TO_SB_DL" manual-idl-import --input "$IDL" --program-id "$PROGRAM_ID"
-done
\ No newline at end of file
+"$PATH_TO_SB_DL" import-idl-dir --dir "$PATH_TO_IDLS"